@@ -0,0 +1,89 @@
+use std::net::IpAddr;
+
+use sha1::{Digest, Sha1};
+
+const FILTER_BITS: usize = 2048;
+pub const FILTER_SIZE: usize = FILTER_BITS / 8;
+
+/// Bloom filter of peer addresses returned by DHT scrapes (BEP 33).
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomFilter([u8; FILTER_SIZE]);
+
+impl BloomFilter {
+    pub fn new() -> Self {
+        Self([0; FILTER_SIZE])
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn insert(&mut self, ip: &IpAddr) {
+        let hash = match ip {
+            IpAddr::V4(ip) => Sha1::digest(ip.octets()),
+            IpAddr::V6(ip) => Sha1::digest(ip.octets()),
+        };
+        for index in [
+            hash[0] as usize | (hash[1] as usize) << 8,
+            hash[2] as usize | (hash[3] as usize) << 8,
+        ] {
+            let index = index % FILTER_BITS;
+            self.0[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    /// Merges the addresses of the other filter into this one.
+    pub fn union(&mut self, other: &BloomFilter) {
+        for (byte, other) in self.0.iter_mut().zip(other.0) {
+            *byte |= other;
+        }
+    }
+
+    /// Estimated number of distinct addresses in the filter.
+    pub fn estimate(&self) -> f64 {
+        let zeros = self
+            .0
+            .iter()
+            .map(|byte| byte.count_zeros() as usize)
+            .sum::<usize>()
+            .max(1);
+        if zeros == FILTER_BITS {
+            return 0.0;
+        }
+        let m = FILTER_BITS as f64;
+        (zeros as f64 / m).ln() / (2.0 * (1.0 - 1.0 / m).ln())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn matches_bep_33_vector() {
+        let mut filter = BloomFilter::new();
+        assert_eq!(filter.estimate(), 0.0);
+        // 192.0.2.0 to 192.0.2.255 and 2001:db8:: to 2001:db8::3e7.
+        for offset in 0..=255 {
+            filter.insert(&Ipv4Addr::new(192, 0, 2, offset).into());
+        }
+        for offset in 0..1000 {
+            filter.insert(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, offset).into());
+        }
+        assert!(
+            (filter.estimate() - 1224.93).abs() < 0.01,
+            "{}",
+            filter.estimate()
+        );
+
+        let mut merged = BloomFilter::from_bytes(&[0; FILTER_SIZE]).unwrap();
+        merged.union(&filter);
+        assert_eq!(merged, filter);
+    }
+}
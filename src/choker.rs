@@ -0,0 +1,293 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::swarm::Swarm;
+
+/// Number of peers unchoked because of their transfer rate.
+const UPLOAD_SLOTS: usize = 4;
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// The optimistic unchoke is rotated every third rechoke.
+const OPTIMISTIC_ROUNDS: usize = 3;
+/// Peers connected for less than this are preferred for the optimistic slot.
+const NEW_PEER_AGE: Duration = Duration::from_secs(30);
+/// How many times more likely a new peer is picked for the optimistic slot.
+const NEW_PEER_WEIGHT: usize = 3;
+
+/// Tit-for-tat upload slot allocation shared by the sessions of a swarm.
+pub struct Choker {
+    state: Mutex<ChokerState>,
+}
+
+#[derive(Default)]
+struct ChokerState {
+    next_id: usize,
+    round: usize,
+    optimistic: Option<usize>,
+    peers: HashMap<usize, PeerStats>,
+}
+
+struct PeerStats {
+    connected_at: Instant,
+    interested: bool,
+    unchoked: bool,
+    /// Bytes received from the peer since the last rechoke.
+    downloaded: usize,
+    /// Bytes sent to the peer since the last rechoke.
+    uploaded: usize,
+}
+
+impl Choker {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(ChokerState::default()),
+        }
+    }
+
+    /// Registers a new session and returns its id.
+    pub fn connect(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.peers.insert(
+            id,
+            PeerStats {
+                connected_at: Instant::now(),
+                interested: false,
+                unchoked: false,
+                downloaded: 0,
+                uploaded: 0,
+            },
+        );
+        id
+    }
+
+    pub fn disconnect(&self, id: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.peers.remove(&id);
+        if state.optimistic == Some(id) {
+            state.optimistic = None;
+        }
+    }
+
+    /// Updates peer interest. A peer becoming interested is unchoked right
+    /// away when a regular upload slot is free.
+    pub fn set_interested(&self, id: usize, interested: bool) {
+        let mut state = self.state.lock().unwrap();
+        let unchoked = state
+            .peers
+            .iter()
+            .filter(|(peer_id, stats)| stats.unchoked && state.optimistic != Some(**peer_id))
+            .count();
+        let Some(stats) = state.peers.get_mut(&id) else {
+            return;
+        };
+        stats.interested = interested;
+        if interested && unchoked < UPLOAD_SLOTS {
+            stats.unchoked = true;
+        }
+    }
+
+    pub fn add_downloaded(&self, id: usize, bytes: usize) {
+        if let Some(stats) = self.state.lock().unwrap().peers.get_mut(&id) {
+            stats.downloaded += bytes;
+        }
+    }
+
+    pub fn add_uploaded(&self, id: usize, bytes: usize) {
+        if let Some(stats) = self.state.lock().unwrap().peers.get_mut(&id) {
+            stats.uploaded += bytes;
+        }
+    }
+
+    pub fn is_unchoked(&self, id: usize) -> bool {
+        let state = self.state.lock().unwrap();
+        state.peers.get(&id).is_some_and(|stats| stats.unchoked)
+    }
+
+    /// Reassigns upload slots. While downloading peers are ranked by how fast
+    /// they upload to us, once seeding by how fast they download from us.
+    pub fn rechoke(&self, seeding: bool) {
+        let mut state = self.state.lock().unwrap();
+        let mut candidates = state
+            .peers
+            .iter()
+            .filter(|(_, stats)| stats.interested)
+            .map(|(id, stats)| {
+                let rate = if seeding {
+                    stats.uploaded
+                } else {
+                    stats.downloaded
+                };
+                (*id, rate)
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|(_, lhs), (_, rhs)| rhs.cmp(lhs));
+        let regular = candidates
+            .iter()
+            .take(UPLOAD_SLOTS)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        let optimistic = state
+            .optimistic
+            .filter(|id| !regular.contains(id) && state.peers.contains_key(id));
+        state.optimistic = if state.round.is_multiple_of(OPTIMISTIC_ROUNDS) || optimistic.is_none()
+        {
+            pick_optimistic(&state.peers, &regular)
+        } else {
+            optimistic
+        };
+        state.round += 1;
+
+        let optimistic = state.optimistic;
+        for (id, stats) in state.peers.iter_mut() {
+            stats.unchoked = regular.contains(id) || optimistic == Some(*id);
+            stats.downloaded = 0;
+            stats.uploaded = 0;
+        }
+    }
+}
+
+/// Picks a random choked peer, giving recently connected peers a higher
+/// chance so they get pieces to trade as soon as possible.
+fn pick_optimistic(peers: &HashMap<usize, PeerStats>, regular: &[usize]) -> Option<usize> {
+    let weighted = peers
+        .iter()
+        .filter(|(id, stats)| stats.interested && !regular.contains(id))
+        .map(|(id, stats)| {
+            let weight = if stats.connected_at.elapsed() < NEW_PEER_AGE {
+                NEW_PEER_WEIGHT
+            } else {
+                1
+            };
+            (*id, weight)
+        })
+        .collect::<Vec<_>>();
+    let total = weighted.iter().map(|(_, weight)| weight).sum::<usize>();
+    if total == 0 {
+        return None;
+    }
+    let mut choice = rand::thread_rng().gen_range(0..total);
+    for (id, weight) in weighted {
+        if choice < weight {
+            return Some(id);
+        }
+        choice -= weight;
+    }
+    None
+}
+
+/// Runs rechoke rounds for the swarm on a background thread.
+pub fn spawn(swarm: Arc<Swarm>) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(RECHOKE_INTERVAL);
+        swarm.choker.rechoke(swarm.is_complete());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unchoked(choker: &Choker, ids: &[usize]) -> Vec<usize> {
+        ids.iter()
+            .copied()
+            .filter(|id| choker.is_unchoked(*id))
+            .collect()
+    }
+
+    fn optimistic(choker: &Choker) -> Option<usize> {
+        choker.state.lock().unwrap().optimistic
+    }
+
+    #[test]
+    fn unchokes_fastest_uploaders() {
+        let choker = Choker::new();
+        let ids = (0..6).map(|_| choker.connect()).collect::<Vec<_>>();
+        for (rate, id) in ids.iter().enumerate() {
+            choker.set_interested(*id, true);
+            choker.add_downloaded(*id, rate * 1000);
+        }
+        choker.rechoke(false);
+
+        // The four peers uploading the most to us, plus an optimistic one
+        // among the other two.
+        let optimistic = optimistic(&choker).unwrap();
+        assert!(ids[..2].contains(&optimistic));
+        let mut expected = vec![optimistic];
+        expected.extend(&ids[2..]);
+        expected.sort();
+        assert_eq!(unchoked(&choker, &ids), expected);
+    }
+
+    #[test]
+    fn ranks_by_download_rate_when_seeding() {
+        let choker = Choker::new();
+        let ids = (0..6).map(|_| choker.connect()).collect::<Vec<_>>();
+        for (rate, id) in ids.iter().enumerate() {
+            choker.set_interested(*id, true);
+            // Uploading to us does not matter to a seed.
+            choker.add_downloaded(*id, rate * 1000);
+            choker.add_uploaded(*id, (6 - rate) * 1000);
+        }
+        choker.rechoke(true);
+
+        let optimistic = optimistic(&choker).unwrap();
+        assert!(ids[4..].contains(&optimistic));
+        let mut expected = ids[..4].to_vec();
+        expected.push(optimistic);
+        assert_eq!(unchoked(&choker, &ids), expected);
+    }
+
+    #[test]
+    fn rotates_optimistic_unchoke_every_third_round() {
+        let choker = Choker::new();
+        let ids = (0..50).map(|_| choker.connect()).collect::<Vec<_>>();
+        for id in &ids {
+            choker.set_interested(*id, true);
+        }
+        // Each cycle starts with a rechoke picking a new optimistic peer,
+        // which is kept for the following rounds.
+        let mut rotations = 0;
+        let mut previous = None;
+        for _ in 0..20 {
+            choker.rechoke(false);
+            let picked = optimistic(&choker).unwrap();
+            if previous.is_some_and(|previous| previous != picked) {
+                rotations += 1;
+            }
+            previous = Some(picked);
+            for _ in 1..OPTIMISTIC_ROUNDS {
+                choker.rechoke(false);
+                assert_eq!(optimistic(&choker), Some(picked));
+            }
+        }
+        assert!(rotations > 0);
+    }
+
+    #[test]
+    fn favours_new_peers_for_optimistic_unchoke() {
+        let stats = |connected_at| PeerStats {
+            connected_at,
+            interested: true,
+            unchoked: false,
+            downloaded: 0,
+            uploaded: 0,
+        };
+        let old = Instant::now() - 2 * NEW_PEER_AGE;
+        let peers = HashMap::from([(0, stats(old)), (1, stats(Instant::now()))]);
+
+        let picks = 4000;
+        let new = (0..picks)
+            .filter(|_| pick_optimistic(&peers, &[]) == Some(1))
+            .count();
+        // Three times as likely as the old peer: 3/4 of the picks.
+        assert!((2800..3200).contains(&new), "{new}");
+        assert_eq!(pick_optimistic(&peers, &[1]), Some(0));
+    }
+}
@@ -0,0 +1,324 @@
+use std::{collections::HashMap, fs::File, io::Read, path::Path};
+
+use anyhow::{Error, Result};
+use clap::ValueEnum;
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+
+use crate::merkle::{self, Hash, BLOCK_SIZE};
+
+pub const DEFAULT_PIECE_LENGTH: usize = 1 << 18;
+
+/// Which hashes a created torrent carries.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum MetaVersion {
+    /// SHA-1 piece hashes only.
+    V1,
+    /// Per-file merkle trees only (BEP 52).
+    V2,
+    /// Both, with padding files aligning the v1 files to pieces.
+    Hybrid,
+}
+
+/// Builds a torrent for the file or directory at `source`, reading all of its
+/// data to hash it. Directories become multi-file torrents named after the
+/// directory, with their files sorted by path.
+pub fn create_torrent(
+    source: &Path,
+    version: MetaVersion,
+    piece_length: usize,
+    announce: Option<&str>,
+    private: bool,
+) -> Result<Vec<u8>> {
+    if piece_length < BLOCK_SIZE || !piece_length.is_power_of_two() {
+        return Err(Error::msg(format!(
+            "Piece length must be a power of two of at least {BLOCK_SIZE} bytes"
+        )));
+    }
+    let name = source
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Error::msg(format!("Invalid torrent name {source:?}")))?
+        .to_string();
+    let paths = if source.is_dir() {
+        let mut paths = Vec::new();
+        walk_directory(source, &mut Vec::new(), &mut paths)?;
+        paths.sort();
+        if paths.is_empty() {
+            return Err(Error::msg(format!("{source:?} contains no files")));
+        }
+        paths
+    } else {
+        vec![Vec::new()]
+    };
+
+    let v1 = version != MetaVersion::V2;
+    let v2 = version != MetaVersion::V1;
+    let mut pieces = PieceHasher::new(piece_length);
+    let mut v1_files = Vec::new();
+    let mut file_tree = HashMap::new();
+    let mut piece_layers = HashMap::new();
+    let mut total_length = 0;
+    // Padding after the last non-empty file, added once a later file turns
+    // out to have data, like the padding `Info::files` infers for v2 files.
+    let mut pending_padding = None;
+    for path in &paths {
+        let local_path = path.iter().fold(source.to_path_buf(), |local, component| {
+            local.join(component)
+        });
+        let mut file = File::open(&local_path)?;
+        let mut length = 0;
+        let mut leaves = Vec::new();
+        loop {
+            let mut chunk = Vec::with_capacity(piece_length);
+            (&mut file)
+                .take(piece_length as u64)
+                .read_to_end(&mut chunk)?;
+            if chunk.is_empty() {
+                break;
+            }
+            length += chunk.len();
+            if let Some((position, padding)) = pending_padding.take() {
+                pieces.update(&vec![0; padding]);
+                v1_files.insert(
+                    position,
+                    file_entry(padding, &[".pad".into(), padding.to_string()], true),
+                );
+            }
+            if v1 {
+                pieces.update(&chunk);
+            }
+            if v2 {
+                leaves.extend(merkle::leaves(&chunk));
+            }
+        }
+        total_length += length;
+        v1_files.push(file_entry(length, path, false));
+        if v2 {
+            let mut node = &mut file_tree;
+            for component in path.iter().chain(path.is_empty().then_some(&name)) {
+                let entry = node
+                    .entry(component.as_bytes().to_vec())
+                    .or_insert_with(|| Value::Dict(HashMap::new()));
+                let Value::Dict(entry) = entry else {
+                    unreachable!("File tree nodes are dictionaries");
+                };
+                node = entry;
+            }
+            let mut description = HashMap::from([(b"length".to_vec(), int(length))]);
+            if length > 0 {
+                let (pieces_root, layer) = file_hashes(&leaves, length, piece_length);
+                description.insert(b"pieces root".to_vec(), Value::Bytes(pieces_root.to_vec()));
+                if let Some(layer) = layer {
+                    piece_layers.insert(pieces_root.to_vec(), Value::Bytes(layer.concat()));
+                }
+            }
+            node.insert(Vec::new(), Value::Dict(description));
+        }
+        // Hybrid torrents start every v1 file at a piece boundary, like v2
+        // files, so that pieces hash the same data in both views.
+        let padding = length.next_multiple_of(piece_length) - length;
+        if version == MetaVersion::Hybrid && padding > 0 {
+            pending_padding = Some((v1_files.len(), padding));
+        }
+    }
+
+    let mut info = HashMap::from([
+        (b"name".to_vec(), Value::Bytes(name.into_bytes())),
+        (b"piece length".to_vec(), int(piece_length)),
+    ]);
+    if v1 {
+        info.insert(b"pieces".to_vec(), Value::Bytes(pieces.finish()));
+        if source.is_dir() {
+            info.insert(b"files".to_vec(), Value::List(v1_files));
+        } else {
+            info.insert(b"length".to_vec(), int(total_length));
+        }
+    }
+    if v2 {
+        info.insert(b"meta version".to_vec(), int(2));
+        info.insert(b"file tree".to_vec(), Value::Dict(file_tree));
+    }
+    if private {
+        info.insert(b"private".to_vec(), int(1));
+    }
+    let mut torrent = HashMap::from([(b"info".to_vec(), Value::Dict(info))]);
+    if let Some(announce) = announce {
+        torrent.insert(
+            b"announce".to_vec(),
+            Value::Bytes(announce.as_bytes().to_vec()),
+        );
+    }
+    if v2 {
+        torrent.insert(b"piece layers".to_vec(), Value::Dict(piece_layers));
+    }
+    Ok(serde_bencode::to_bytes(&Value::Dict(torrent))?)
+}
+
+/// Hashes data into v1 pieces as it is read.
+struct PieceHasher {
+    piece_length: usize,
+    buffer: Vec<u8>,
+    hashes: Vec<u8>,
+}
+
+impl PieceHasher {
+    fn new(piece_length: usize) -> Self {
+        Self {
+            piece_length,
+            buffer: Vec::with_capacity(piece_length),
+            hashes: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let length = data.len().min(self.piece_length - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..length]);
+            data = &data[length..];
+            if self.buffer.len() == self.piece_length {
+                self.hashes.extend(Sha1::digest(&self.buffer));
+                self.buffer.clear();
+            }
+        }
+    }
+
+    /// Concatenated piece hashes, including the shorter last piece.
+    fn finish(mut self) -> Vec<u8> {
+        if !self.buffer.is_empty() {
+            self.hashes.extend(Sha1::digest(&self.buffer));
+        }
+        self.hashes
+    }
+}
+
+/// Pieces root of a non-empty file, and its piece layer when the file is
+/// larger than a piece.
+fn file_hashes(leaves: &[Hash], length: usize, piece_length: usize) -> (Hash, Option<Vec<Hash>>) {
+    if length <= piece_length {
+        return (
+            merkle::root(leaves, 0, leaves.len().next_power_of_two()),
+            None,
+        );
+    }
+    let blocks = piece_length / BLOCK_SIZE;
+    let layer = leaves
+        .chunks(blocks)
+        .map(|piece| merkle::root(piece, 0, blocks))
+        .collect::<Vec<_>>();
+    let pieces_root = merkle::root(
+        &layer,
+        merkle::piece_height(piece_length),
+        layer.len().next_power_of_two(),
+    );
+    (pieces_root, Some(layer))
+}
+
+fn file_entry(length: usize, path: &[String], padding: bool) -> Value {
+    let path = path
+        .iter()
+        .map(|component| Value::Bytes(component.as_bytes().to_vec()))
+        .collect();
+    let mut entry = HashMap::from([
+        (b"length".to_vec(), int(length)),
+        (b"path".to_vec(), Value::List(path)),
+    ]);
+    if padding {
+        entry.insert(b"attr".to_vec(), Value::Bytes(b"p".to_vec()));
+    }
+    Value::Dict(entry)
+}
+
+fn int(value: usize) -> Value {
+    Value::Int(value as i64)
+}
+
+/// Collects the paths of the files below `directory`, relative to the
+/// torrent's root. Symlinks are not followed, so that the torrent cannot
+/// pick up data from outside the directory, and are left out.
+fn walk_directory(
+    directory: &Path,
+    prefix: &mut Vec<String>,
+    paths: &mut Vec<Vec<String>>,
+) -> Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|name| Error::msg(format!("Invalid file name {name:?}")))?;
+        let file_type = entry.file_type()?;
+        prefix.push(name);
+        if file_type.is_dir() {
+            walk_directory(&entry.path(), prefix, paths)?;
+        } else if file_type.is_file() {
+            paths.push(prefix.clone());
+        } else {
+            eprintln!("Skipping {:?}: not a regular file", entry.path());
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{merkle::MerkleTrees, torrent_file::TorrentFile};
+
+    #[test]
+    fn pads_hybrid_files_like_v2() {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("a"), vec![1; 100]).unwrap();
+        std::fs::write(source.join("b"), b"").unwrap();
+        std::fs::write(source.join("c"), vec![2; BLOCK_SIZE + 1]).unwrap();
+        std::fs::write(source.join("d"), vec![3; BLOCK_SIZE + 5]).unwrap();
+        std::fs::write(source.join("e"), b"").unwrap();
+
+        let create = |version| {
+            let torrent = create_torrent(&source, version, BLOCK_SIZE, None, false).unwrap();
+            TorrentFile::from_bytes(&torrent).unwrap()
+        };
+        let files = |torrent: &TorrentFile| {
+            torrent
+                .info
+                .files()
+                .into_iter()
+                .map(|file| (file.path.join("/"), file.length, file.attr))
+                .collect::<Vec<_>>()
+        };
+        let torrent = create(MetaVersion::Hybrid);
+        // The v1 files, with their padding, match the padding inferred for
+        // the v2 files. Only the files followed by data are padded.
+        assert_eq!(files(&torrent), files(&create(MetaVersion::V2)));
+        assert_eq!(
+            files(&torrent)
+                .into_iter()
+                .filter(|(_, _, attr)| attr == "p")
+                .map(|(_, length, _)| length)
+                .collect::<Vec<_>>(),
+            [BLOCK_SIZE - 100, BLOCK_SIZE - 1]
+        );
+        assert!(MerkleTrees::new(&torrent).unwrap().is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_symlinks() {
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), b"secret").unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        std::fs::create_dir_all(source.join("sub")).unwrap();
+        std::fs::write(source.join("sub/file"), b"data").unwrap();
+        std::os::unix::fs::symlink(outside.path(), source.join("linked_dir")).unwrap();
+        std::os::unix::fs::symlink(outside.path().join("secret"), source.join("linked_file"))
+            .unwrap();
+
+        let mut paths = Vec::new();
+        walk_directory(&source, &mut Vec::new(), &mut paths).unwrap();
+        assert_eq!(paths, [["sub", "file"]]);
+    }
+}
@@ -58,3 +58,62 @@ pub fn decode_bencoded_value(encoded_value: &str) -> Result<(&str, Value)> {
         ))),
     }
 }
+
+/// Keys of a bencoded dictionary with the raw bytes of their values.
+pub fn dictionary_entries(bytes: &[u8]) -> Result<Vec<(Vec<u8>, &[u8])>> {
+    if bytes.first() != Some(&b'd') {
+        return Err(Error::msg("Bencoded value is not a dictionary"));
+    }
+    let mut entries = Vec::new();
+    let mut position = 1;
+    while bytes.get(position) != Some(&b'e') {
+        let key_end = value_end(bytes, position)?;
+        let key = &bytes[position..key_end];
+        let Some(colon) = key.iter().position(|byte| *byte == b':') else {
+            return Err(Error::msg(format!("Invalid dictionary key at {position}")));
+        };
+        let end = value_end(bytes, key_end)?;
+        entries.push((key[colon + 1..].to_vec(), &bytes[key_end..end]));
+        position = end;
+    }
+    if position + 1 != bytes.len() {
+        return Err(Error::msg("Unexpected data after the dictionary"));
+    }
+    Ok(entries)
+}
+
+/// Position just after the bencoded value starting at `start`.
+fn value_end(bytes: &[u8], start: usize) -> Result<usize> {
+    let invalid = || Error::msg(format!("Invalid bencoded value at {start}"));
+    match bytes.get(start).ok_or_else(invalid)? {
+        b'i' => {
+            let end = bytes[start..]
+                .iter()
+                .position(|byte| *byte == b'e')
+                .ok_or_else(invalid)?;
+            Ok(start + end + 1)
+        }
+        b'l' | b'd' => {
+            let mut position = start + 1;
+            while bytes.get(position).ok_or_else(invalid)? != &b'e' {
+                position = value_end(bytes, position)?;
+            }
+            Ok(position + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = bytes[start..]
+                .iter()
+                .position(|byte| *byte == b':')
+                .ok_or_else(invalid)?;
+            let length = std::str::from_utf8(&bytes[start..start + colon])
+                .ok()
+                .and_then(|length| length.parse::<usize>().ok())
+                .ok_or_else(invalid)?;
+            (start + colon + 1)
+                .checked_add(length)
+                .filter(|end| *end <= bytes.len())
+                .ok_or_else(invalid)
+        }
+        _ => Err(invalid()),
+    }
+}
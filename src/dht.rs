@@ -0,0 +1,1228 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket},
+    path::Path,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Error, Result};
+use rand::{seq::IteratorRandom, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bloom_filter::BloomFilter,
+    dht_item::{self, Item, Mutable},
+    krpc::{self, Arguments, Message, Response},
+    routing_table::{Node, NodeId, RoutingTable, K},
+    swarm::Swarm,
+    torrent_file::InfoHash,
+};
+
+pub const DEFAULT_DHT_PORT: u16 = 6881;
+pub const DEFAULT_BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+pub const DEFAULT_STATE_PATH: &str = "dht.dat";
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Number of nodes queried in parallel during a lookup.
+const ALPHA: usize = 3;
+const MAX_PACKET_SIZE: usize = 2048;
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+/// Announced peers are forgotten unless they announce again.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_STORED_PEERS: usize = 200;
+/// Maximum number of peers returned for a single `get_peers` query.
+const MAX_VALUES: usize = 50;
+/// Stored items are forgotten unless they are put again (BEP 44).
+const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+const MAX_STORED_ITEMS: usize = 1000;
+/// Maximum number of info hashes returned by `sample_infohashes`.
+const MAX_SAMPLES: usize = 20;
+/// Seconds other nodes should wait before asking us for new samples.
+const SAMPLE_INTERVAL: i64 = 6 * 60 * 60;
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// Number of nodes which must report the same external address before we
+/// believe it.
+const EXTERNAL_IP_VOTES: usize = 3;
+/// How often a download or seed looks up and announces itself again.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Mainline DHT node (BEP 5).
+pub struct Dht {
+    socket: UdpSocket,
+    next_transaction: AtomicU16,
+    pending: Mutex<HashMap<Vec<u8>, PendingQuery>>,
+    table: Mutex<RoutingTable>,
+    /// Peers which announced themselves to us, by info hash.
+    peers: Mutex<HashMap<InfoHash, Vec<StoredPeer>>>,
+    /// Items put to us by other nodes, by target.
+    items: Mutex<HashMap<NodeId, StoredItem>>,
+    /// When each node may be asked for new samples again (BEP 51).
+    next_sample: Mutex<HashMap<SocketAddrV4, Instant>>,
+    secrets: Mutex<Secrets>,
+    external_ip: Mutex<ExternalIp>,
+}
+
+/// Our external address as reported in the `ip` field of responses.
+#[derive(Default)]
+struct ExternalIp {
+    address: Option<Ipv4Addr>,
+    /// Nodes which reported each address.
+    votes: HashMap<Ipv4Addr, HashSet<SocketAddrV4>>,
+}
+
+struct PendingQuery {
+    address: SocketAddrV4,
+    sender: Sender<Result<Response>>,
+}
+
+struct StoredItem {
+    item: Item,
+    stored_at: Instant,
+}
+
+struct StoredPeer {
+    address: SocketAddrV4,
+    seed: bool,
+    announced_at: Instant,
+}
+
+/// Tokens are derived from the querying IP and a secret which changes every
+/// few minutes. Tokens made with the previous secret are still accepted.
+struct Secrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated_at: Instant,
+}
+
+/// Routing table saved between runs so we do not have to bootstrap from
+/// scratch.
+#[derive(Serialize, Deserialize)]
+struct DhtState {
+    id: ByteBuf,
+    nodes: ByteBuf,
+}
+
+pub struct GetPeers {
+    pub token: Option<Vec<u8>>,
+    pub peers: Vec<SocketAddrV4>,
+    pub nodes: Vec<Node>,
+    pub seeds_filter: Option<BloomFilter>,
+    pub peers_filter: Option<BloomFilter>,
+}
+
+/// Swarm size estimated from the bloom filters of the nodes closest to the
+/// info hash (BEP 33).
+pub struct Scrape {
+    pub seeds: f64,
+    pub leechers: f64,
+}
+
+/// Response to `sample_infohashes` (BEP 51).
+pub struct Samples {
+    /// Seconds before the node should be asked again.
+    pub interval: u64,
+    /// Number of info hashes the node stores.
+    pub num: usize,
+    pub samples: Vec<InfoHash>,
+    pub nodes: Vec<Node>,
+}
+
+/// Result of looking up an item: the most recent version found and the
+/// nodes which gave us a token to put it with.
+pub struct ItemLookup {
+    pub item: Option<Item>,
+    nodes: Vec<(Node, Vec<u8>)>,
+}
+
+/// Result of querying a single node during a lookup.
+struct LookupStep {
+    nodes: Vec<Node>,
+    peers: Vec<SocketAddrV4>,
+    token: Option<Vec<u8>>,
+}
+
+impl Dht {
+    /// Binds the node to a UDP port, restoring its id and routing table from
+    /// `state_path` when it exists.
+    pub fn bind(port: u16, state_path: &Path) -> Result<Arc<Self>> {
+        let state = std::fs::read(state_path)
+            .ok()
+            .and_then(|bytes| serde_bencode::from_bytes::<DhtState>(&bytes).ok());
+        let id = state
+            .as_ref()
+            .and_then(|state| NodeId::from_slice(&state.id))
+            .unwrap_or_else(NodeId::random);
+        let mut table = RoutingTable::new(id);
+        for node in state
+            .map(|state| krpc::decode_nodes(&state.nodes))
+            .unwrap_or_default()
+        {
+            table.insert(node.id, node.address);
+        }
+        let dht = Arc::new(Self {
+            socket: UdpSocket::bind(("0.0.0.0", port))?,
+            next_transaction: AtomicU16::new(0),
+            pending: Mutex::new(HashMap::new()),
+            table: Mutex::new(table),
+            peers: Mutex::new(HashMap::new()),
+            items: Mutex::new(HashMap::new()),
+            next_sample: Mutex::new(HashMap::new()),
+            secrets: Mutex::new(Secrets {
+                current: random_secret(),
+                previous: random_secret(),
+                rotated_at: Instant::now(),
+            }),
+            external_ip: Mutex::new(ExternalIp::default()),
+        });
+        let receiver = dht.clone();
+        std::thread::spawn(move || receiver.receive());
+        let refresher = dht.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(REFRESH_INTERVAL);
+            refresher.refresh();
+        });
+        Ok(dht)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.table.lock().unwrap().id()
+    }
+
+    pub fn port(&self) -> Result<u16> {
+        Ok(self.socket.local_addr()?.port())
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    pub fn save(&self, state_path: &Path) -> Result<()> {
+        let nodes = self.table.lock().unwrap().nodes();
+        let state = DhtState {
+            id: ByteBuf::from(self.id().0.to_vec()),
+            nodes: krpc::encode_nodes(&nodes),
+        };
+        std::fs::write(state_path, serde_bencode::to_bytes(&state)?)?;
+        Ok(())
+    }
+
+    /// Fills the routing table with nodes close to our id, starting from the
+    /// given well-known nodes (`host:port`).
+    pub fn bootstrap(&self, nodes: &[String]) {
+        let addresses = nodes
+            .iter()
+            .filter_map(|node| node.to_socket_addrs().ok())
+            .flatten()
+            .filter_map(krpc::as_v4)
+            .collect::<Vec<_>>();
+        let target = self.id();
+        std::thread::scope(|scope| {
+            for address in addresses {
+                scope.spawn(move || {
+                    if let Ok(nodes) = self.find_node(address, &target) {
+                        let mut table = self.table.lock().unwrap();
+                        for node in nodes {
+                            table.insert(node.id, node.address);
+                        }
+                    }
+                });
+            }
+        });
+        let target = self.id();
+        self.lookup(&target, |node| {
+            let nodes = self.find_node(node.address, &target)?;
+            Ok(LookupStep {
+                nodes,
+                peers: Vec::new(),
+                token: None,
+            })
+        });
+    }
+
+    /// Looks up peers for the info hash and, when `port` is given, announces
+    /// that we accept connections for it on that port.
+    pub fn find_peers(
+        &self,
+        info_hash: &InfoHash,
+        port: Option<u16>,
+        seed: bool,
+    ) -> Vec<SocketAddrV4> {
+        let target = NodeId(info_hash.0);
+        let (peers, responded) = self.lookup(&target, |node| {
+            let response = self.get_peers(node.address, info_hash, false)?;
+            Ok(LookupStep {
+                nodes: response.nodes,
+                peers: response.peers,
+                token: response.token,
+            })
+        });
+        if let Some(port) = port {
+            std::thread::scope(|scope| {
+                for (node, token) in responded.iter().take(K) {
+                    scope.spawn(move || {
+                        _ = self.announce_peer(node.address, info_hash, port, seed, token);
+                    });
+                }
+            });
+        }
+        peers
+    }
+
+    /// Estimates the number of seeds and leechers in the swarm without a
+    /// tracker.
+    pub fn scrape(&self, info_hash: &InfoHash) -> Scrape {
+        let target = NodeId(info_hash.0);
+        let filters = Mutex::new(Vec::new());
+        self.lookup(&target, |node| {
+            let response = self.get_peers(node.address, info_hash, true)?;
+            if let (Some(seeds), Some(peers)) = (response.seeds_filter, response.peers_filter) {
+                filters.lock().unwrap().push((node.id, seeds, peers));
+            }
+            Ok(LookupStep {
+                nodes: response.nodes,
+                peers: Vec::new(),
+                token: response.token,
+            })
+        });
+        // Only the closest nodes are expected to have the whole swarm.
+        let mut filters = filters.into_inner().unwrap();
+        filters.sort_by(|(lhs, _, _), (rhs, _, _)| target.cmp_distance(lhs, rhs));
+        let mut seeds = BloomFilter::new();
+        let mut leechers = BloomFilter::new();
+        for (_, node_seeds, node_leechers) in filters.iter().take(K) {
+            seeds.union(node_seeds);
+            leechers.union(node_leechers);
+        }
+        Scrape {
+            seeds: seeds.estimate(),
+            leechers: leechers.estimate(),
+        }
+    }
+
+    /// Collects info hashes stored by the nodes around the target (BEP 51).
+    /// Returns the samples and the total number of info hashes the sampled
+    /// nodes reported. Nodes are not sampled again before the interval they
+    /// asked for.
+    pub fn sample_swarms(&self, target: &NodeId) -> (Vec<InfoHash>, usize) {
+        let sampled = Mutex::new((HashSet::new(), 0));
+        self.lookup(target, |node| {
+            let next_sample = self.next_sample.lock().unwrap().get(&node.address).copied();
+            if next_sample.is_some_and(|next_sample| next_sample > Instant::now()) {
+                let nodes = self.find_node(node.address, target)?;
+                return Ok(LookupStep {
+                    nodes,
+                    peers: Vec::new(),
+                    token: None,
+                });
+            }
+            let response = self.sample_infohashes(node.address, target)?;
+            self.next_sample.lock().unwrap().insert(
+                node.address,
+                Instant::now() + Duration::from_secs(response.interval),
+            );
+            let mut sampled = sampled.lock().unwrap();
+            sampled.0.extend(response.samples);
+            sampled.1 += response.num;
+            Ok(LookupStep {
+                nodes: response.nodes,
+                peers: Vec::new(),
+                token: None,
+            })
+        });
+        let (samples, num) = sampled.into_inner().unwrap();
+        (samples.into_iter().collect(), num)
+    }
+
+    /// Looks up an item by target. `salt` is only used for mutable items,
+    /// since nodes do not return it.
+    pub fn get_item(&self, target: &NodeId, salt: &[u8]) -> ItemLookup {
+        let best = Mutex::new(None::<Item>);
+        let (_, nodes) = self.lookup(target, |node| {
+            let (step, item) = self.get(node.address, target, salt)?;
+            let valid = item
+                .filter(|item| item.verify().is_ok())
+                .filter(|item| item.target().is_ok_and(|item| &item == target));
+            if let Some(item) = valid {
+                let mut best = best.lock().unwrap();
+                if best.as_ref().is_none_or(|best| item.seq() > best.seq()) {
+                    *best = Some(item);
+                }
+            }
+            Ok(step)
+        });
+        ItemLookup {
+            item: best.into_inner().unwrap(),
+            nodes,
+        }
+    }
+
+    /// Stores the item on the closest nodes found by `lookup`. With `cas`
+    /// a mutable item only replaces the version with that sequence number.
+    /// Returns the number of nodes which accepted the item.
+    pub fn put_item(&self, lookup: &ItemLookup, item: &Item, cas: Option<i64>) -> usize {
+        std::thread::scope(|scope| {
+            let handles = lookup
+                .nodes
+                .iter()
+                .take(K)
+                .map(|(node, token)| scope.spawn(move || self.put(node.address, token, item, cas)))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join())
+                .filter(|result| result.as_ref().is_ok_and(|result| result.is_ok()))
+                .count()
+        })
+    }
+
+    /// Iterative Kademlia lookup. Returns the peers found and the nodes which
+    /// responded with a token, closest first.
+    fn lookup<F>(&self, target: &NodeId, query: F) -> (Vec<SocketAddrV4>, Vec<(Node, Vec<u8>)>)
+    where
+        F: Fn(&Node) -> Result<LookupStep> + Sync,
+    {
+        let mut shortlist = self.table.lock().unwrap().closest(target, K);
+        let mut queried = HashSet::new();
+        let mut peers = Vec::new();
+        let mut responded = Vec::new();
+        loop {
+            shortlist.sort_by(|lhs, rhs| target.cmp_distance(&lhs.id, &rhs.id));
+            shortlist.dedup_by(|lhs, rhs| lhs.id == rhs.id);
+            let batch = shortlist
+                .iter()
+                .take(K)
+                .filter(|node| !queried.contains(&node.address))
+                .take(ALPHA)
+                .cloned()
+                .collect::<Vec<_>>();
+            if batch.is_empty() {
+                break;
+            }
+            queried.extend(batch.iter().map(|node| node.address));
+            let results = std::thread::scope(|scope| {
+                let handles = batch
+                    .iter()
+                    .map(|node| scope.spawn(|| query(node)))
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect::<Vec<_>>()
+            });
+            for (node, result) in batch.into_iter().zip(results) {
+                match result {
+                    Ok(step) => {
+                        shortlist.extend(step.nodes);
+                        for peer in step.peers {
+                            if !peers.contains(&peer) {
+                                peers.push(peer);
+                            }
+                        }
+                        if let Some(token) = step.token {
+                            responded.push((node, token));
+                        }
+                    }
+                    Err(_) => shortlist.retain(|other| other.address != node.address),
+                }
+            }
+        }
+        responded.sort_by(|(lhs, _), (rhs, _)| target.cmp_distance(&lhs.id, &rhs.id));
+        (peers, responded)
+    }
+
+    pub fn ping(&self, address: SocketAddrV4) -> Result<NodeId> {
+        let response = self.query(address, "ping", Arguments::new(&self.id()))?;
+        node_id(&response.id)
+    }
+
+    pub fn find_node(&self, address: SocketAddrV4, target: &NodeId) -> Result<Vec<Node>> {
+        let mut arguments = Arguments::new(&self.id());
+        arguments.target = Some(ByteBuf::from(target.0.to_vec()));
+        let response = self.query(address, "find_node", arguments)?;
+        Ok(response
+            .nodes
+            .map(|nodes| krpc::decode_nodes(&nodes))
+            .unwrap_or_default())
+    }
+
+    pub fn get_peers(
+        &self,
+        address: SocketAddrV4,
+        info_hash: &InfoHash,
+        scrape: bool,
+    ) -> Result<GetPeers> {
+        let mut arguments = Arguments::new(&self.id());
+        arguments.info_hash = Some(ByteBuf::from(info_hash.0.to_vec()));
+        if scrape {
+            arguments.scrape = Some(1);
+        }
+        let response = self.query(address, "get_peers", arguments)?;
+        Ok(GetPeers {
+            token: response.token.map(ByteBuf::into_vec),
+            peers: response
+                .values
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|peer| krpc::decode_peer(peer))
+                .collect(),
+            nodes: response
+                .nodes
+                .map(|nodes| krpc::decode_nodes(&nodes))
+                .unwrap_or_default(),
+            seeds_filter: response
+                .seeds_filter
+                .and_then(|filter| BloomFilter::from_bytes(&filter)),
+            peers_filter: response
+                .peers_filter
+                .and_then(|filter| BloomFilter::from_bytes(&filter)),
+        })
+    }
+
+    pub fn announce_peer(
+        &self,
+        address: SocketAddrV4,
+        info_hash: &InfoHash,
+        port: u16,
+        seed: bool,
+        token: &[u8],
+    ) -> Result<()> {
+        let mut arguments = Arguments::new(&self.id());
+        arguments.info_hash = Some(ByteBuf::from(info_hash.0.to_vec()));
+        arguments.port = Some(port);
+        if seed {
+            arguments.seed = Some(1);
+        }
+        arguments.token = Some(ByteBuf::from(token));
+        self.query(address, "announce_peer", arguments)?;
+        Ok(())
+    }
+
+    pub fn sample_infohashes(&self, address: SocketAddrV4, target: &NodeId) -> Result<Samples> {
+        let mut arguments = Arguments::new(&self.id());
+        arguments.target = Some(ByteBuf::from(target.0.to_vec()));
+        let response = self.query(address, "sample_infohashes", arguments)?;
+        Ok(Samples {
+            interval: response.interval.unwrap_or_default().max(0) as u64,
+            num: response.num.unwrap_or_default().max(0) as usize,
+            samples: response
+                .samples
+                .unwrap_or_default()
+                .chunks_exact(20)
+                .filter_map(info_hash)
+                .collect(),
+            nodes: response
+                .nodes
+                .map(|nodes| krpc::decode_nodes(&nodes))
+                .unwrap_or_default(),
+        })
+    }
+
+    fn get(
+        &self,
+        address: SocketAddrV4,
+        target: &NodeId,
+        salt: &[u8],
+    ) -> Result<(LookupStep, Option<Item>)> {
+        let mut arguments = Arguments::new(&self.id());
+        arguments.target = Some(ByteBuf::from(target.0.to_vec()));
+        let response = self.query(address, "get", arguments)?;
+        let item = match (response.v, response.k) {
+            (Some(value), Some(public_key)) => Some(Item {
+                value,
+                mutable: Some(Mutable {
+                    public_key: public_key.as_slice().try_into()?,
+                    salt: salt.to_vec(),
+                    seq: response
+                        .seq
+                        .ok_or_else(|| Error::msg("Mutable item has no seq"))?,
+                    signature: response
+                        .sig
+                        .ok_or_else(|| Error::msg("Mutable item has no signature"))?
+                        .as_slice()
+                        .try_into()?,
+                }),
+            }),
+            (Some(value), None) => Some(Item {
+                value,
+                mutable: None,
+            }),
+            (None, _) => None,
+        };
+        let step = LookupStep {
+            nodes: response
+                .nodes
+                .map(|nodes| krpc::decode_nodes(&nodes))
+                .unwrap_or_default(),
+            peers: Vec::new(),
+            token: response.token.map(ByteBuf::into_vec),
+        };
+        Ok((step, item))
+    }
+
+    fn put(
+        &self,
+        address: SocketAddrV4,
+        token: &[u8],
+        item: &Item,
+        cas: Option<i64>,
+    ) -> Result<()> {
+        let mut arguments = Arguments::new(&self.id());
+        arguments.token = Some(ByteBuf::from(token));
+        arguments.v = Some(item.value.clone());
+        if let Some(mutable) = &item.mutable {
+            arguments.k = Some(ByteBuf::from(mutable.public_key.to_vec()));
+            if !mutable.salt.is_empty() {
+                arguments.salt = Some(ByteBuf::from(mutable.salt.clone()));
+            }
+            arguments.seq = Some(mutable.seq);
+            arguments.sig = Some(ByteBuf::from(mutable.signature.to_vec()));
+            arguments.cas = cas;
+        }
+        self.query(address, "put", arguments)?;
+        Ok(())
+    }
+
+    /// Adds a node learned from a peer's `Port` message once it responds.
+    pub fn add_node(&self, address: SocketAddrV4) {
+        _ = self.ping(address);
+    }
+
+    fn query(&self, address: SocketAddrV4, method: &str, arguments: Arguments) -> Result<Response> {
+        let transaction = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+            .to_vec();
+        let (sender, receiver) = mpsc::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(transaction.clone(), PendingQuery { address, sender });
+        let message = Message::query(&transaction, method, arguments);
+        let result = self
+            .send(address, &message)
+            .and_then(|_| Ok(receiver.recv_timeout(QUERY_TIMEOUT)?));
+        self.pending.lock().unwrap().remove(&transaction);
+        match result {
+            Ok(response) => response,
+            Err(error) => {
+                self.table.lock().unwrap().fail(&address);
+                Err(error)
+            }
+        }
+    }
+
+    fn send(&self, address: SocketAddrV4, message: &Message) -> Result<()> {
+        self.socket
+            .send_to(&serde_bencode::to_bytes(message)?, address)?;
+        Ok(())
+    }
+
+    fn receive(&self) {
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        loop {
+            let Ok((size, address)) = self.socket.recv_from(&mut buffer) else {
+                continue;
+            };
+            let Some(address) = krpc::as_v4(address) else {
+                continue;
+            };
+            let Ok(message) = serde_bencode::from_bytes::<Message>(&buffer[..size]) else {
+                continue;
+            };
+            match message.y.as_str() {
+                "q" => {
+                    let mut reply = self.handle_query(address, &message);
+                    reply.ip = Some(ByteBuf::from(krpc::encode_peer(&address).to_vec()));
+                    _ = self.send(address, &reply);
+                }
+                "r" | "e" => self.handle_response(address, message),
+                _ => {}
+            }
+        }
+    }
+
+    fn handle_response(&self, address: SocketAddrV4, message: Message) {
+        // A response from another address may be spoofed, so it leaves the
+        // query pending for the real node's response.
+        let pending = {
+            let mut pending = self.pending.lock().unwrap();
+            let t = message.t.as_slice();
+            if pending
+                .get(t)
+                .is_none_or(|pending| pending.address != address)
+            {
+                return;
+            }
+            pending.remove(t).unwrap()
+        };
+        if let Some(ip) = message.ip.as_ref().and_then(|ip| krpc::decode_peer(ip)) {
+            self.vote_external_ip(address, *ip.ip());
+        }
+        let result = match (message.r, message.e) {
+            (Some(response), _) => match node_id(&response.id) {
+                Ok(id) => {
+                    self.table.lock().unwrap().insert(id, address);
+                    Ok(response)
+                }
+                Err(error) => Err(error),
+            },
+            (None, Some((code, description))) => Err(Error::msg(format!(
+                "DHT node {address} responded with error {code}: {description}"
+            ))),
+            (None, None) => Err(Error::msg("DHT response has no body")),
+        };
+        _ = pending.sender.send(result);
+    }
+
+    fn handle_query(&self, address: SocketAddrV4, message: &Message) -> Message {
+        let t = message.t.as_slice();
+        let (Some(method), Some(arguments)) = (&message.q, &message.a) else {
+            return Message::error(t, krpc::ERROR_PROTOCOL, "Malformed query");
+        };
+        let Ok(id) = node_id(&arguments.id) else {
+            return Message::error(t, krpc::ERROR_PROTOCOL, "Invalid node id");
+        };
+        // Nodes querying us are reachable, so they can join the routing table
+        // like the ones responding to us.
+        self.table.lock().unwrap().insert(id, address);
+        let mut response = Response::new(&self.id());
+        match method.as_str() {
+            "ping" => {}
+            "find_node" => {
+                let Some(target) = arguments
+                    .target
+                    .as_ref()
+                    .and_then(|target| NodeId::from_slice(target))
+                else {
+                    return Message::error(t, krpc::ERROR_PROTOCOL, "Missing target");
+                };
+                response.nodes = Some(self.closest_nodes(&target));
+            }
+            "get_peers" => {
+                let Some(info_hash) = arguments
+                    .info_hash
+                    .as_ref()
+                    .and_then(|bytes| info_hash(bytes))
+                else {
+                    return Message::error(t, krpc::ERROR_PROTOCOL, "Missing info_hash");
+                };
+                response.token = Some(ByteBuf::from(self.token(&address)));
+                if arguments.scrape == Some(1) {
+                    let (seeds, leechers) = self.scrape_filters(&info_hash);
+                    response.seeds_filter = Some(ByteBuf::from(seeds.as_bytes()));
+                    response.peers_filter = Some(ByteBuf::from(leechers.as_bytes()));
+                }
+                let peers = self.stored_peers(&info_hash, arguments.noseed == Some(1));
+                if peers.is_empty() {
+                    response.nodes = Some(self.closest_nodes(&NodeId(info_hash.0)));
+                } else {
+                    response.values = Some(peers);
+                }
+            }
+            "announce_peer" => {
+                let Some(info_hash) = arguments
+                    .info_hash
+                    .as_ref()
+                    .and_then(|bytes| info_hash(bytes))
+                else {
+                    return Message::error(t, krpc::ERROR_PROTOCOL, "Missing info_hash");
+                };
+                let valid = arguments
+                    .token
+                    .as_deref()
+                    .is_some_and(|token| self.is_valid_token(&address, token));
+                if !valid {
+                    return Message::error(t, krpc::ERROR_PROTOCOL, "Bad token");
+                }
+                let port = if arguments.implied_port == Some(1) {
+                    address.port()
+                } else {
+                    match arguments.port {
+                        Some(port) => port,
+                        None => return Message::error(t, krpc::ERROR_PROTOCOL, "Missing port"),
+                    }
+                };
+                let address = SocketAddrV4::new(*address.ip(), port);
+                self.store_peer(info_hash, address, arguments.seed == Some(1));
+            }
+            "sample_infohashes" => {
+                let Some(target) = arguments
+                    .target
+                    .as_ref()
+                    .and_then(|target| NodeId::from_slice(target))
+                else {
+                    return Message::error(t, krpc::ERROR_PROTOCOL, "Missing target");
+                };
+                let (num, samples) = self.sample_stored();
+                response.nodes = Some(self.closest_nodes(&target));
+                response.interval = Some(SAMPLE_INTERVAL);
+                response.num = Some(num as i64);
+                response.samples = Some(ByteBuf::from(samples));
+            }
+            "get" => {
+                let Some(target) = arguments
+                    .target
+                    .as_ref()
+                    .and_then(|target| NodeId::from_slice(target))
+                else {
+                    return Message::error(t, krpc::ERROR_PROTOCOL, "Missing target");
+                };
+                response.token = Some(ByteBuf::from(self.token(&address)));
+                response.nodes = Some(self.closest_nodes(&target));
+                if let Some(item) = self.stored_item(&target) {
+                    response.seq = item.seq();
+                    // Requesters which already have this version only want
+                    // to know its sequence number.
+                    let outdated = item
+                        .seq()
+                        .zip(arguments.seq)
+                        .is_some_and(|(stored, seq)| stored <= seq);
+                    if !outdated {
+                        response.v = Some(item.value);
+                        if let Some(mutable) = item.mutable {
+                            response.k = Some(ByteBuf::from(mutable.public_key.to_vec()));
+                            response.sig = Some(ByteBuf::from(mutable.signature.to_vec()));
+                        }
+                    }
+                }
+            }
+            "put" => {
+                let valid = arguments
+                    .token
+                    .as_deref()
+                    .is_some_and(|token| self.is_valid_token(&address, token));
+                if !valid {
+                    return Message::error(t, krpc::ERROR_PROTOCOL, "Bad token");
+                }
+                let item = match put_item(arguments) {
+                    Ok(item) => item,
+                    Err(error) => {
+                        return Message::error(t, krpc::ERROR_PROTOCOL, &error.to_string())
+                    }
+                };
+                if let Err((code, description)) = item.verify() {
+                    return Message::error(t, code, description);
+                }
+                if let Err((code, description)) = self.store_item(item, arguments.cas) {
+                    return Message::error(t, code, description);
+                }
+            }
+            _ => return Message::error(t, krpc::ERROR_METHOD_UNKNOWN, "Method Unknown"),
+        }
+        Message::response(t, response)
+    }
+
+    /// Counts a node's report of our external address. Once enough nodes
+    /// agree on a new address our id is regenerated from it if it no longer
+    /// matches (BEP 42).
+    fn vote_external_ip(&self, voter: SocketAddrV4, ip: Ipv4Addr) {
+        let mut external_ip = self.external_ip.lock().unwrap();
+        for (address, voters) in external_ip.votes.iter_mut() {
+            if *address != ip {
+                voters.remove(&voter);
+            }
+        }
+        let voters = external_ip.votes.entry(ip).or_default();
+        voters.insert(voter);
+        if voters.len() < EXTERNAL_IP_VOTES || external_ip.address == Some(ip) {
+            return;
+        }
+        external_ip.address = Some(ip);
+        let mut table = self.table.lock().unwrap();
+        if !table.id().is_valid_for(&ip) {
+            table.set_id(NodeId::secure(ip));
+        }
+    }
+
+    fn closest_nodes(&self, target: &NodeId) -> ByteBuf {
+        krpc::encode_nodes(&self.table.lock().unwrap().closest(target, K))
+    }
+
+    /// Peers announced for the info hash, newest first. With `noseed` only
+    /// the ones still downloading.
+    fn stored_peers(&self, info_hash: &InfoHash, noseed: bool) -> Vec<ByteBuf> {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(stored) = peers.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        stored.retain(|peer| peer.announced_at.elapsed() < PEER_TTL);
+        stored
+            .iter()
+            .rev()
+            .filter(|peer| !noseed || !peer.seed)
+            .take(MAX_VALUES)
+            .map(|peer| ByteBuf::from(krpc::encode_peer(&peer.address).to_vec()))
+            .collect()
+    }
+
+    /// Bloom filters of the seeds and leechers announced for the info hash.
+    fn scrape_filters(&self, info_hash: &InfoHash) -> (BloomFilter, BloomFilter) {
+        let mut seeds = BloomFilter::new();
+        let mut leechers = BloomFilter::new();
+        let peers = self.peers.lock().unwrap();
+        let live = peers
+            .get(info_hash)
+            .into_iter()
+            .flatten()
+            .filter(|peer| peer.announced_at.elapsed() < PEER_TTL);
+        for peer in live {
+            let ip = IpAddr::V4(*peer.address.ip());
+            if peer.seed {
+                seeds.insert(&ip);
+            } else {
+                leechers.insert(&ip);
+            }
+        }
+        (seeds, leechers)
+    }
+
+    /// Number of info hashes we store peers for and a random sample of them
+    /// in compact form.
+    fn sample_stored(&self) -> (usize, Vec<u8>) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|_, stored| {
+            stored.retain(|peer| peer.announced_at.elapsed() < PEER_TTL);
+            !stored.is_empty()
+        });
+        let samples = peers
+            .keys()
+            .choose_multiple(&mut rand::thread_rng(), MAX_SAMPLES)
+            .into_iter()
+            .flat_map(|info_hash| info_hash.0)
+            .collect();
+        (peers.len(), samples)
+    }
+
+    fn store_peer(&self, info_hash: InfoHash, address: SocketAddrV4, seed: bool) {
+        let mut peers = self.peers.lock().unwrap();
+        let stored = peers.entry(info_hash).or_default();
+        stored.retain(|peer| peer.address != address);
+        stored.push(StoredPeer {
+            address,
+            seed,
+            announced_at: Instant::now(),
+        });
+        if stored.len() > MAX_STORED_PEERS {
+            stored.remove(0);
+        }
+    }
+
+    fn stored_item(&self, target: &NodeId) -> Option<Item> {
+        let mut items = self.items.lock().unwrap();
+        items.retain(|_, stored| stored.stored_at.elapsed() < ITEM_TTL);
+        items.get(target).map(|stored| stored.item.clone())
+    }
+
+    /// Stores a verified item unless it is older than the version we have.
+    fn store_item(
+        &self,
+        item: Item,
+        cas: Option<i64>,
+    ) -> std::result::Result<(), (i64, &'static str)> {
+        let target = item
+            .target()
+            .map_err(|_| (krpc::ERROR_PROTOCOL, "Invalid item"))?;
+        let mut items = self.items.lock().unwrap();
+        if let Some(stored) = items.get(&target) {
+            if cas.is_some_and(|cas| stored.item.seq() != Some(cas)) {
+                return Err((dht_item::ERROR_CAS_MISMATCH, "CAS mismatch"));
+            }
+            let replaced = item.seq() > stored.item.seq()
+                || (item.seq() == stored.item.seq() && item.value == stored.item.value);
+            if !replaced {
+                return Err((
+                    dht_item::ERROR_SEQ_TOO_LOW,
+                    "Sequence number less than current",
+                ));
+            }
+        } else if items.len() >= MAX_STORED_ITEMS {
+            let oldest = items
+                .iter()
+                .min_by_key(|(_, stored)| stored.stored_at)
+                .map(|(target, _)| *target);
+            if let Some(oldest) = oldest {
+                items.remove(&oldest);
+            }
+        }
+        items.insert(
+            target,
+            StoredItem {
+                item,
+                stored_at: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    fn token(&self, address: &SocketAddrV4) -> Vec<u8> {
+        let mut secrets = self.secrets.lock().unwrap();
+        secrets.rotate();
+        make_token(&secrets.current, address)
+    }
+
+    fn is_valid_token(&self, address: &SocketAddrV4, token: &[u8]) -> bool {
+        let mut secrets = self.secrets.lock().unwrap();
+        secrets.rotate();
+        token == make_token(&secrets.current, address)
+            || token == make_token(&secrets.previous, address)
+    }
+
+    /// Pings nodes we have not heard from for a while, so that dead ones
+    /// can be replaced.
+    fn refresh(&self) {
+        let questionable = self.table.lock().unwrap().questionable();
+        for nodes in questionable.chunks(ALPHA * K) {
+            std::thread::scope(|scope| {
+                for node in nodes {
+                    scope.spawn(|| self.ping(node.address));
+                }
+            });
+        }
+    }
+}
+
+impl Secrets {
+    fn rotate(&mut self) {
+        if self.rotated_at.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = random_secret();
+            self.rotated_at = Instant::now();
+        }
+    }
+}
+
+/// Periodically looks up peers for the swarm and announces it to the DHT.
+pub fn spawn_announcer(dht: Arc<Dht>, swarm: Arc<Swarm>) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(ANNOUNCE_INTERVAL);
+        let peers = dht.find_peers(&swarm.info_hash, Some(swarm.port), swarm.is_complete());
+        swarm.add_candidates(peers.into_iter().map(SocketAddr::V4));
+    });
+}
+
+fn make_token(secret: &[u8], address: &SocketAddrV4) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    hasher.update(address.ip().octets());
+    hasher.finalize().to_vec()
+}
+
+fn random_secret() -> [u8; 20] {
+    let mut secret = [0; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    secret
+}
+
+/// Item carried by the arguments of a `put` query.
+fn put_item(arguments: &Arguments) -> Result<Item> {
+    let value = arguments.v.clone().ok_or_else(|| Error::msg("Missing v"))?;
+    let Some(public_key) = &arguments.k else {
+        return Ok(Item {
+            value,
+            mutable: None,
+        });
+    };
+    Ok(Item {
+        value,
+        mutable: Some(Mutable {
+            public_key: public_key
+                .as_slice()
+                .try_into()
+                .map_err(|_| Error::msg("Invalid k"))?,
+            salt: arguments
+                .salt
+                .as_ref()
+                .map(|salt| salt.to_vec())
+                .unwrap_or_default(),
+            seq: arguments.seq.ok_or_else(|| Error::msg("Missing seq"))?,
+            signature: arguments
+                .sig
+                .as_ref()
+                .and_then(|sig| sig.as_slice().try_into().ok())
+                .ok_or_else(|| Error::msg("Invalid sig"))?,
+        }),
+    })
+}
+
+fn node_id(bytes: &[u8]) -> Result<NodeId> {
+    NodeId::from_slice(bytes).ok_or_else(|| Error::msg("DHT node id must be 20 bytes"))
+}
+
+fn info_hash(bytes: &[u8]) -> Option<InfoHash> {
+    Some(InfoHash(bytes.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start() -> (Arc<Dht>, SocketAddrV4) {
+        let directory = tempfile::tempdir().unwrap();
+        let dht = Dht::bind(0, &directory.path().join("dht.dat")).unwrap();
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, dht.port().unwrap());
+        (dht, address)
+    }
+
+    #[test]
+    fn bootstrap_and_find_node() {
+        let (router, router_address) = start();
+        let (first, first_address) = start();
+        let (second, _) = start();
+        first.bootstrap(&[router_address.to_string()]);
+        second.bootstrap(&[router_address.to_string()]);
+
+        // The router learns about nodes from their queries.
+        assert_eq!(router.node_count(), 2);
+        // The second node finds the first one through the router.
+        assert!(second
+            .table
+            .lock()
+            .unwrap()
+            .nodes()
+            .iter()
+            .any(|node| node.id == first.id() && node.address == first_address));
+        let nodes = second.find_node(router_address, &first.id()).unwrap();
+        assert_eq!(nodes[0].id, first.id());
+        assert_eq!(nodes[0].address, first_address);
+    }
+
+    #[test]
+    fn ignores_responses_from_other_addresses() {
+        let (dht, _) = start();
+        let node = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 7000);
+        let spoofer = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 7001);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        dht.pending.lock().unwrap().insert(
+            b"aa".to_vec(),
+            PendingQuery {
+                address: node,
+                sender,
+            },
+        );
+        let response = || Message::response(b"aa", Response::new(&NodeId([1; 20])));
+
+        dht.handle_response(spoofer, response());
+        assert!(receiver.try_recv().is_err());
+        dht.handle_response(node, response());
+        assert!(receiver.try_recv().unwrap().is_ok());
+        assert!(dht.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn announce_and_get_peers() {
+        let (_, node_address) = start();
+        let (_, other_address) = start();
+        let (first, _) = start();
+        let (second, _) = start();
+        let info_hash = InfoHash([7; 20]);
+
+        let response = first.get_peers(node_address, &info_hash, false).unwrap();
+        assert!(response.peers.is_empty());
+        let token = response.token.unwrap();
+        assert!(first
+            .announce_peer(node_address, &info_hash, 6000, false, b"invalid")
+            .is_err());
+        // Tokens are only valid for the node which handed them out.
+        assert!(first
+            .announce_peer(other_address, &info_hash, 6000, false, &token)
+            .is_err());
+        first
+            .announce_peer(node_address, &info_hash, 6000, false, &token)
+            .unwrap();
+
+        let response = second.get_peers(node_address, &info_hash, false).unwrap();
+        assert_eq!(
+            response.peers,
+            vec![SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6000)]
+        );
+    }
+
+    #[test]
+    fn save_and_restore_state() {
+        let (router, router_address) = start();
+        let (node, _) = start();
+        node.bootstrap(&[router_address.to_string()]);
+
+        let directory = tempfile::tempdir().unwrap();
+        let state_path = directory.path().join("dht.dat");
+        node.save(&state_path).unwrap();
+        let restored = Dht::bind(0, &state_path).unwrap();
+        assert_eq!(restored.id(), node.id());
+        assert_eq!(restored.node_count(), 1);
+        assert_eq!(restored.table.lock().unwrap().nodes()[0].id, router.id());
+    }
+
+    #[test]
+    fn put_and_get_mutable_items() {
+        let (router, router_address) = start();
+        let (first, _) = start();
+        let (second, _) = start();
+        first.bootstrap(&[router_address.to_string()]);
+        second.bootstrap(&[router_address.to_string()]);
+        let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let item = |seq, value: &str| {
+            let value = serde_bencode::value::Value::Bytes(value.as_bytes().to_vec());
+            Item::mutable(&key, b"salt".to_vec(), seq, value).unwrap()
+        };
+        let target = item(1, "").target().unwrap();
+
+        let lookup = first.get_item(&target, b"salt");
+        assert!(lookup.item.is_none());
+        assert!(first.put_item(&lookup, &item(2, "second"), None) > 0);
+        let lookup = second.get_item(&target, b"salt");
+        let found = lookup.item.as_ref().unwrap();
+        assert_eq!(found.seq(), Some(2));
+        assert_eq!(found.value, item(2, "second").value);
+
+        // Older versions and writes based on an outdated version are refused.
+        assert_eq!(second.put_item(&lookup, &item(1, "first"), None), 0);
+        assert_eq!(second.put_item(&lookup, &item(3, "third"), Some(1)), 0);
+        assert_eq!(
+            router.store_item(item(1, "first"), None).unwrap_err().0,
+            dht_item::ERROR_SEQ_TOO_LOW
+        );
+        assert_eq!(
+            router.store_item(item(3, "third"), Some(1)).unwrap_err().0,
+            dht_item::ERROR_CAS_MISMATCH
+        );
+        assert!(second.put_item(&lookup, &item(3, "third"), Some(2)) > 0);
+        let lookup = first.get_item(&target, b"salt");
+        assert_eq!(lookup.item.unwrap().seq(), Some(3));
+    }
+
+    #[test]
+    fn samples_stored_info_hashes() {
+        let (node, node_address) = start();
+        let (sampler, _) = start();
+        let peer = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6000);
+        for byte in 0..30 {
+            node.store_peer(InfoHash([byte; 20]), peer, false);
+        }
+
+        let response = sampler
+            .sample_infohashes(node_address, &NodeId([0; 20]))
+            .unwrap();
+        assert_eq!(response.num, 30);
+        assert_eq!(response.interval, SAMPLE_INTERVAL as u64);
+        assert_eq!(response.samples.len(), MAX_SAMPLES);
+        let distinct = response
+            .samples
+            .iter()
+            .map(|info_hash| info_hash.0)
+            .collect::<HashSet<_>>();
+        assert_eq!(distinct.len(), MAX_SAMPLES);
+        assert!(distinct.iter().all(|info_hash| info_hash[0] < 30));
+    }
+}
@@ -0,0 +1,258 @@
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+use anyhow::{Error, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::RngCore;
+use serde_bencode::value::Value;
+use sha1::{Digest, Sha1};
+
+use crate::{decode, routing_table::NodeId};
+
+/// Maximum size of a bencoded item value.
+pub const MAX_VALUE_SIZE: usize = 1000;
+pub const MAX_SALT_SIZE: usize = 64;
+
+pub const ERROR_VALUE_TOO_BIG: i64 = 205;
+pub const ERROR_INVALID_SIGNATURE: i64 = 206;
+pub const ERROR_SALT_TOO_BIG: i64 = 207;
+pub const ERROR_CAS_MISMATCH: i64 = 301;
+pub const ERROR_SEQ_TOO_LOW: i64 = 302;
+
+/// Data stored in the DHT (BEP 44). Immutable items are addressed by the
+/// hash of their value, mutable ones by the hash of their public key and
+/// salt.
+#[derive(Debug, Clone)]
+pub struct Item {
+    pub value: Value,
+    pub mutable: Option<Mutable>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Mutable {
+    pub public_key: [u8; 32],
+    pub salt: Vec<u8>,
+    pub seq: i64,
+    pub signature: [u8; 64],
+}
+
+impl Item {
+    pub fn immutable(value: Value) -> Result<Self> {
+        let item = Self {
+            value,
+            mutable: None,
+        };
+        item.check_size()?;
+        Ok(item)
+    }
+
+    pub fn mutable(key: &SigningKey, salt: Vec<u8>, seq: i64, value: Value) -> Result<Self> {
+        let signature = key.sign(&signed_bytes(&salt, seq, &value)?);
+        let item = Self {
+            value,
+            mutable: Some(Mutable {
+                public_key: key.verifying_key().to_bytes(),
+                salt,
+                seq,
+                signature: signature.to_bytes(),
+            }),
+        };
+        item.check_size()?;
+        Ok(item)
+    }
+
+    pub fn target(&self) -> Result<NodeId> {
+        match &self.mutable {
+            Some(mutable) => Ok(mutable_target(&mutable.public_key, &mutable.salt)),
+            None => Ok(immutable_target(&serde_bencode::to_bytes(&self.value)?)),
+        }
+    }
+
+    pub fn seq(&self) -> Option<i64> {
+        self.mutable.as_ref().map(|mutable| mutable.seq)
+    }
+
+    /// Checks the item against its limits and, for mutable items, its
+    /// signature. Returns the KRPC error code describing the problem.
+    pub fn verify(&self) -> std::result::Result<(), (i64, &'static str)> {
+        if self.check_size().is_err() {
+            return Err((ERROR_VALUE_TOO_BIG, "Message (v field) too big"));
+        }
+        let Some(mutable) = &self.mutable else {
+            return Ok(());
+        };
+        if mutable.salt.len() > MAX_SALT_SIZE {
+            return Err((ERROR_SALT_TOO_BIG, "Salt too big"));
+        }
+        let valid = VerifyingKey::from_bytes(&mutable.public_key)
+            .ok()
+            .zip(signed_bytes(&mutable.salt, mutable.seq, &self.value).ok())
+            .is_some_and(|(key, bytes)| {
+                key.verify(&bytes, &Signature::from_bytes(&mutable.signature))
+                    .is_ok()
+            });
+        if !valid {
+            return Err((ERROR_INVALID_SIGNATURE, "Invalid signature"));
+        }
+        Ok(())
+    }
+
+    fn check_size(&self) -> Result<()> {
+        let size = serde_bencode::to_bytes(&self.value)?.len();
+        if size > MAX_VALUE_SIZE {
+            return Err(Error::msg(format!(
+                "Item value is {size} bytes, at most {MAX_VALUE_SIZE} are allowed"
+            )));
+        }
+        if self
+            .mutable
+            .as_ref()
+            .is_some_and(|mutable| mutable.salt.len() > MAX_SALT_SIZE)
+        {
+            return Err(Error::msg(format!(
+                "Item salt must be at most {MAX_SALT_SIZE} bytes"
+            )));
+        }
+        Ok(())
+    }
+}
+
+pub fn immutable_target(value: &[u8]) -> NodeId {
+    NodeId(Sha1::digest(value).into())
+}
+
+pub fn mutable_target(public_key: &[u8; 32], salt: &[u8]) -> NodeId {
+    let mut hasher = Sha1::new();
+    hasher.update(public_key);
+    hasher.update(salt);
+    NodeId(hasher.finalize().into())
+}
+
+/// Bytes covered by a mutable item signature: the salt, sequence number and
+/// value as they appear in the bencoded `put` arguments.
+fn signed_bytes(salt: &[u8], seq: i64, value: &Value) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    if !salt.is_empty() {
+        bytes.extend(format!("4:salt{}:", salt.len()).as_bytes());
+        bytes.extend(salt);
+    }
+    bytes.extend(format!("3:seqi{seq}e1:v").as_bytes());
+    bytes.extend(serde_bencode::to_bytes(value)?);
+    Ok(bytes)
+}
+
+/// Reads a hex encoded Ed25519 secret key, generating and saving a new one
+/// when the file does not exist.
+pub fn load_key(path: &Path) -> Result<SigningKey> {
+    if path.exists() {
+        let secret = hex::decode(std::fs::read_to_string(path)?.trim())?
+            .try_into()
+            .map_err(|_| Error::msg(format!("Secret key in {path:?} must be 32 bytes")))?;
+        return Ok(SigningKey::from_bytes(&secret));
+    }
+    let mut secret = [0; 32];
+    rand::thread_rng().fill_bytes(&mut secret);
+    // Only the owner may read the key, and an existing file is never
+    // overwritten.
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options
+        .open(path)?
+        .write_all(hex::encode(secret).as_bytes())?;
+    eprintln!("Generated a new key in {path:?}");
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+/// Strings are shown as they are, other values in the `decode` format.
+pub fn display_value(value: &Value) -> Result<String> {
+    if let Value::Bytes(bytes) = value {
+        return Ok(String::from_utf8_lossy(bytes).into_owned());
+    }
+    let encoded = String::from_utf8_lossy(&serde_bencode::to_bytes(value)?).into_owned();
+    let (_, decoded) = decode::decode_bencoded_value(&encoded)?;
+    Ok(decoded.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_key_is_private_and_reloaded() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("key.hex");
+        let key = load_key(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert_eq!(load_key(&path).unwrap().to_bytes(), key.to_bytes());
+    }
+
+    /// Mutable item from the BEP 44 test vectors, signed with its key.
+    fn vector_item(salt: &[u8], signature: &str) -> Item {
+        Item {
+            value: Value::Bytes(b"Hello World!".to_vec()),
+            mutable: Some(Mutable {
+                public_key: hex::decode(
+                    "77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548",
+                )
+                .unwrap()
+                .try_into()
+                .unwrap(),
+                salt: salt.to_vec(),
+                seq: 1,
+                signature: hex::decode(signature).unwrap().try_into().unwrap(),
+            }),
+        }
+    }
+
+    #[test]
+    fn matches_bep_44_vectors() {
+        let value = Value::Bytes(b"Hello World!".to_vec());
+        assert_eq!(
+            signed_bytes(b"", 1, &value).unwrap(),
+            b"3:seqi1e1:v12:Hello World!"
+        );
+        let item = vector_item(
+            b"",
+            "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff\
+             1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01",
+        );
+        assert!(item.verify().is_ok());
+        assert_eq!(
+            hex::encode(item.target().unwrap().0),
+            "4a533d47ec9c7d95b1ad75f576cffc641853b750"
+        );
+
+        assert_eq!(
+            signed_bytes(b"foobar", 1, &value).unwrap(),
+            b"4:salt6:foobar3:seqi1e1:v12:Hello World!"
+        );
+        let mut item = vector_item(
+            b"foobar",
+            "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17d\
+             df9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08",
+        );
+        assert!(item.verify().is_ok());
+        assert_eq!(
+            hex::encode(item.target().unwrap().0),
+            "411eba73b6f087ca51a3795d9c8c938d365e32c1"
+        );
+        // Any change to the signed fields invalidates the signature.
+        item.mutable.as_mut().unwrap().seq = 2;
+        assert_eq!(item.verify().unwrap_err().0, ERROR_INVALID_SIGNATURE);
+
+        let item = Item::immutable(value).unwrap();
+        assert_eq!(
+            hex::encode(item.target().unwrap().0),
+            "e5f96f6f38320f0f33959cb4d3d656452117aadb"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Error, Result};
+use serde_bencode::value::Value;
+
+use crate::{decode::dictionary_entries, torrent_file::TorrentFile};
+
+/// Keys outside the info dictionary which can be changed without changing
+/// the torrent's identity.
+pub const EDITABLE_KEYS: [&str; 5] = [
+    "announce",
+    "announce-list",
+    "comment",
+    "created by",
+    "url-list",
+];
+
+/// Sets (`Some`) or removes (`None`) outer keys of a torrent file. Every
+/// other key, and the info dictionary in particular, is copied byte for byte,
+/// and the result is checked to have the same info hashes.
+pub fn edit_torrent(torrent: &[u8], changes: &[(&str, Option<Value>)]) -> Result<Vec<u8>> {
+    let mut entries = dictionary_entries(torrent)?
+        .into_iter()
+        .map(|(key, value)| (key, value.to_vec()))
+        .collect::<BTreeMap<_, _>>();
+    for (key, value) in changes {
+        if !EDITABLE_KEYS.contains(key) {
+            return Err(Error::msg(format!("Key {key:?} cannot be edited")));
+        }
+        match value {
+            Some(value) => entries.insert(key.as_bytes().to_vec(), serde_bencode::to_bytes(value)?),
+            None => entries.remove(key.as_bytes()),
+        };
+    }
+    let mut edited = vec![b'd'];
+    for (key, value) in entries {
+        edited.extend(format!("{}:", key.len()).as_bytes());
+        edited.extend(key);
+        edited.extend(value);
+    }
+    edited.push(b'e');
+
+    let original = TorrentFile::from_bytes(torrent)?;
+    let result = TorrentFile::from_bytes(&edited)?;
+    if original.info.hash()? != result.info.hash()?
+        || original.info.hash_v2()? != result.info.hash_v2()?
+    {
+        return Err(Error::msg("Editing the torrent changed its info hash"));
+    }
+    Ok(edited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_string_lengths_past_the_end() {
+        for torrent in [
+            b"d4:infod4:name1:xe7:comment5:abce".as_slice(),
+            b"d4:infod4:name1:xe7:comment18446744073709551615:abce",
+            b"d4:infod4:name1:xe7:comment99999999999999999999999:e",
+        ] {
+            let error = edit_torrent(torrent, &[]).unwrap_err();
+            assert!(error.to_string().starts_with("Invalid bencoded value"));
+        }
+    }
+
+    /// Info dictionary whose keys are not in sorted order.
+    const INFO: &[u8] =
+        b"d12:piece lengthi16384e4:name1:x6:lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+
+    #[test]
+    fn keeps_info_bytes() {
+        let mut torrent = b"d8:announce9:http://a/4:info".to_vec();
+        torrent.extend(INFO);
+        torrent.push(b'e');
+        let changes = [
+            ("announce", Some(Value::Bytes(b"http://b/".to_vec()))),
+            ("comment", Some(Value::Bytes(b"Edited".to_vec()))),
+        ];
+        let edited = edit_torrent(&torrent, &changes).unwrap();
+
+        let mut expected = b"d8:announce9:http://b/7:comment6:Edited4:info".to_vec();
+        expected.extend(INFO);
+        expected.push(b'e');
+        assert_eq!(edited, expected);
+        let original = TorrentFile::from_bytes(&torrent).unwrap();
+        let edited = TorrentFile::from_bytes(&edited).unwrap();
+        assert_eq!(original.info.hash().unwrap(), edited.info.hash().unwrap());
+    }
+
+    #[test]
+    fn rejects_keys_outside_the_editable_ones() {
+        let mut torrent = b"d4:info".to_vec();
+        torrent.extend(INFO);
+        torrent.push(b'e');
+        for key in ["info", "piece layers", "name"] {
+            let error = edit_torrent(&torrent, &[(key, None)]).unwrap_err();
+            assert_eq!(error.to_string(), format!("Key {key:?} cannot be edited"));
+        }
+    }
+}
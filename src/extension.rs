@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::peer::TryFromBytes;
+
+/// Extended message id of the extension protocol handshake (BEP 10).
+pub const HANDSHAKE_ID: u8 = 0;
+/// Id peers use to send us `ut_pex` messages.
+pub const UT_PEX_ID: u8 = 1;
+pub const UT_PEX: &str = "ut_pex";
+const CLIENT_VERSION: &str = "bittorrent-starter-rust 0.1.0";
+
+/// Payload of an `Extended` message: the extended message id followed by a
+/// bencoded dictionary.
+pub struct ExtendedPayload {
+    pub id: u8,
+    pub payload: Vec<u8>,
+}
+
+impl ExtendedPayload {
+    pub fn new<T: Serialize>(id: u8, message: &T) -> Result<Self> {
+        Ok(Self {
+            id,
+            payload: serde_bencode::to_bytes(message)?,
+        })
+    }
+
+    pub fn parse<'de, T: Deserialize<'de>>(&'de self) -> Result<T> {
+        Ok(serde_bencode::from_bytes(&self.payload)?)
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.payload.len() + 1);
+        bytes.push(self.id);
+        bytes.extend(self.payload);
+        bytes
+    }
+}
+
+impl TryFromBytes for ExtendedPayload {
+    fn try_from_bytes(mut bytes: Vec<u8>) -> Result<Self> {
+        if bytes.is_empty() {
+            return Err(Error::msg("Extended message must contain an id."));
+        }
+        let id = bytes.remove(0);
+        Ok(Self { id, payload: bytes })
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Supported extensions mapped to the ids the sender expects them with.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// Port the sender listens on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+    /// Client name and version. Not necessarily UTF-8.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+}
+
+impl ExtendedHandshake {
+    pub fn new(port: u16, pex: bool) -> Self {
+        let mut m = BTreeMap::new();
+        if pex {
+            m.insert(UT_PEX.to_string(), UT_PEX_ID);
+        }
+        Self {
+            m,
+            p: Some(port),
+            v: Some(ByteBuf::from(CLIENT_VERSION)),
+        }
+    }
+
+    /// Id the peer expects for the extension, if it supports it.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|id| *id != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handshake_with_binary_client_version() {
+        let payload = ExtendedPayload {
+            id: HANDSHAKE_ID,
+            payload: b"d1:md6:ut_pexi3ee1:pi6881e1:v4:\xb5T\xff1e".to_vec(),
+        };
+        let handshake = payload.parse::<ExtendedHandshake>().unwrap();
+        assert_eq!(handshake.extension_id(UT_PEX), Some(3));
+        assert_eq!(handshake.p, Some(6881));
+        assert_eq!(handshake.v.unwrap().as_slice(), b"\xb5T\xff1");
+    }
+}
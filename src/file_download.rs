@@ -1,216 +1,149 @@
 use std::{
-    io::{Read, Write},
-    net::TcpStream,
-    path::PathBuf,
-    sync::{Arc, Mutex},
+    net::{SocketAddr, TcpStream},
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    peer::{
-        Bitfield, BytesConvertible, EmptyPayload, Handshake, Message, MessageType, Piece,
-        RequestPayload, TryFromBytes, PEER_ID,
-    },
-    torrent_file::{InfoHash, Piece as PieceHash, TorrentFile},
-    tracker::{self, Peer},
+    choker,
+    dht::{self, Dht},
+    listener::Listener,
+    lsd::Lsd,
+    merkle::MerkleTrees,
+    session::{handshake, Session},
+    storage::Storage,
+    swarm::Swarm,
+    torrent_file::TorrentFile,
+    tracker::{self, Announce, Event},
+    web_seed::WebSeed,
 };
-use anyhow::Result;
-use bytes::Buf;
-
-pub async fn download_file(file: TorrentFile, output: &PathBuf) -> Result<()> {
-    let info_hash = file.info.hash()?;
-    let peers =
-        tracker::discover_peers(file.announce.as_str(), &info_hash, file.info.length).await?;
-    let file_length = file.info.length;
-    let piece_length = file.info.piece_length;
+use anyhow::{Error, Result};
+
+/// How often idle workers check for peers learned from other peers.
+const CANDIDATE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long workers wait for local peers to answer our announcements before
+/// giving up on an empty swarm.
+const DISCOVERY_GRACE: Duration = Duration::from_secs(5);
+
+pub async fn download_file(
+    file: TorrentFile,
+    output: &Path,
+    port: u16,
+    max_inbound_connections: usize,
+    max_hash_failures: usize,
+    peers: Vec<SocketAddr>,
+    dht: Option<Arc<Dht>>,
+) -> Result<()> {
     let info_hash = file.info.hash()?;
-    let peers = Arc::new(Mutex::new(peers));
-    let file_buffer = Arc::new(Mutex::new(vec![0; file.info.length]));
-    let pieces = Arc::new(Mutex::new(
-        file.info.pieces.into_iter().enumerate().collect(),
-    ));
-    let handles = (0..5)
+    let web_seeds = file
+        .url_list
+        .iter()
+        .map(|url| WebSeed::get_right(url, &file.info))
+        .chain(
+            file.httpseeds
+                .iter()
+                .map(|url| WebSeed::hoffman(url, info_hash.clone())),
+        )
+        .collect::<Vec<_>>();
+    let merkle = MerkleTrees::new(&file)?;
+    let storage = Storage::open(output, &file.info)?;
+    let mut swarm = Swarm::new(file.info, storage)?;
+    swarm.max_hash_failures = max_hash_failures;
+    swarm.port = port;
+    swarm.dht = dht.clone();
+    swarm.merkle = merkle;
+    let swarm = Arc::new(swarm);
+    choker::spawn(swarm.clone());
+    match Listener::bind(port, max_inbound_connections) {
+        Ok(listener) => {
+            listener.add_torrent(swarm.clone());
+            listener.spawn();
+        }
+        Err(error) => eprintln!("Failed to listen for peers on port {port}: {error:?}"),
+    }
+    match Lsd::bind() {
+        Ok(lsd) => {
+            lsd.add_torrent(swarm.clone());
+            lsd.spawn();
+        }
+        Err(error) => eprintln!("Failed to start local service discovery: {error:?}"),
+    }
+    swarm.add_external_candidates(peers, "the command line");
+    if let Some(announce) = &file.announce {
+        let peers = tracker::announce(
+            announce,
+            &Announce::from_swarm(&swarm, port, Some(Event::Started)),
+        )
+        .await?
+        .peers;
+        swarm.add_candidates(peers.into_iter().map(|peer| SocketAddr::V4(peer.0)));
+    }
+    if let Some(dht) = dht {
+        let peers = dht.find_peers(&swarm.info_hash, Some(port), false);
+        let peers = peers.into_iter().map(SocketAddr::V4).collect();
+        swarm.add_external_candidates(peers, "the DHT");
+        dht::spawn_announcer(dht, swarm.clone());
+    }
+    let started = Instant::now();
+    let mut handles = (0..5)
         .map(|_| {
-            let peers = peers.clone();
-            let pieces = pieces.clone();
-            let file_buffer = file_buffer.clone();
-            let info_hash = info_hash.clone();
+            let swarm = swarm.clone();
             std::thread::spawn(move || {
-                run(
-                    peers,
-                    pieces,
-                    file_buffer,
-                    info_hash,
-                    file_length,
-                    piece_length,
-                );
+                run(swarm, started);
             })
         })
         .collect::<Vec<_>>();
+    handles.extend(web_seeds.into_iter().map(|seed| seed.spawn(swarm.clone())));
     for handle in handles {
         handle.join().unwrap();
     }
-    let file = file_buffer.lock().unwrap();
-    std::fs::write(output, file.as_slice())?;
+    if !swarm.is_complete() {
+        return Err(Error::msg("Ran out of peers before the download completed"));
+    }
+    swarm.verify_files()?;
+    let endgame_requests = swarm.endgame_requests.load(Ordering::Relaxed);
+    if endgame_requests > 0 {
+        eprintln!(
+            "Endgame sent {endgame_requests} duplicate requests and received {} duplicate bytes",
+            swarm.duplicate_bytes.load(Ordering::Relaxed)
+        );
+    }
+    if let Some(announce) = &file.announce {
+        tracker::announce(
+            announce,
+            &Announce::from_swarm(&swarm, port, Some(Event::Completed)),
+        )
+        .await?;
+    }
     Ok(())
 }
 
-fn run(
-    peers: Arc<Mutex<Vec<Peer>>>,
-    pieces: Arc<Mutex<Vec<(usize, PieceHash)>>>,
-    file_buffer: Arc<Mutex<Vec<u8>>>,
-    info_hash: InfoHash,
-    file_length: usize,
-    piece_length: usize,
-) {
+fn run(swarm: Arc<Swarm>, started: Instant) {
     loop {
-        let Some(peer) = peers.lock().unwrap().pop() else {
+        if swarm.is_complete() {
             return;
+        }
+        let Some(peer) = swarm.next_candidate() else {
+            // Connected and local peers may still tell us about new ones.
+            if swarm.session_count() == 0 && started.elapsed() >= DISCOVERY_GRACE {
+                return;
+            }
+            std::thread::sleep(CANDIDATE_POLL_INTERVAL);
+            continue;
         };
-        match download_from_peer(
-            &peer,
-            pieces.clone(),
-            file_buffer.clone(),
-            info_hash.clone(),
-            file_length,
-            piece_length,
-        ) {
+        match download_from_peer(peer, swarm.clone()) {
             Ok(_) => {}
             Err(error) => {
-                eprintln!(
-                    "Failed to download piece from peer {} with error: {:?}",
-                    peer.0, error
-                );
-                peers.lock().unwrap().push(peer);
+                eprintln!("Failed to download piece from peer {peer} with error: {error:?}");
+                swarm.add_candidates([peer]);
             }
         }
     }
 }
 
-fn download_from_peer(
-    peer: &Peer,
-    pieces: Arc<Mutex<Vec<(usize, PieceHash)>>>,
-    file_buffer: Arc<Mutex<Vec<u8>>>,
-    info_hash: InfoHash,
-    file_length: usize,
-    piece_length: usize,
-) -> Result<()> {
-    let mut stream = std::net::TcpStream::connect(peer.0)?;
-    handshake(&info_hash, &mut stream)?;
-
-    let bitfield_mesasge = read_message::<Bitfield>(&mut stream)?;
-    assert_eq!(bitfield_mesasge.message_type, MessageType::Bitfield);
-
-    send_message(
-        Message {
-            message_type: MessageType::Interested,
-            payload: EmptyPayload,
-        },
-        &mut stream,
-    )?;
-    let unchoke_message = read_message::<EmptyPayload>(&mut stream)?;
-    assert_eq!(unchoke_message.message_type, MessageType::Unchoke);
-
-    loop {
-        let mut pieces = pieces.lock().unwrap();
-        let index = pieces
-            .iter()
-            .position(|(index, _)| bitfield_mesasge.payload.has_piece(*index));
-
-        let Some(piece_index) = index else {
-            return Ok(());
-        };
-
-        let (piece_index, piece_hash) = pieces.remove(piece_index);
-        drop(pieces);
-
-        let piece_buffer = request_peice(
-            piece_index,
-            piece_length,
-            &piece_hash,
-            file_length,
-            &mut stream,
-        )?;
-        let mut file_buffer = file_buffer.lock().unwrap();
-        let offset = piece_index * piece_length;
-        piece_buffer
-            .as_slice()
-            .copy_to_slice(&mut file_buffer.as_mut_slice()[offset..offset + piece_buffer.len()]);
-    }
-}
-
-fn handshake(info_hash: &InfoHash, stream: &mut TcpStream) -> Result<()> {
-    let mut handshake = Handshake::new(info_hash, PEER_ID);
-    let bytes = handshake.as_bytes_mut();
-
-    stream.write_all(bytes)?;
-    stream.read_exact(bytes)?;
-    Ok(())
-}
-
-fn read_message<P: TryFromBytes>(stream: &mut TcpStream) -> Result<Message<P>> {
-    let mut header = [0u8; 4];
-    stream.read_exact(header.as_mut())?;
-    let length = u32::from_be_bytes(header) as usize;
-
-    let mut message_id = [0u8; 1];
-    stream.read_exact(message_id.as_mut())?;
-    let message_type = MessageType::from(message_id[0]);
-
-    let mut payload = vec![0; length - message_id.len()];
-    if !payload.is_empty() {
-        stream.read_exact(payload.as_mut())?;
-    }
-
-    let payload = P::try_from_bytes(payload)?;
-    Ok(Message {
-        message_type,
-        payload,
-    })
-}
-
-fn send_message<P: BytesConvertible>(message: Message<P>, stream: &mut TcpStream) -> Result<()> {
-    let mut payload = message.payload.as_bytes();
-    let message_size = (payload.len() as i32 + 1).to_be_bytes();
-    let message_id: u8 = message.message_type.into();
-    let mut buffer = vec![0; payload.len() + 4 + 1];
-
-    message_size.as_ref().copy_to_slice(&mut buffer[0..4]);
-    [message_id].as_ref().copy_to_slice(&mut buffer[4..5]);
-    payload.copy_to_slice(&mut buffer[5..]);
-
-    stream.write_all(&buffer)?;
-
-    Ok(())
-}
-
-const CHUNK_SIZE: usize = 1 << 14;
-fn request_peice(
-    piece_index: usize,
-    size: usize,
-    hash: &PieceHash,
-    file_length: usize,
-    stream: &mut TcpStream,
-) -> Result<Vec<u8>> {
-    let mut offset = 0;
-    let piece_size = size.min(file_length - piece_index * size);
-    let mut buffer = Vec::with_capacity(piece_size);
-    while offset < piece_size {
-        let block_size = (piece_size - offset).min(CHUNK_SIZE);
-        let payload = RequestPayload::new(piece_index, offset, block_size);
-        send_message(
-            Message {
-                message_type: MessageType::Request,
-                payload,
-            },
-            stream,
-        )?;
-        let chunk = read_message::<Piece>(stream)?;
-        assert_eq!(chunk.message_type, MessageType::Piece);
-        assert_eq!(chunk.payload.block.len(), block_size);
-
-        buffer.extend(chunk.payload.block.as_slice());
-        offset += block_size;
-    }
-    assert_eq!(hash, &PieceHash::from(buffer.as_slice()));
-    Ok(buffer)
+fn download_from_peer(peer: SocketAddr, swarm: Arc<Swarm>) -> Result<()> {
+    let mut stream = TcpStream::connect(peer)?;
+    let handshake = handshake(&swarm, &mut stream)?;
+    Session::new(swarm, stream, &handshake, true)?.run()
 }
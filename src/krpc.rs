@@ -0,0 +1,236 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
+
+use crate::routing_table::{Node, NodeId, NODE_ID_SIZE};
+
+const COMPACT_NODE_SIZE: usize = NODE_ID_SIZE + 6;
+const COMPACT_PEER_SIZE: usize = 6;
+
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/// A KRPC message (BEP 5): a query, a response or an error.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Message {
+    /// Transaction id echoed back by the responding node.
+    pub t: ByteBuf,
+    /// `q` for queries, `r` for responses and `e` for errors.
+    pub y: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub a: Option<Arguments>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r: Option<Response>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_error",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub e: Option<(i64, String)>,
+    /// Compact address of the querying node as seen by the responder
+    /// (BEP 42).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<ByteBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Arguments {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    /// Announcing peer is a seed (BEP 33).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u8>,
+    /// Asks for bloom filters of the swarm instead of just peers (BEP 33).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scrape: Option<u8>,
+    /// Asks for peers which are not seeds only (BEP 33).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub noseed: Option<u8>,
+    /// Item value (BEP 44).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<Value>,
+    /// Public key of a mutable item.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub k: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sig: Option<ByteBuf>,
+    /// Sequence number the stored mutable item must have for a `put` to
+    /// replace it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cas: Option<i64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Vec<ByteBuf>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub k: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sig: Option<ByteBuf>,
+    /// Bloom filter of seeds in the swarm (BEP 33).
+    #[serde(rename = "BFsd", default, skip_serializing_if = "Option::is_none")]
+    pub seeds_filter: Option<ByteBuf>,
+    /// Bloom filter of all peers in the swarm (BEP 33).
+    #[serde(rename = "BFpe", default, skip_serializing_if = "Option::is_none")]
+    pub peers_filter: Option<ByteBuf>,
+    /// Seconds until the node may be asked for new samples (BEP 51).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<i64>,
+    /// Number of info hashes the node stores.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num: Option<i64>,
+    /// Info hashes the node stores, concatenated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub samples: Option<ByteBuf>,
+}
+
+impl Message {
+    pub fn query(transaction: &[u8], method: &str, arguments: Arguments) -> Self {
+        Self {
+            t: ByteBuf::from(transaction),
+            y: "q".to_string(),
+            q: Some(method.to_string()),
+            a: Some(arguments),
+            ..Default::default()
+        }
+    }
+
+    pub fn response(transaction: &[u8], response: Response) -> Self {
+        Self {
+            t: ByteBuf::from(transaction),
+            y: "r".to_string(),
+            r: Some(response),
+            ..Default::default()
+        }
+    }
+
+    pub fn error(transaction: &[u8], code: i64, message: &str) -> Self {
+        Self {
+            t: ByteBuf::from(transaction),
+            y: "e".to_string(),
+            e: Some((code, message.to_string())),
+            ..Default::default()
+        }
+    }
+}
+
+impl Arguments {
+    pub fn new(id: &NodeId) -> Self {
+        Self {
+            id: ByteBuf::from(id.0.to_vec()),
+            ..Default::default()
+        }
+    }
+}
+
+impl Response {
+    pub fn new(id: &NodeId) -> Self {
+        Self {
+            id: ByteBuf::from(id.0.to_vec()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Reads the `[code, message]` list of an error. serde_bencode cannot read
+/// it as a tuple.
+fn deserialize_error<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<(i64, String)>, D::Error> {
+    match Option::<Vec<Value>>::deserialize(deserializer)?.as_deref() {
+        None => Ok(None),
+        Some([Value::Int(code), Value::Bytes(message)]) => {
+            Ok(Some((*code, String::from_utf8_lossy(message).into_owned())))
+        }
+        Some(_) => Err(serde::de::Error::custom("Malformed KRPC error")),
+    }
+}
+
+pub fn encode_nodes(nodes: &[Node]) -> ByteBuf {
+    let mut bytes = Vec::with_capacity(nodes.len() * COMPACT_NODE_SIZE);
+    for node in nodes {
+        bytes.extend_from_slice(&node.id.0);
+        bytes.extend_from_slice(&encode_peer(&node.address));
+    }
+    ByteBuf::from(bytes)
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<Node> {
+    bytes
+        .chunks_exact(COMPACT_NODE_SIZE)
+        .filter_map(|chunk| {
+            let id = NodeId::from_slice(&chunk[..NODE_ID_SIZE])?;
+            let address = decode_peer(&chunk[NODE_ID_SIZE..])?;
+            Some(Node::new(id, address))
+        })
+        .collect()
+}
+
+pub fn encode_peer(address: &SocketAddrV4) -> [u8; COMPACT_PEER_SIZE] {
+    let mut bytes = [0; COMPACT_PEER_SIZE];
+    bytes[..4].copy_from_slice(&address.ip().octets());
+    bytes[4..].copy_from_slice(&address.port().to_be_bytes());
+    bytes
+}
+
+pub fn decode_peer(bytes: &[u8]) -> Option<SocketAddrV4> {
+    if bytes.len() != COMPACT_PEER_SIZE {
+        return None;
+    }
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    Some(SocketAddrV4::new(
+        ip,
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    ))
+}
+
+/// DHT only speaks IPv4, so other addresses are ignored.
+pub fn as_v4(address: SocketAddr) -> Option<SocketAddrV4> {
+    match address {
+        SocketAddr::V4(address) => Some(address),
+        SocketAddr::V6(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_errors() {
+        let bytes = serde_bencode::to_bytes(&Message::error(b"aa", 302, "Too low")).unwrap();
+        assert_eq!(bytes, b"d1:eli302e7:Too lowe1:t2:aa1:y1:ee");
+        let message = serde_bencode::from_bytes::<Message>(&bytes).unwrap();
+        assert_eq!(message.t.as_slice(), b"aa");
+        assert_eq!(message.e, Some((302, "Too low".to_string())));
+
+        assert!(serde_bencode::from_bytes::<Message>(b"d1:eli302ee1:t2:aa1:y1:ee").is_err());
+    }
+}
@@ -0,0 +1,409 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+use serde_bencode::value::Value;
+
+use crate::{
+    merkle::{MerkleTrees, BLOCK_SIZE},
+    torrent_file::{check_path_component, TorrentFile},
+};
+
+const PIECE_HASH_SIZE: usize = 20;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    /// The torrent is malformed and clients may reject it or misbehave.
+    Error,
+    /// The torrent works but is unusual.
+    Warning,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Issue {
+    pub severity: Severity,
+    /// Stable identifier of the kind of issue, such as
+    /// `piece-count-mismatch`.
+    pub code: &'static str,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Report {
+    /// Whether the torrent has no errors. Warnings are allowed.
+    pub valid: bool,
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    pub fn count(&self, severity: Severity) -> usize {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity == severity)
+            .count()
+    }
+
+    fn error(&mut self, code: &'static str, message: impl Into<String>) {
+        self.add(Severity::Error, code, message.into());
+    }
+
+    fn warning(&mut self, code: &'static str, message: impl Into<String>) {
+        self.add(Severity::Warning, code, message.into());
+    }
+
+    fn add(&mut self, severity: Severity, code: &'static str, message: String) {
+        self.issues.push(Issue {
+            severity,
+            code,
+            message,
+        });
+    }
+}
+
+/// Checks the structure of a torrent file. Checks work on the raw bencoded
+/// values, so that problems the parser would hide or reject are reported
+/// along with everything else.
+pub fn lint(torrent: &[u8]) -> Report {
+    let mut report = Report::default();
+    match serde_bencode::from_bytes::<Value>(torrent) {
+        Ok(Value::Dict(root)) => lint_root(torrent, &root, &mut report),
+        Ok(_) => report.error("not-a-dictionary", "Torrent file is not a dictionary"),
+        Err(error) => report.error("invalid-bencode", format!("Invalid bencoding: {error}")),
+    }
+    report.valid = report.count(Severity::Error) == 0;
+    report
+}
+
+type Dict = HashMap<Vec<u8>, Value>;
+
+fn lint_root(torrent: &[u8], root: &Dict, report: &mut Report) {
+    lint_trackers(root, report);
+    let Some(info) = root.get(b"info".as_slice()) else {
+        report.error("missing-info", "Torrent has no info dictionary");
+        return;
+    };
+    let Value::Dict(info) = info else {
+        report.error("missing-info", "The info key is not a dictionary");
+        return;
+    };
+    lint_info(info, report);
+    if report.count(Severity::Error) > 0 {
+        return;
+    }
+    // The v2 file tree and piece layers are checked by the parser.
+    let file = match TorrentFile::from_bytes(torrent) {
+        Ok(file) => file,
+        Err(error) => {
+            report.error("invalid-torrent", format!("Invalid torrent: {error}"));
+            return;
+        }
+    };
+    lint_v2(&file, report);
+}
+
+fn lint_trackers(root: &Dict, report: &mut Report) {
+    let mut urls = Vec::new();
+    match root.get(b"announce".as_slice()) {
+        Some(Value::Bytes(url)) => urls.push(url.clone()),
+        Some(_) => report.error("invalid-announce", "The announce key is not a string"),
+        None => {}
+    }
+    match root.get(b"announce-list".as_slice()) {
+        Some(Value::List(tiers)) => {
+            for tier in tiers {
+                let Value::List(tier) = tier else {
+                    report.error("invalid-announce-list", "Announce list tier is not a list");
+                    continue;
+                };
+                for url in tier {
+                    match url {
+                        Value::Bytes(url) => urls.push(url.clone()),
+                        _ => report.error("invalid-announce-list", "Tracker URL is not a string"),
+                    }
+                }
+            }
+        }
+        Some(_) => report.error("invalid-announce-list", "The announce list is not a list"),
+        None => {}
+    }
+    for url in &urls {
+        let url = String::from_utf8_lossy(url);
+        if !["http://", "https://", "udp://"]
+            .iter()
+            .any(|scheme| url.starts_with(scheme))
+        {
+            report.warning(
+                "unsupported-tracker",
+                format!("Tracker URL {url:?} is not an HTTP or UDP URL"),
+            );
+        }
+    }
+    if urls.is_empty() && !root.contains_key(b"nodes".as_slice()) {
+        report.warning(
+            "no-trackers",
+            "Torrent has no trackers or DHT nodes, peers can only be found on the DHT",
+        );
+    }
+}
+
+fn lint_info(info: &Dict, report: &mut Report) {
+    match info.get(b"name".as_slice()) {
+        Some(Value::Bytes(name)) if name.is_empty() => {
+            report.error("empty-name", "Torrent name is empty")
+        }
+        Some(Value::Bytes(name)) => match std::str::from_utf8(name) {
+            Ok(name) => {
+                if let Err(error) = check_path_component(name) {
+                    report.error("invalid-name", format!("Invalid torrent name: {error}"));
+                }
+            }
+            Err(_) => report.error("invalid-name", "Torrent name is not UTF-8"),
+        },
+        _ => report.error("missing-name", "Torrent has no name"),
+    }
+
+    let v2 = match info.get(b"meta version".as_slice()) {
+        None => false,
+        Some(Value::Int(2)) => true,
+        Some(_) => {
+            report.error("unsupported-meta-version", "Unsupported meta version");
+            return;
+        }
+    };
+    let v1 = !v2 || info.contains_key(b"pieces".as_slice());
+    let piece_length = match info.get(b"piece length".as_slice()) {
+        Some(Value::Int(length)) if *length <= 0 => {
+            report.error("zero-piece-length", format!("Piece length is {length}"));
+            None
+        }
+        Some(Value::Int(length)) => Some(*length as usize),
+        _ => {
+            report.error("missing-piece-length", "Torrent has no piece length");
+            None
+        }
+    };
+    if let Some(piece_length) = piece_length {
+        if !piece_length.is_power_of_two() || piece_length < BLOCK_SIZE {
+            let message =
+                format!("Piece length {piece_length} is not a power of two of at least 16 KiB");
+            if v2 {
+                report.error("invalid-piece-length", message);
+            } else {
+                report.warning("unusual-piece-length", message);
+            }
+        }
+    }
+
+    match info.get(b"private".as_slice()) {
+        None | Some(Value::Int(0 | 1)) => {}
+        Some(_) => report.warning("invalid-private", "The private flag is neither 0 nor 1"),
+    }
+
+    if v1 {
+        lint_v1(info, piece_length, report);
+    }
+}
+
+fn lint_v1(info: &Dict, piece_length: Option<usize>, report: &mut Report) {
+    let pieces = match info.get(b"pieces".as_slice()) {
+        Some(Value::Bytes(pieces)) if !pieces.len().is_multiple_of(PIECE_HASH_SIZE) => {
+            report.error(
+                "invalid-pieces",
+                format!(
+                    "Piece hashes are {} bytes long, not a multiple of {PIECE_HASH_SIZE}",
+                    pieces.len()
+                ),
+            );
+            None
+        }
+        Some(Value::Bytes(pieces)) => Some(pieces.len() / PIECE_HASH_SIZE),
+        _ => {
+            report.error("missing-pieces", "Torrent has no piece hashes");
+            None
+        }
+    };
+
+    let total_length = match (
+        info.get(b"length".as_slice()),
+        info.get(b"files".as_slice()),
+    ) {
+        (Some(_), Some(_)) => {
+            report.error("length-and-files", "Torrent has both a length and files");
+            None
+        }
+        (Some(Value::Int(length)), None) if *length >= 0 => Some(*length as usize),
+        (Some(_), None) => {
+            report.error("invalid-length", "Invalid torrent length");
+            None
+        }
+        (None, Some(Value::List(files))) => lint_files(files, report),
+        (None, Some(_)) => {
+            report.error("invalid-files", "The files key is not a list");
+            None
+        }
+        (None, None) => {
+            report.error("missing-length", "Torrent has neither a length nor files");
+            None
+        }
+    };
+
+    if let Some(total_length) = total_length {
+        if total_length == 0 {
+            report.warning("empty-torrent", "Torrent has no data");
+        }
+        if let (Some(pieces), Some(piece_length)) = (pieces, piece_length) {
+            let expected = total_length.div_ceil(piece_length);
+            if pieces != expected {
+                report.error(
+                    "piece-count-mismatch",
+                    format!(
+                        "Torrent has {pieces} piece hashes, but {total_length} bytes make {expected} pieces"
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Checks the files of a multi-file torrent and returns their total length.
+fn lint_files(files: &[Value], report: &mut Report) -> Option<usize> {
+    if files.is_empty() {
+        report.error("missing-files", "Torrent has an empty file list");
+    }
+    let mut total_length = Some(0usize);
+    let mut paths = HashSet::new();
+    for file in files {
+        let Value::Dict(file) = file else {
+            report.error("invalid-file", "File entry is not a dictionary");
+            total_length = None;
+            continue;
+        };
+        match file.get(b"length".as_slice()) {
+            Some(Value::Int(length)) if *length >= 0 => {
+                if let Some(total) = total_length {
+                    total_length = total.checked_add(*length as usize);
+                    if total_length.is_none() {
+                        report.error("invalid-file-length", "Total length of the files overflows");
+                    }
+                }
+            }
+            _ => {
+                report.error("invalid-file-length", "File has no valid length");
+                total_length = None;
+            }
+        }
+        let Some(path) = file_path(file, report) else {
+            continue;
+        };
+        if !paths.insert(path.clone()) {
+            report.error(
+                "duplicate-path",
+                format!("File {:?} appears more than once", path.join("/")),
+            );
+        }
+    }
+    // A file cannot also be a directory holding other files.
+    for path in &paths {
+        if (1..path.len()).any(|length| paths.contains(&path[..length])) {
+            report.error(
+                "path-conflict",
+                format!("File {:?} is inside another file", path.join("/")),
+            );
+        }
+    }
+    total_length
+}
+
+fn file_path(file: &Dict, report: &mut Report) -> Option<Vec<String>> {
+    let Some(Value::List(components)) = file.get(b"path".as_slice()) else {
+        report.error("invalid-path", "File has no path");
+        return None;
+    };
+    if components.is_empty() {
+        report.error("empty-path", "File has an empty path");
+        return None;
+    }
+    let mut path = Vec::new();
+    for component in components {
+        let Value::Bytes(component) = component else {
+            report.error("invalid-path", "File path component is not a string");
+            return None;
+        };
+        let Ok(component) = String::from_utf8(component.clone()) else {
+            report.error("invalid-path", "File path component is not UTF-8");
+            return None;
+        };
+        path.push(component);
+    }
+    for component in &path {
+        if let Err(error) = check_path_component(component) {
+            report.error(
+                "path-traversal",
+                format!("File {:?}: {error}", path.join("/")),
+            );
+            return None;
+        }
+    }
+    Some(path)
+}
+
+fn lint_v2(file: &TorrentFile, report: &mut Report) {
+    if !file.info.is_v2() {
+        return;
+    }
+    match file.info.v2_files() {
+        Ok(files) => {
+            for v2_file in files {
+                if let Some(error) = v2_file
+                    .path
+                    .iter()
+                    .find_map(|component| check_path_component(component).err())
+                {
+                    report.error(
+                        "path-traversal",
+                        format!("File {:?}: {error}", v2_file.path.join("/")),
+                    );
+                }
+            }
+        }
+        Err(error) => {
+            report.error("invalid-file-tree", format!("Invalid file tree: {error}"));
+            return;
+        }
+    }
+    if let Err(error) = file.piece_layers() {
+        report.error("invalid-piece-layers", error.to_string());
+        return;
+    }
+    if let Err(error) = MerkleTrees::new(file) {
+        report.error("v1-v2-mismatch", error.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(report: &Report) -> Vec<&'static str> {
+        report.issues.iter().map(|issue| issue.code).collect()
+    }
+
+    #[test]
+    fn reports_overflowing_file_lengths() {
+        let length = i64::MAX;
+        let torrent = format!(
+            "d4:infod5:filesld6:lengthi{length}e4:pathl1:aeed6:lengthi{length}e4:pathl1:beed6:lengthi{length}e4:pathl1:ceee4:name1:x12:piece lengthi16384e6:pieces0:ee"
+        );
+        let report = lint(torrent.as_bytes());
+        assert!(!report.valid);
+        assert_eq!(codes(&report), ["no-trackers", "invalid-file-length"]);
+    }
+
+    #[test]
+    fn reports_piece_count_mismatch() {
+        let torrent = b"d8:announce20:http://tracker/annce4:infod6:lengthi20000e4:name1:x12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let report = lint(torrent);
+        assert!(!report.valid);
+        assert_eq!(codes(&report), ["piece-count-mismatch"]);
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{Error, Result};
+
+use crate::{
+    peer::Handshake,
+    session::{self, Session},
+    swarm::Swarm,
+    torrent_file::InfoHash,
+};
+
+pub const DEFAULT_PORT: u16 = 6881;
+pub const DEFAULT_MAX_INBOUND_CONNECTIONS: usize = 50;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts inbound peer connections for every torrent registered with it.
+pub struct Listener {
+    listener: TcpListener,
+    torrents: Arc<Mutex<HashMap<InfoHash, Arc<Swarm>>>>,
+    /// Inbound sessions currently open. Outbound sessions are limited by the
+    /// number of download workers instead.
+    connections: Arc<AtomicUsize>,
+    max_inbound_connections: usize,
+}
+
+impl Listener {
+    pub fn bind(port: u16, max_inbound_connections: usize) -> Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        Ok(Self {
+            listener,
+            torrents: Arc::new(Mutex::new(HashMap::new())),
+            connections: Arc::new(AtomicUsize::new(0)),
+            max_inbound_connections,
+        })
+    }
+
+    pub fn add_torrent(&self, swarm: Arc<Swarm>) {
+        let mut torrents = self.torrents.lock().unwrap();
+        torrents.insert(swarm.info_hash.clone(), swarm);
+    }
+
+    /// Accepts connections on a background thread until the process exits.
+    pub fn spawn(self) {
+        std::thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        eprintln!("Failed to accept peer connection: {error:?}");
+                        continue;
+                    }
+                };
+                let Some(slot) = self.reserve_slot() else {
+                    let address = stream.peer_addr().map_or_else(
+                        |_| "unknown address".to_string(),
+                        |address| address.to_string(),
+                    );
+                    eprintln!(
+                        "Dropped peer connection from {address}: limit of {} inbound connections reached",
+                        self.max_inbound_connections
+                    );
+                    continue;
+                };
+                let torrents = self.torrents.clone();
+                std::thread::spawn(move || {
+                    let _slot = slot;
+                    if let Err(error) = serve_peer(stream, torrents) {
+                        eprintln!("Inbound peer session failed with error: {error:?}");
+                    }
+                });
+            }
+        });
+    }
+
+    /// Takes one of the inbound connection slots, unless all are in use.
+    fn reserve_slot(&self) -> Option<Slot> {
+        self.connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |connections| {
+                (connections < self.max_inbound_connections).then_some(connections + 1)
+            })
+            .ok()?;
+        Some(Slot(self.connections.clone()))
+    }
+}
+
+/// Inbound connection slot, released when the session ends, even by a panic.
+struct Slot(Arc<AtomicUsize>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+fn serve_peer(
+    mut stream: TcpStream,
+    torrents: Arc<Mutex<HashMap<InfoHash, Arc<Swarm>>>>,
+) -> Result<()> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut handshake = Handshake::new(&InfoHash([0; 20]), [0; 20]);
+    stream.read_exact(handshake.as_bytes_mut())?;
+    if !handshake.is_bittorrent() {
+        return Err(Error::msg("Peer does not speak BitTorrent protocol"));
+    }
+    let info_hash = handshake.info_hash();
+    let Some(swarm) = torrents.lock().unwrap().get(&info_hash).cloned() else {
+        return Err(Error::msg(format!(
+            "Peer requested unknown info hash {}",
+            hex::encode(info_hash.0)
+        )));
+    };
+    if swarm.is_banned(stream.peer_addr()?.ip()) {
+        return Err(Error::msg("Peer is banned"));
+    }
+    let mut reply = session::local_handshake(&swarm);
+    stream.write_all(reply.as_bytes_mut())?;
+    stream.set_read_timeout(None)?;
+    Session::new(swarm, stream, &handshake, false)?.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_inbound_connections() {
+        let listener = Listener::bind(0, 2).unwrap();
+        let first = listener.reserve_slot().unwrap();
+        let second = listener.reserve_slot().unwrap();
+        assert!(listener.reserve_slot().is_none());
+
+        drop(first);
+        let third = listener.reserve_slot().unwrap();
+        assert!(listener.reserve_slot().is_none());
+        drop((second, third));
+        assert_eq!(listener.connections.load(Ordering::Acquire), 0);
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Error, Result};
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{swarm::Swarm, torrent_file::InfoHash};
+
+pub const LSD_PORT: u16 = 6771;
+const GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// A torrent is announced at most once a minute, even when asked to by
+/// other peers' announcements.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+const TICK: Duration = Duration::from_secs(1);
+const MAX_MESSAGE_SIZE: usize = 1400;
+
+/// Local Service Discovery (BEP 14): finds peers on the local network with
+/// multicast `BT-SEARCH` announcements.
+pub struct Lsd {
+    sockets: Vec<(UdpSocket, SocketAddr)>,
+    /// Identifies our own announcements when they are looped back to us.
+    cookie: String,
+    torrents: Mutex<HashMap<InfoHash, LocalTorrent>>,
+}
+
+struct LocalTorrent {
+    swarm: Arc<Swarm>,
+    last_announce: Option<Instant>,
+    /// Another peer announced the torrent, so it should hear about us soon.
+    reply: bool,
+}
+
+impl Lsd {
+    /// Joins the IPv4 and IPv6 multicast groups. Fails only when neither can
+    /// be joined.
+    pub fn bind() -> Result<Self> {
+        let mut sockets = Vec::new();
+        let mut errors = Vec::new();
+        match bind_v4() {
+            Ok(socket) => sockets.push((socket, SocketAddr::from((GROUP_V4, LSD_PORT)))),
+            Err(error) => errors.push(error),
+        }
+        match bind_v6() {
+            Ok(socket) => sockets.push((socket, SocketAddr::from((GROUP_V6, LSD_PORT)))),
+            Err(error) => errors.push(error),
+        }
+        if sockets.is_empty() {
+            return Err(Error::msg(format!(
+                "Failed to join local service discovery groups: {errors:?}"
+            )));
+        }
+        Ok(Self {
+            sockets,
+            cookie: format!("{:08x}", rand::thread_rng().gen::<u32>()),
+            torrents: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Private torrents only use peers from their trackers, so they are
+    /// never announced.
+    pub fn add_torrent(&self, swarm: Arc<Swarm>) {
+        if swarm.private {
+            return;
+        }
+        let mut torrents = self.torrents.lock().unwrap();
+        torrents.insert(
+            swarm.info_hash.clone(),
+            LocalTorrent {
+                swarm,
+                last_announce: None,
+                reply: false,
+            },
+        );
+    }
+
+    /// Announces the torrents and listens for other peers on background
+    /// threads until the process exits.
+    pub fn spawn(self) {
+        let lsd = Arc::new(self);
+        for index in 0..lsd.sockets.len() {
+            let lsd = lsd.clone();
+            std::thread::spawn(move || lsd.receive(index));
+        }
+        std::thread::spawn(move || loop {
+            lsd.announce();
+            std::thread::sleep(TICK);
+        });
+    }
+
+    fn announce(&self) {
+        let mut torrents = self.torrents.lock().unwrap();
+        for torrent in torrents.values_mut() {
+            let due = match torrent.last_announce {
+                None => true,
+                Some(last_announce) if torrent.reply => {
+                    last_announce.elapsed() >= MIN_ANNOUNCE_INTERVAL
+                }
+                Some(last_announce) => last_announce.elapsed() >= ANNOUNCE_INTERVAL,
+            };
+            if !due {
+                continue;
+            }
+            torrent.last_announce = Some(Instant::now());
+            torrent.reply = false;
+            for (socket, group) in &self.sockets {
+                let message = format!(
+                    "BT-SEARCH * HTTP/1.1\r\nHost: {group}\r\nPort: {}\r\nInfohash: {}\r\ncookie: {}\r\n\r\n\r\n",
+                    torrent.swarm.port,
+                    hex::encode(torrent.swarm.info_hash.0),
+                    self.cookie
+                );
+                if let Err(error) = socket.send_to(message.as_bytes(), group) {
+                    eprintln!("Failed to send local service discovery announce: {error:?}");
+                }
+            }
+        }
+    }
+
+    fn receive(&self, index: usize) {
+        let (socket, _) = &self.sockets[index];
+        let mut buffer = [0; MAX_MESSAGE_SIZE];
+        loop {
+            let (size, address) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(error) => {
+                    // Errors such as a dropped network interface persist, so
+                    // they are not retried in a busy loop.
+                    eprintln!("Failed to receive local service discovery announce: {error:?}");
+                    std::thread::sleep(TICK);
+                    continue;
+                }
+            };
+            let Some(announce) = self.parse_announce(&buffer[..size]) else {
+                continue;
+            };
+            let peer = SocketAddr::new(address.ip(), announce.port);
+            let mut torrents = self.torrents.lock().unwrap();
+            for info_hash in announce.info_hashes {
+                if let Some(torrent) = torrents.get_mut(&info_hash) {
+                    torrent.swarm.add_candidates([peer]);
+                    torrent.reply = true;
+                }
+            }
+        }
+    }
+
+    /// Parses an announce from another peer. Our own announces are looped
+    /// back by the multicast group and ignored.
+    fn parse_announce(&self, message: &[u8]) -> Option<Announce> {
+        let announce = Announce::parse(&String::from_utf8_lossy(message))?;
+        if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+            return None;
+        }
+        Some(announce)
+    }
+}
+
+/// A `BT-SEARCH` message received from another peer.
+struct Announce {
+    port: u16,
+    info_hashes: Vec<InfoHash>,
+    cookie: Option<String>,
+}
+
+impl Announce {
+    fn parse(message: &str) -> Option<Self> {
+        let mut lines = message.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse().ok(),
+                "infohash" => {
+                    let hash = hex::decode(value).ok()?;
+                    info_hashes.push(InfoHash(hash.try_into().ok()?));
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(Self {
+            port: port?,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+/// Binds the discovery port with address reuse, so that several clients on
+/// the same machine can listen to the group.
+fn bind_v4() -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)).into())?;
+    socket.join_multicast_v4(&GROUP_V4, &Ipv4Addr::UNSPECIFIED)?;
+    Ok(socket.into())
+}
+
+fn bind_v6() -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, LSD_PORT)).into())?;
+    socket.join_multicast_v6(&GROUP_V6, 0)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn message(header: &str, cookie: &str) -> String {
+        format!(
+            "{header}\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: {INFO_HASH}\r\ncookie: {cookie}\r\n\r\n\r\n"
+        )
+    }
+
+    #[test]
+    fn parses_announces() {
+        let announce = Announce::parse(&message("BT-SEARCH * HTTP/1.1", "abcd")).unwrap();
+        assert_eq!(announce.port, 6881);
+        assert_eq!(
+            announce.info_hashes,
+            [InfoHash(
+                hex::decode(INFO_HASH).unwrap().try_into().unwrap()
+            )]
+        );
+        assert_eq!(announce.cookie.as_deref(), Some("abcd"));
+
+        assert!(Announce::parse(&message("NOTIFY * HTTP/1.1", "abcd")).is_none());
+        assert!(Announce::parse("BT-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\r\n").is_some());
+        assert!(Announce::parse("BT-SEARCH * HTTP/1.1\r\nInfohash: 00\r\nPort: 1\r\n").is_none());
+        assert!(Announce::parse("BT-SEARCH * HTTP/1.1\r\nPort: none\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn ignores_own_announces() {
+        let lsd = Lsd {
+            sockets: Vec::new(),
+            cookie: "abcd".to_string(),
+            torrents: Mutex::new(HashMap::new()),
+        };
+        let own = message("BT-SEARCH * HTTP/1.1", "abcd");
+        assert!(lsd.parse_announce(own.as_bytes()).is_none());
+        let other = message("BT-SEARCH * HTTP/1.1", "ef01");
+        assert!(lsd.parse_announce(other.as_bytes()).is_some());
+    }
+}
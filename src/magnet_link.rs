@@ -1,6 +1,5 @@
 use anyhow::Error;
 use reqwest::Url;
-use urlencoding;
 
 const EXACT_TOPIC: &str = "xt";
 const DISPLAY_NAME: &str = "dn";
@@ -15,6 +14,7 @@ pub struct MagnetLink {
 }
 
 pub struct InfoHash {
+    #[allow(dead_code)]
     pub urn: String,
     pub hash: String,
 }
@@ -61,7 +61,7 @@ impl MagnetLink {
         });
         magnet_link.peer_address = params.iter().filter_map(|(name, value)| {
             if *name == PEER_ADDRESS {
-                let decoded_url = urlencoding::decode(*&value).ok()?;
+                let decoded_url = urlencoding::decode(value).ok()?;
                 reqwest::Url::parse(decoded_url.as_ref()).ok()
             } else {
                 None
@@ -69,7 +69,7 @@ impl MagnetLink {
         }).collect();
         magnet_link.tracker_address = params.iter().filter_map(|(name, value)| {
             if *name == TRACKER_ADDRESS {
-                let decoded_url = urlencoding::decode(*&value).ok()?;
+                let decoded_url = urlencoding::decode(value).ok()?;
                 reqwest::Url::parse(decoded_url.as_ref()).ok()
             } else {
                 None
@@ -1,18 +1,49 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{Error, Result};
+use clap::{builder::PossibleValuesParser, Args, Parser, Subcommand, ValueEnum};
+use create::{create_torrent, MetaVersion, DEFAULT_PIECE_LENGTH};
+use dht::{Dht, DEFAULT_BOOTSTRAP_NODES, DEFAULT_DHT_PORT, DEFAULT_STATE_PATH};
+use dht_item::Item;
+use edit::{edit_torrent, EDITABLE_KEYS};
+use lint::{lint, Severity};
+use listener::{DEFAULT_MAX_INBOUND_CONNECTIONS, DEFAULT_PORT};
 use magnet_link::MagnetLink;
 use peer::{download_peice, handshake};
-use std::{net::SocketAddrV4, path::PathBuf};
+use routing_table::NodeId;
+use serde_bencode::value::Value;
+use std::{net::{SocketAddr, SocketAddrV4}, path::PathBuf, sync::Arc};
+use swarm::DEFAULT_MAX_HASH_FAILURES;
 use torrent_file::TorrentFile;
 
-use crate::file_download::download_file;
+use crate::{file_download::download_file, seed::seed_file};
 
+mod bloom_filter;
+mod choker;
+mod create;
 mod decode;
+mod dht;
+mod dht_item;
+mod edit;
+mod extension;
 mod file_download;
+mod krpc;
+mod lint;
+mod listener;
+mod lsd;
+mod magnet_link;
+mod merkle;
+mod output;
 mod peer;
+mod peer_id;
+mod pex;
+mod picker;
+mod routing_table;
+mod seed;
+mod session;
+mod storage;
+mod swarm;
 mod torrent_file;
 mod tracker;
-mod magnet_link;
+mod web_seed;
 
 #[derive(Parser, Debug)]
 struct Cli {
@@ -28,13 +59,21 @@ enum Command {
     },
     Info {
         file_path: PathBuf,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
     },
     Peers {
         file_path: PathBuf,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+        #[command(flatten)]
+        dht: DhtArgs,
     },
     Handshake {
         file_path: PathBuf,
         peer: SocketAddrV4,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
     },
     DownloadPiece {
         #[arg(short)]
@@ -46,10 +85,223 @@ enum Command {
         #[arg(short)]
         output: PathBuf,
         file_path: PathBuf,
+        /// Port to accept incoming peer connections on.
+        #[arg(long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// Maximum number of simultaneous inbound peer connections. Outbound
+        /// connections are not counted.
+        #[arg(long, default_value_t = DEFAULT_MAX_INBOUND_CONNECTIONS)]
+        max_inbound_connections: usize,
+        /// Number of corrupt pieces a peer may send before it is banned.
+        #[arg(long, default_value_t = DEFAULT_MAX_HASH_FAILURES)]
+        max_hash_failures: usize,
+        /// Peer to connect to besides the ones from trackers, may be
+        /// repeated. Ignored for private torrents.
+        #[arg(long)]
+        peer: Vec<SocketAddr>,
+        #[command(flatten)]
+        dht: DhtArgs,
     },
     MagnetParse {
         link: String,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    Seed {
+        file_path: PathBuf,
+        data_path: PathBuf,
+        /// Port to accept incoming peer connections on.
+        #[arg(long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// Maximum number of simultaneous inbound peer connections. Outbound
+        /// connections are not counted.
+        #[arg(long, default_value_t = DEFAULT_MAX_INBOUND_CONNECTIONS)]
+        max_inbound_connections: usize,
+        #[command(flatten)]
+        dht: DhtArgs,
+    },
+    /// Creates a torrent from a file or directory.
+    Create {
+        #[arg(short)]
+        output: PathBuf,
+        source: PathBuf,
+        /// Tracker announce URL. Torrents without one rely on the DHT.
+        #[arg(long)]
+        announce: Option<String>,
+        /// Bytes per piece, a power of two of at least 16 KiB.
+        #[arg(long, default_value_t = DEFAULT_PIECE_LENGTH)]
+        piece_length: usize,
+        /// Hashes to include: v1 piece hashes, v2 merkle trees or both.
+        #[arg(long, value_enum, default_value_t = MetaVersion::V1)]
+        version: MetaVersion,
+        /// Only use peers from the torrent's trackers (BEP 27).
+        #[arg(long)]
+        private: bool,
+    },
+    /// Rewrites the keys outside a torrent's info dictionary, keeping its
+    /// info hash.
+    Edit {
+        #[arg(short)]
+        output: PathBuf,
+        file_path: PathBuf,
+        /// New tracker URL.
+        #[arg(long)]
+        announce: Option<String>,
+        /// Tier of comma separated tracker URLs, may be repeated. Replaces
+        /// the announce list.
+        #[arg(long)]
+        announce_list: Vec<String>,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long)]
+        created_by: Option<String>,
+        /// Web seed URL, may be repeated. Replaces the URL list.
+        #[arg(long)]
+        url_list: Vec<String>,
+        /// Key to remove, may be repeated.
+        #[arg(long, value_parser = PossibleValuesParser::new(EDITABLE_KEYS))]
+        remove: Vec<String>,
+    },
+    /// Checks a torrent file for structural errors and oddities.
+    Lint {
+        file_path: PathBuf,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Fetches an item from the DHT by its target or, for mutable items, by
+    /// the public key it was signed with.
+    #[command(alias = "dht-get")]
+    DhtGet {
+        /// Hex encoded target of an immutable item.
+        #[arg(required_unless_present = "public_key")]
+        target: Option<String>,
+        /// Hex encoded public key of a mutable item.
+        #[arg(long, conflicts_with = "target")]
+        public_key: Option<String>,
+        /// Salt of a mutable item.
+        #[arg(long, default_value = "", requires = "public_key")]
+        salt: String,
+        #[command(flatten)]
+        dht: DhtNodeArgs,
+    },
+    /// Estimates the size of a swarm from the DHT.
+    #[command(alias = "dht-scrape")]
+    DhtScrape {
+        file_path: PathBuf,
+        /// Also list the info hashes stored by nodes near the torrent.
+        #[arg(long)]
+        samples: bool,
+        #[command(flatten)]
+        dht: DhtNodeArgs,
+    },
+    /// Stores a string in the DHT.
+    #[command(alias = "dht-put")]
+    DhtPut {
+        value: String,
+        /// File with the hex encoded Ed25519 secret key which makes the item
+        /// mutable. A new key is generated when the file does not exist.
+        #[arg(long)]
+        key_file: Option<PathBuf>,
+        /// Salt of a mutable item.
+        #[arg(long, default_value = "", requires = "key_file")]
+        salt: String,
+        /// Sequence number of a mutable item, one above the stored item by
+        /// default.
+        #[arg(long, requires = "key_file")]
+        seq: Option<i64>,
+        #[command(flatten)]
+        dht: DhtNodeArgs,
+    },
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    /// Human readable lines.
+    Text,
+    /// A single JSON document.
+    Json,
+}
+
+#[derive(Args, Debug)]
+#[clap(rename_all = "snake_case")]
+struct DhtArgs {
+    /// Find peers on the DHT even when the torrent has a tracker.
+    #[arg(long)]
+    dht: bool,
+    #[command(flatten)]
+    node: DhtNodeArgs,
+}
+
+#[derive(Args, Debug)]
+#[clap(rename_all = "snake_case")]
+struct DhtNodeArgs {
+    /// UDP port of the DHT node.
+    #[arg(long, default_value_t = DEFAULT_DHT_PORT)]
+    dht_port: u16,
+    /// DHT node (`host:port`) to bootstrap from, may be repeated.
+    #[arg(long)]
+    dht_bootstrap: Vec<String>,
+    /// File the DHT routing table is kept in between runs.
+    #[arg(long, default_value = DEFAULT_STATE_PATH)]
+    dht_state: PathBuf,
+}
+
+impl DhtArgs {
+    /// Starts a DHT node when asked to or when the torrent has no tracker.
+    /// Private torrents never use the DHT.
+    fn start(&self, torrent: &TorrentFile) -> Result<Option<Arc<Dht>>> {
+        if torrent.info.is_private() {
+            if self.dht {
+                eprintln!("Not using the DHT: the torrent is private");
+            }
+            return Ok(None);
+        }
+        if !self.dht && torrent.announce.is_some() {
+            return Ok(None);
+        }
+        self.node.open(&torrent.nodes).map(Some)
+    }
+
+    fn save(&self, dht: Option<Arc<Dht>>) -> Result<()> {
+        match dht {
+            Some(dht) => self.node.save(&dht),
+            None => Ok(()),
+        }
+    }
+}
+
+impl DhtNodeArgs {
+    fn open(&self, nodes: &[(String, u16)]) -> Result<Arc<Dht>> {
+        let dht = Dht::bind(self.dht_port, &self.dht_state)?;
+        let mut bootstrap = if self.dht_bootstrap.is_empty() {
+            DEFAULT_BOOTSTRAP_NODES.map(String::from).to_vec()
+        } else {
+            self.dht_bootstrap.clone()
+        };
+        bootstrap.extend(nodes.iter().map(|(host, port)| format!("{host}:{port}")));
+        dht.bootstrap(&bootstrap);
+        eprintln!("DHT routing table has {} nodes", dht.node_count());
+        Ok(dht)
+    }
+
+    fn save(&self, dht: &Dht) -> Result<()> {
+        dht.save(&self.dht_state)
+    }
+}
+
+fn print_json(value: &serde_json::Value) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn print_info_hashes(torrent: &TorrentFile) -> Result<()> {
+    if torrent.info.is_v1() {
+        println!("Info Hash: {}", hex::encode(torrent.info.hash()?.0));
+    }
+    if let Some(hash) = torrent.info.hash_v2()? {
+        println!("Info Hash v2: {}", hex::encode(hash.0));
     }
+    Ok(())
 }
 
 #[tokio::main]
@@ -60,28 +312,63 @@ async fn main() -> Result<()> {
             let (_, decoded_value) = decode::decode_bencoded_value(encoded_value)?;
             println!("{}", decoded_value);
         }
-        Command::Info { file_path } => {
+        Command::Info { file_path, format } => {
             let file = std::fs::read(file_path)?;
-            let torrent = serde_bencode::from_bytes::<TorrentFile>(&file)?;
-            println!("{torrent}");
+            let torrent = TorrentFile::from_bytes(&file)?;
+            // Rejects v2 torrents whose piece layers do not match their files.
+            torrent.piece_layers()?;
+            match format {
+                OutputFormat::Text => println!("{torrent}"),
+                OutputFormat::Json => print_json(&output::torrent(&torrent)?)?,
+            }
         }
-        Command::Peers { file_path } => {
+        Command::Peers { file_path, format, dht: dht_args } => {
             let file = std::fs::read(file_path)?;
-            let torrent = serde_bencode::from_bytes::<TorrentFile>(&file)?;
+            let torrent = TorrentFile::from_bytes(&file)?;
             let info_hash = torrent.info.hash()?;
-            let peers =
-                tracker::discover_peers(torrent.announce.as_str(), &info_hash, torrent.info.length)
-                    .await?;
-            for peer in peers {
-                println!("{}", peer.0);
+            let dht = dht_args.start(&torrent)?;
+            let mut peers = Vec::new();
+            if let Some(announce) = &torrent.announce {
+                let tracker_peers =
+                    tracker::discover_peers(announce, &info_hash, torrent.info.total_length())
+                        .await?;
+                peers.extend(tracker_peers.into_iter().map(|peer| peer.0));
+            }
+            if let Some(dht) = &dht {
+                for peer in dht.find_peers(&info_hash, None, false) {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+            }
+            dht_args.save(dht)?;
+            match format {
+                OutputFormat::Text => {
+                    for peer in peers {
+                        println!("{peer}");
+                    }
+                }
+                OutputFormat::Json => {
+                    let peers = peers.into_iter().map(SocketAddr::V4).collect::<Vec<_>>();
+                    print_json(&output::peers(&peers))?;
+                }
             }
         }
-        Command::Handshake { file_path, peer } => {
+        Command::Handshake { file_path, peer, format } => {
             let file = std::fs::read(file_path)?;
-            let torrent = serde_bencode::from_bytes::<TorrentFile>(&file)?;
+            let torrent = TorrentFile::from_bytes(&file)?;
             let mut stream = tokio::net::TcpStream::connect(peer).await?;
             let peer_id = handshake(&torrent.info.hash()?, &mut stream).await?;
-            println!("Peer ID: {peer_id}");
+            let client = peer_id::client_name(&peer_id);
+            match format {
+                OutputFormat::Text => {
+                    println!("Peer ID: {}", hex::encode(peer_id));
+                    println!("Client: {}", client.as_deref().unwrap_or("unknown"));
+                }
+                OutputFormat::Json => {
+                    print_json(&output::handshake(&SocketAddr::V4(*peer), &peer_id, client))?
+                }
+            }
         }
         Command::DownloadPiece {
             output,
@@ -89,24 +376,247 @@ async fn main() -> Result<()> {
             piece: piece_index,
         } => {
             let file = std::fs::read(file_path)?;
-            let torrent = serde_bencode::from_bytes::<TorrentFile>(&file)?;
+            let torrent = TorrentFile::from_bytes(&file)?;
             let piece = download_peice(&torrent, *piece_index).await?;
             std::fs::write(output, &piece)?;
             println!("Piece {piece_index} downloaded to {output:?}");
         }
-        Command::Download { output, file_path } => {
+        Command::Download {
+            output,
+            file_path,
+            port,
+            max_inbound_connections,
+            max_hash_failures,
+            peer,
+            dht: dht_args,
+        } => {
             let file = std::fs::read(file_path)?;
-            let torrent = serde_bencode::from_bytes::<TorrentFile>(&file)?;
-            download_file(torrent, output).await?;
+            let torrent = TorrentFile::from_bytes(&file)?;
+            let dht = dht_args.start(&torrent)?;
+            download_file(
+                torrent,
+                output,
+                *port,
+                *max_inbound_connections,
+                *max_hash_failures,
+                peer.clone(),
+                dht.clone(),
+            )
+            .await?;
+            dht_args.save(dht)?;
             println!("Downloaded {file_path:?} to {output:?}");
         },
-        Command::MagnetParse { link } => {
+        Command::MagnetParse { link, format } => {
             let magnet_link = MagnetLink::parse(link.as_str())?;
+            if *format == OutputFormat::Json {
+                print_json(&output::magnet_link(&magnet_link))?;
+                return Ok(());
+            }
             for tracker in magnet_link.tracker_address {
                 println!("Tracker URL: {tracker}");
             }
             println!("Info Hash: {}", magnet_link.info_hash.hash);
         }
+        Command::Seed {
+            file_path,
+            data_path,
+            port,
+            max_inbound_connections,
+            dht: dht_args,
+        } => {
+            let file = std::fs::read(file_path)?;
+            let torrent = TorrentFile::from_bytes(&file)?;
+            let dht = dht_args.start(&torrent)?;
+            seed_file(
+                torrent,
+                data_path,
+                *port,
+                *max_inbound_connections,
+                dht.clone(),
+            )
+            .await?;
+            dht_args.save(dht)?;
+        }
+        Command::Create {
+            output,
+            source,
+            announce,
+            piece_length,
+            version,
+            private,
+        } => {
+            let file = create_torrent(
+                source,
+                *version,
+                *piece_length,
+                announce.as_deref(),
+                *private,
+            )?;
+            std::fs::write(output, &file)?;
+            println!("Created {output:?}");
+            print_info_hashes(&TorrentFile::from_bytes(&file)?)?;
+        }
+        Command::Edit {
+            output,
+            file_path,
+            announce,
+            announce_list,
+            comment,
+            created_by,
+            url_list,
+            remove,
+        } => {
+            let file = std::fs::read(file_path)?;
+            let string = |value: &str| Value::Bytes(value.as_bytes().to_vec());
+            let mut changes = remove
+                .iter()
+                .map(|key| (key.as_str(), None))
+                .collect::<Vec<_>>();
+            if let Some(announce) = announce {
+                changes.push(("announce", Some(string(announce))));
+            }
+            if !announce_list.is_empty() {
+                let tiers = announce_list
+                    .iter()
+                    .map(|tier| Value::List(tier.split(',').map(string).collect()))
+                    .collect();
+                changes.push(("announce-list", Some(Value::List(tiers))));
+            }
+            if let Some(comment) = comment {
+                changes.push(("comment", Some(string(comment))));
+            }
+            if let Some(created_by) = created_by {
+                changes.push(("created by", Some(string(created_by))));
+            }
+            if !url_list.is_empty() {
+                changes.push((
+                    "url-list",
+                    Some(Value::List(
+                        url_list.iter().map(|url| string(url)).collect(),
+                    )),
+                ));
+            }
+            let edited = edit_torrent(&file, &changes)?;
+            std::fs::write(output, &edited)?;
+            println!("Edited {file_path:?} into {output:?}");
+            print_info_hashes(&TorrentFile::from_bytes(&edited)?)?;
+        }
+        Command::Lint { file_path, format } => {
+            let file = std::fs::read(file_path)?;
+            let report = lint(&file);
+            match format {
+                OutputFormat::Text => {
+                    for issue in &report.issues {
+                        let severity = match issue.severity {
+                            Severity::Error => "error",
+                            Severity::Warning => "warning",
+                        };
+                        println!("{severity}[{}]: {}", issue.code, issue.message);
+                    }
+                    println!(
+                        "{} errors, {} warnings",
+                        report.count(Severity::Error),
+                        report.count(Severity::Warning)
+                    );
+                }
+                OutputFormat::Json => print_json(&serde_json::to_value(&report)?)?,
+            }
+            if !report.valid {
+                return Err(Error::msg(format!("{file_path:?} is not a valid torrent")));
+            }
+        }
+        Command::DhtGet {
+            target,
+            public_key,
+            salt,
+            dht: dht_args,
+        } => {
+            let dht = dht_args.open(&[])?;
+            let (target, salt) = match (public_key, target) {
+                (Some(public_key), _) => {
+                    let public_key = hex::decode(public_key)?
+                        .try_into()
+                        .map_err(|_| Error::msg("Public key must be 32 bytes"))?;
+                    (dht_item::mutable_target(&public_key, salt.as_bytes()), salt.as_bytes())
+                }
+                (None, target) => {
+                    let target = hex::decode(target.as_deref().unwrap_or_default())?;
+                    let target = NodeId::from_slice(&target)
+                        .ok_or_else(|| Error::msg("Target must be 20 bytes"))?;
+                    (target, &[][..])
+                }
+            };
+            let lookup = dht.get_item(&target, salt);
+            dht_args.save(&dht)?;
+            let Some(item) = lookup.item else {
+                return Err(Error::msg("Item not found"));
+            };
+            if let Some(seq) = item.seq() {
+                println!("Sequence: {seq}");
+            }
+            println!("{}", dht_item::display_value(&item.value)?);
+        }
+        Command::DhtScrape {
+            file_path,
+            samples,
+            dht: dht_args,
+        } => {
+            let file = std::fs::read(file_path)?;
+            let torrent = TorrentFile::from_bytes(&file)?;
+            let info_hash = torrent.info.hash()?;
+            let dht = dht_args.open(&torrent.nodes)?;
+            let scrape = dht.scrape(&info_hash);
+            println!("Seeders: {:.0}", scrape.seeds);
+            println!("Leechers: {:.0}", scrape.leechers);
+            if *samples {
+                let (sampled, stored) = dht.sample_swarms(&NodeId(info_hash.0));
+                println!("Sampled {} of {stored} info hashes", sampled.len());
+                for sample in sampled {
+                    println!("{}", hex::encode(sample.0));
+                }
+            }
+            dht_args.save(&dht)?;
+        }
+        Command::DhtPut {
+            value,
+            key_file,
+            salt,
+            seq,
+            dht: dht_args,
+        } => {
+            let dht = dht_args.open(&[])?;
+            let value = Value::Bytes(value.as_bytes().to_vec());
+            let (item, lookup, cas) = match key_file {
+                Some(key_file) => {
+                    let key = dht_item::load_key(key_file)?;
+                    let public_key = key.verifying_key().to_bytes();
+                    let target = dht_item::mutable_target(&public_key, salt.as_bytes());
+                    let lookup = dht.get_item(&target, salt.as_bytes());
+                    let current = lookup.item.as_ref().and_then(Item::seq);
+                    // Only replace the version we based our sequence number on.
+                    let (seq, cas) = match seq {
+                        Some(seq) => (*seq, None),
+                        None => (current.map_or(1, |seq| seq + 1), current),
+                    };
+                    let item = Item::mutable(&key, salt.as_bytes().to_vec(), seq, value)?;
+                    println!("Public key: {}", hex::encode(public_key));
+                    println!("Sequence: {seq}");
+                    (item, lookup, cas)
+                }
+                None => {
+                    let item = Item::immutable(value)?;
+                    let lookup = dht.get_item(&item.target()?, &[]);
+                    (item, lookup, None)
+                }
+            };
+            println!("Target: {}", hex::encode(item.target()?.0));
+            let stored = dht.put_item(&lookup, &item, cas);
+            dht_args.save(&dht)?;
+            if stored == 0 {
+                return Err(Error::msg("No DHT node accepted the item"));
+            }
+            println!("Stored on {stored} nodes");
+        }
     }
     Ok(())
 }
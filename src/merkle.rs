@@ -0,0 +1,341 @@
+use anyhow::{Error, Result};
+use sha2::{Digest, Sha256};
+
+use crate::{peer::HashRequestPayload, torrent_file::TorrentFile};
+
+/// Size of the blocks hashed into the leaves of a v2 file's merkle tree.
+pub const BLOCK_SIZE: usize = 1 << 14;
+pub const HASH_SIZE: usize = 32;
+
+pub type Hash = [u8; HASH_SIZE];
+
+pub fn hash(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+pub fn parent(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Root of a subtree of `height` levels whose leaves are all zero, which is
+/// how trees are padded beyond the end of a file (BEP 52).
+pub fn pad(height: u32) -> Hash {
+    (0..height).fold([0; HASH_SIZE], |hash, _| parent(&hash, &hash))
+}
+
+/// Root of the tree over `hashes`, which are roots of subtrees of `height`
+/// levels, padded with empty subtrees to `width` entries. `width` must be a
+/// power of two.
+pub fn root(hashes: &[Hash], height: u32, width: usize) -> Hash {
+    let mut layer = hashes.to_vec();
+    let mut height = height;
+    let mut width = width;
+    while width > 1 {
+        if layer.len() % 2 == 1 {
+            layer.push(pad(height));
+        }
+        layer = layer
+            .chunks(2)
+            .map(|pair| parent(&pair[0], &pair[1]))
+            .collect();
+        height += 1;
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or_else(|| pad(height))
+}
+
+/// Number of levels of the subtree covering one piece.
+pub fn piece_height(piece_length: usize) -> u32 {
+    (piece_length / BLOCK_SIZE).max(1).ilog2()
+}
+
+/// Hashes of the blocks of some data, the leaves of its merkle tree.
+pub fn leaves(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_SIZE).map(hash).collect()
+}
+
+/// Most hashes served in answer to a single request.
+const MAX_HASHES: usize = 512;
+
+/// Merkle trees of the files of a v2 or hybrid torrent, placed in its pieces.
+/// They let every block be checked on its own as soon as it arrives, and are
+/// the only piece hashes of v2-only torrents.
+pub struct MerkleTrees {
+    piece_length: usize,
+    files: Vec<MerkleFile>,
+}
+
+struct MerkleFile {
+    pieces_root: Hash,
+    /// Where the file starts in the pieces. Files are aligned to pieces.
+    offset: usize,
+    length: usize,
+    /// Hashes of the file's pieces. Empty when the file fits in one piece.
+    piece_layer: Vec<Hash>,
+}
+
+impl MerkleFile {
+    /// Number of leaves of the tree, including the padding beyond the end
+    /// of the file.
+    fn width(&self, piece_length: usize) -> usize {
+        if self.piece_layer.is_empty() {
+            self.length.div_ceil(BLOCK_SIZE).next_power_of_two()
+        } else {
+            self.piece_layer.len().next_power_of_two() * (piece_length / BLOCK_SIZE)
+        }
+    }
+}
+
+/// Part of a file's merkle tree covering the blocks of one piece.
+pub struct PieceTree {
+    pub pieces_root: Hash,
+    /// Index of the first leaf.
+    pub index: usize,
+    /// Number of leaves, a power of two.
+    pub width: usize,
+    /// Root of the subtree, known from the torrent.
+    pub root: Hash,
+}
+
+impl MerkleTrees {
+    /// Returns `None` for v1-only torrents.
+    pub fn new(torrent: &TorrentFile) -> Result<Option<Self>> {
+        let info = &torrent.info;
+        if !info.is_v2() {
+            return Ok(None);
+        }
+        let piece_length = info.piece_length;
+        if piece_length < BLOCK_SIZE || !piece_length.is_power_of_two() {
+            return Err(Error::msg(format!(
+                "Invalid piece length {piece_length} for a v2 torrent"
+            )));
+        }
+        let mut layers = torrent.piece_layers()?;
+        let mut files = Vec::new();
+        let mut offset = 0;
+        for file in info.v2_files()? {
+            if let Some(pieces_root) = file.pieces_root {
+                files.push(MerkleFile {
+                    pieces_root,
+                    offset,
+                    length: file.length,
+                    piece_layer: layers.remove(&pieces_root).unwrap_or_default(),
+                });
+            }
+            offset += file.length.next_multiple_of(piece_length);
+        }
+        let end = files.last().map_or(0, |file| file.offset + file.length);
+        if end != info.total_length() {
+            return Err(Error::msg(
+                "The v1 and v2 files of the torrent do not match",
+            ));
+        }
+        Ok(Some(Self {
+            piece_length,
+            files,
+        }))
+    }
+
+    /// File the piece belongs to and the piece's index within the file.
+    fn file(&self, piece: usize) -> Option<(&MerkleFile, usize)> {
+        let start = piece * self.piece_length;
+        let file = self
+            .files
+            .iter()
+            .find(|file| file.offset <= start && start < file.offset + file.length)?;
+        Some((file, (start - file.offset) / self.piece_length))
+    }
+
+    pub fn piece_tree(&self, piece: usize) -> Option<PieceTree> {
+        let (file, index) = self.file(piece)?;
+        if file.piece_layer.is_empty() {
+            return Some(PieceTree {
+                pieces_root: file.pieces_root,
+                index: 0,
+                width: file.width(self.piece_length),
+                root: file.pieces_root,
+            });
+        }
+        let width = self.piece_length / BLOCK_SIZE;
+        Some(PieceTree {
+            pieces_root: file.pieces_root,
+            index: index * width,
+            width,
+            root: file.piece_layer[index],
+        })
+    }
+
+    /// Piece whose blocks are the leaves starting at `index` in the file's
+    /// tree.
+    pub fn piece_at(&self, pieces_root: &Hash, index: usize) -> Option<usize> {
+        let file = self
+            .files
+            .iter()
+            .find(|file| &file.pieces_root == pieces_root)?;
+        Some(file.offset / self.piece_length + index * BLOCK_SIZE / self.piece_length)
+    }
+
+    /// Checks a whole piece against the root of its subtree, from the piece
+    /// layer or the pieces root of its file.
+    pub fn verify_piece(&self, piece: usize, data: &[u8]) -> bool {
+        let (Some((file, index)), Some(tree)) = (self.file(piece), self.piece_tree(piece)) else {
+            return false;
+        };
+        let length = data.len().min(file.length - index * self.piece_length);
+        root(&leaves(&data[..length]), 0, tree.width) == tree.root
+    }
+
+    /// Checks a block of a piece against the leaves of the piece's subtree.
+    /// Blocks of the padding after a file are left to the piece hash.
+    pub fn verify_block(&self, piece: usize, begin: usize, block: &[u8], leaves: &[Hash]) -> bool {
+        let Some((file, _)) = self.file(piece) else {
+            return true;
+        };
+        let offset = piece * self.piece_length + begin - file.offset;
+        if offset >= file.length {
+            return true;
+        }
+        let length = block.len().min(file.length - offset);
+        leaves
+            .get(begin / BLOCK_SIZE)
+            .is_none_or(|leaf| *leaf == hash(&block[..length]))
+    }
+
+    /// Answers a hash request. `read` returns the data at an offset of the
+    /// pieces, or `None` when it is not downloaded.
+    pub fn hashes(
+        &self,
+        request: &HashRequestPayload,
+        read: impl Fn(usize, usize) -> Option<Vec<u8>>,
+    ) -> Option<Vec<Hash>> {
+        let file = self
+            .files
+            .iter()
+            .find(|file| file.pieces_root == request.pieces_root())?;
+        let width = file.width(self.piece_length);
+        let height = width.ilog2();
+        // Every field comes from the peer, so the arithmetic is checked.
+        let (base, index, length) = (request.base_layer(), request.index(), request.length());
+        if !length.is_power_of_two() || length > MAX_HASHES || !index.is_multiple_of(length) {
+            return None;
+        }
+        let level = base
+            .checked_add(length.ilog2())
+            .filter(|level| *level <= height)?;
+        index
+            .checked_add(length)
+            .zip(1usize.checked_shl(base))
+            .and_then(|(end, span)| end.checked_mul(span))
+            .filter(|end| *end <= width)?;
+        let proof_end = level.checked_add(request.proof_layers())?;
+        let mut hashes = (index..index + length)
+            .map(|index| self.node(file, base, index, &read))
+            .collect::<Option<Vec<_>>>()?;
+        // Uncle hashes up to the root, or as many as requested.
+        let mut index = index / length;
+        for level in level..height.min(proof_end) {
+            hashes.push(self.node(file, level, index ^ 1, &read)?);
+            index /= 2;
+        }
+        Some(hashes)
+    }
+
+    /// Hash of a node of the file's tree, from the piece layer when it is
+    /// high enough and from the data otherwise.
+    fn node(
+        &self,
+        file: &MerkleFile,
+        level: u32,
+        index: usize,
+        read: &impl Fn(usize, usize) -> Option<Vec<u8>>,
+    ) -> Option<Hash> {
+        let height = piece_height(self.piece_length);
+        if !file.piece_layer.is_empty() && level >= height {
+            let span = 1 << (level - height);
+            let start = (index * span).min(file.piece_layer.len());
+            let end = (start + span).min(file.piece_layer.len());
+            return Some(root(&file.piece_layer[start..end], height, span));
+        }
+        let span = 1 << level;
+        let start = index * span * BLOCK_SIZE;
+        if start >= file.length {
+            return Some(pad(level));
+        }
+        let end = (start + span * BLOCK_SIZE).min(file.length);
+        let data = read(file.offset + start, end - start)?;
+        Some(root(&leaves(&data), 0, span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{create_torrent, MetaVersion};
+
+    const PIECE_LENGTH: usize = 1 << 15;
+
+    #[test]
+    fn verifies_v2_only_pieces() {
+        let directory = tempfile::tempdir().unwrap();
+        // One file with a piece layer, and one smaller than a piece which is
+        // checked against its pieces root.
+        let large = (0..80000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let small = vec![7; 20000];
+        std::fs::write(directory.path().join("large"), &large).unwrap();
+        std::fs::write(directory.path().join("small"), &small).unwrap();
+        let torrent =
+            create_torrent(directory.path(), MetaVersion::V2, PIECE_LENGTH, None, false).unwrap();
+        let torrent = TorrentFile::from_bytes(&torrent).unwrap();
+        let trees = MerkleTrees::new(&torrent).unwrap().unwrap();
+
+        // The large file is padded to three pieces, so the small one starts
+        // at piece 3.
+        assert_eq!(torrent.info.total_length(), 3 * PIECE_LENGTH + small.len());
+        for (index, piece) in large.chunks(PIECE_LENGTH).enumerate() {
+            assert!(trees.verify_piece(index, piece));
+        }
+        assert!(trees.verify_piece(3, &small));
+        assert!(!trees.verify_piece(0, &large[PIECE_LENGTH..2 * PIECE_LENGTH]));
+        assert!(!trees.verify_piece(3, &[8; 20000]));
+        assert!(!trees.verify_piece(4, &small));
+    }
+
+    #[test]
+    fn rejects_malformed_hash_requests() {
+        let directory = tempfile::tempdir().unwrap();
+        let data = (0..100000).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+        std::fs::write(directory.path().join("file"), &data).unwrap();
+        let torrent =
+            create_torrent(directory.path(), MetaVersion::V2, PIECE_LENGTH, None, false).unwrap();
+        let torrent = TorrentFile::from_bytes(&torrent).unwrap();
+        let trees = MerkleTrees::new(&torrent).unwrap().unwrap();
+        let pieces_root = trees.files[0].pieces_root;
+        let read = |offset: usize, length: usize| Some(data[offset..offset + length].to_vec());
+
+        // The two leaves of the first piece, with proofs up to the root of
+        // the tree over four pieces.
+        let request = HashRequestPayload::new(&pieces_root, 0, 0, 2, 10);
+        let hashes = trees.hashes(&request, read).unwrap();
+        assert_eq!(hashes[..2], leaves(&data[..PIECE_LENGTH]));
+        assert_eq!(hashes.len(), 2 + 2);
+
+        for (base, index, length, proof_layers) in [
+            (u32::MAX, 0, 1, u32::MAX),
+            (u32::MAX, 0, 2, 0),
+            (0, 0, 2, u32::MAX),
+            (31, 0, 1, 0),
+            (64, 0, 1, 0),
+            (0, u32::MAX as usize, 1, 0),
+            (0, 0, 3, 0),
+        ] {
+            let request = HashRequestPayload::new(&pieces_root, base, index, length, proof_layers);
+            assert!(
+                trees.hashes(&request, read).is_none(),
+                "{base} {index} {length} {proof_layers}"
+            );
+        }
+    }
+}
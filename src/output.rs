@@ -0,0 +1,171 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::{magnet_link::MagnetLink, torrent_file::TorrentFile};
+
+// Documents printed with `--format json`. Scripts rely on their keys, so keys
+// may be added but are never renamed or removed. Hashes are hex encoded and
+// missing values are null.
+
+pub fn torrent(torrent: &TorrentFile) -> Result<Value> {
+    let info = &torrent.info;
+    let pieces_roots = info
+        .v2_files()?
+        .into_iter()
+        .map(|file| (file.path, file.pieces_root))
+        .collect::<HashMap<_, _>>();
+    let files = info
+        .files()
+        .into_iter()
+        .map(|file| {
+            // The file of a single-file torrent is named after the torrent.
+            let path = if file.path.is_empty() {
+                vec![info.name.clone()]
+            } else {
+                file.path
+            };
+            let pieces_root = pieces_roots.get(&path).copied().flatten();
+            json!({
+                "path": path.join("/"),
+                "length": file.length,
+                "attr": file.attr,
+                "pieces_root": pieces_root.map(hex::encode),
+            })
+        })
+        .collect::<Vec<_>>();
+    let trackers = torrent
+        .announce
+        .iter()
+        .chain(torrent.announce_list.iter().flatten())
+        .fold(Vec::new(), |mut trackers, url| {
+            if !trackers.contains(url) {
+                trackers.push(url.clone());
+            }
+            trackers
+        });
+    Ok(json!({
+        "name": info.name,
+        "length": info.total_length(),
+        "piece_length": info.piece_length,
+        "private": info.is_private(),
+        "info_hash": if info.is_v1() { Some(hex::encode(info.hash()?.0)) } else { None },
+        "info_hash_v2": info.hash_v2()?.map(|hash| hex::encode(hash.0)),
+        "trackers": trackers,
+        "web_seeds": torrent.url_list.iter().chain(&torrent.httpseeds).collect::<Vec<_>>(),
+        "piece_hashes": info.pieces.iter().map(hex::encode).collect::<Vec<_>>(),
+        "files": files,
+    }))
+}
+
+pub fn peers(peers: &[SocketAddr]) -> Value {
+    let peers = peers
+        .iter()
+        .map(|peer| json!({ "ip": peer.ip().to_string(), "port": peer.port() }))
+        .collect::<Vec<_>>();
+    json!({ "peers": peers })
+}
+
+pub fn magnet_link(link: &MagnetLink) -> Value {
+    json!({
+        "info_hash": link.info_hash.hash,
+        "name": link.display_name,
+        "trackers": link.tracker_address.iter().map(|url| url.as_str()).collect::<Vec<_>>(),
+        "peers": link.peer_address.iter().map(|url| url.as_str()).collect::<Vec<_>>(),
+    })
+}
+
+pub fn handshake(peer: &SocketAddr, peer_id: &[u8], client: Option<String>) -> Value {
+    json!({
+        "peer": { "ip": peer.ip().to_string(), "port": peer.port() },
+        "peer_id": hex::encode(peer_id),
+        "client": client,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::*;
+
+    fn keys(value: &Value) -> Vec<&str> {
+        let mut keys = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn torrent_document() {
+        let torrent = b"d8:announce9:http://a/4:infod6:lengthi3e4:name4:file\
+            12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        let torrent = TorrentFile::from_bytes(torrent).unwrap();
+        let document = super::torrent(&torrent).unwrap();
+        assert_eq!(
+            keys(&document),
+            [
+                "files",
+                "info_hash",
+                "info_hash_v2",
+                "length",
+                "name",
+                "piece_hashes",
+                "piece_length",
+                "private",
+                "trackers",
+                "web_seeds"
+            ]
+        );
+        assert_eq!(
+            document["info_hash"],
+            hex::encode(torrent.info.hash().unwrap().0)
+        );
+        assert_eq!(document["info_hash_v2"], Value::Null);
+        assert_eq!(document["trackers"], json!(["http://a/"]));
+        assert_eq!(document["piece_hashes"], json!([hex::encode([b'a'; 20])]));
+        assert_eq!(
+            document["files"],
+            json!([{ "path": "file", "length": 3, "attr": "", "pieces_root": null }])
+        );
+    }
+
+    #[test]
+    fn peer_documents() {
+        let peer = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881));
+        assert_eq!(
+            peers(&[peer]),
+            json!({ "peers": [{ "ip": "10.0.0.1", "port": 6881 }] })
+        );
+        assert_eq!(
+            handshake(&peer, &[0xab; 20], None),
+            json!({
+                "peer": { "ip": "10.0.0.1", "port": 6881 },
+                "peer_id": "ab".repeat(20),
+                "client": null,
+            })
+        );
+    }
+
+    #[test]
+    fn magnet_link_document() {
+        let link = MagnetLink::parse(
+            "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&tr=http%3A%2F%2Fa%2F",
+        )
+        .unwrap();
+        assert_eq!(
+            magnet_link(&link),
+            json!({
+                "info_hash": "0123456789abcdef0123456789abcdef01234567",
+                "name": null,
+                "trackers": ["http://a/"],
+                "peers": [],
+            })
+        );
+    }
+}
@@ -1,23 +1,25 @@
+use crate::merkle::{Hash, HASH_SIZE};
+use crate::peer_id::{self, PEER_ID_SIZE};
 use crate::torrent_file::{InfoHash, Piece as PieceHash, TorrentFile};
 use crate::tracker;
 use anyhow::{Error, Result};
 use bytes::Buf;
+use sha1::{Digest, Sha1};
+use std::net::Ipv4Addr;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
-pub const PEER_ID: [u8; 20] = *b"00112233445566778899";
-
 #[repr(C)]
 pub struct Handshake {
     protocol_len: u8,
     protocol: [u8; 19],
     reserved: [u8; 8],
     info_hash: [u8; 20],
-    peer_id: [u8; 20],
+    peer_id: [u8; PEER_ID_SIZE],
 }
 
 #[repr(u8)]
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum MessageType {
     Choke,
     Unchoke,
@@ -28,11 +30,23 @@ pub enum MessageType {
     Request,
     Piece,
     Cancel,
+    Port,
+    SuggestPiece,
+    HaveAll,
+    HaveNone,
+    RejectRequest,
+    AllowedFast,
+    Extended,
+    HashRequest,
+    Hashes,
+    HashReject,
 }
 
-impl From<u8> for MessageType {
-    fn from(value: u8) -> Self {
-        match value {
+impl TryFrom<u8> for MessageType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        let message_type = match value {
             0 => MessageType::Choke,
             1 => MessageType::Unchoke,
             2 => MessageType::Interested,
@@ -42,14 +56,47 @@ impl From<u8> for MessageType {
             6 => MessageType::Request,
             7 => MessageType::Piece,
             8 => MessageType::Cancel,
-            _ => panic!("Unsupported message type {value}"),
+            9 => MessageType::Port,
+            0x0D => MessageType::SuggestPiece,
+            0x0E => MessageType::HaveAll,
+            0x0F => MessageType::HaveNone,
+            0x10 => MessageType::RejectRequest,
+            0x11 => MessageType::AllowedFast,
+            20 => MessageType::Extended,
+            21 => MessageType::HashRequest,
+            22 => MessageType::Hashes,
+            23 => MessageType::HashReject,
+            _ => return Err(Error::msg(format!("Unsupported message type {value}"))),
+        };
+        Ok(message_type)
+    }
+}
+
+/// Longest payload accepted from a peer for anything but a bitfield. Blocks,
+/// metadata pieces and hash lists are at most 16 KiB plus a short header.
+pub const MAX_PAYLOAD_LENGTH: usize = 2 * CHUNK_SIZE;
+
+impl MessageType {
+    /// Checks the length of a message's payload before it is allocated, so
+    /// that peers cannot make us allocate arbitrary amounts of memory.
+    /// Bitfields have one bit per piece.
+    pub fn check_payload_length(&self, length: usize, pieces_count: usize) -> Result<()> {
+        let max_length = match self {
+            MessageType::Bitfield => pieces_count.div_ceil(8),
+            _ => MAX_PAYLOAD_LENGTH,
+        };
+        if length > max_length {
+            return Err(Error::msg(format!(
+                "{self:?} message of {length} bytes is too long"
+            )));
         }
+        Ok(())
     }
 }
 
-impl Into<u8> for MessageType {
-    fn into(self) -> u8 {
-        match self {
+impl From<MessageType> for u8 {
+    fn from(value: MessageType) -> Self {
+        match value {
             MessageType::Choke => 0,
             MessageType::Unchoke => 1,
             MessageType::Interested => 2,
@@ -59,6 +106,16 @@ impl Into<u8> for MessageType {
             MessageType::Request => 6,
             MessageType::Piece => 7,
             MessageType::Cancel => 8,
+            MessageType::Port => 9,
+            MessageType::SuggestPiece => 0x0D,
+            MessageType::HaveAll => 0x0E,
+            MessageType::HaveNone => 0x0F,
+            MessageType::RejectRequest => 0x10,
+            MessageType::AllowedFast => 0x11,
+            MessageType::Extended => 20,
+            MessageType::HashRequest => 21,
+            MessageType::Hashes => 22,
+            MessageType::HashReject => 23,
         }
     }
 }
@@ -72,7 +129,7 @@ pub trait TryFromBytes: Sized {
 }
 
 pub struct EmptyPayload;
-const EMPTY_SLICE: &'static [u8; 0] = &[];
+const EMPTY_SLICE: &[u8; 0] = &[];
 
 impl BytesConvertible for EmptyPayload {
     fn as_bytes(&self) -> &[u8] {
@@ -82,17 +139,41 @@ impl BytesConvertible for EmptyPayload {
 
 impl TryFromBytes for EmptyPayload {
     fn try_from_bytes(bytes: Vec<u8>) -> Result<Self> {
-        assert!(bytes.is_empty());
+        if !bytes.is_empty() {
+            return Err(Error::msg(format!(
+                "Expected an empty payload, received {} bytes",
+                bytes.len()
+            )));
+        }
         Ok(Self)
     }
 }
 
+impl BytesConvertible for Vec<u8> {
+    fn as_bytes(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl TryFromBytes for Vec<u8> {
+    fn try_from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        Ok(bytes)
+    }
+}
+
 pub struct Message<Payload> {
     pub message_type: MessageType,
     pub payload: Payload,
 }
 
+impl Message<Vec<u8>> {
+    pub fn parse_payload<P: TryFromBytes>(self) -> Result<P> {
+        P::try_from_bytes(self.payload)
+    }
+}
+
 #[repr(C)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct RequestPayload {
     index: [u8; 4],
     begin: [u8; 4],
@@ -107,6 +188,217 @@ impl RequestPayload {
             length: (length as i32).to_be_bytes(),
         }
     }
+
+    pub fn index(&self) -> usize {
+        u32::from_be_bytes(self.index) as usize
+    }
+
+    pub fn begin(&self) -> usize {
+        u32::from_be_bytes(self.begin) as usize
+    }
+
+    pub fn length(&self) -> usize {
+        u32::from_be_bytes(self.length) as usize
+    }
+}
+
+impl TryFromBytes for RequestPayload {
+    fn try_from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        if bytes.len() != 12 {
+            return Err(Error::msg("Request payload must be 12 bytes."));
+        }
+        Ok(Self {
+            index: bytes[0..4].try_into()?,
+            begin: bytes[4..8].try_into()?,
+            length: bytes[8..12].try_into()?,
+        })
+    }
+}
+
+/// Payload of `Have`, `Suggest Piece` and `Allowed Fast` messages.
+#[repr(C)]
+pub struct HavePayload {
+    index: [u8; 4],
+}
+
+impl HavePayload {
+    pub fn new(index: usize) -> Self {
+        Self {
+            index: (index as u32).to_be_bytes(),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        u32::from_be_bytes(self.index) as usize
+    }
+}
+
+impl BytesConvertible for HavePayload {
+    fn as_bytes(&self) -> &[u8] {
+        &self.index
+    }
+}
+
+impl TryFromBytes for HavePayload {
+    fn try_from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let index: [u8; 4] = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::msg("Have payload must be 4 bytes."))?;
+        Ok(Self { index })
+    }
+}
+
+/// Payload of a `Port` message: the UDP port of the sender's DHT node.
+pub struct PortPayload {
+    port: [u8; 2],
+}
+
+impl PortPayload {
+    pub fn new(port: u16) -> Self {
+        Self {
+            port: port.to_be_bytes(),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        u16::from_be_bytes(self.port)
+    }
+}
+
+impl BytesConvertible for PortPayload {
+    fn as_bytes(&self) -> &[u8] {
+        &self.port
+    }
+}
+
+impl TryFromBytes for PortPayload {
+    fn try_from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let port: [u8; 2] = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::msg("Port payload must be 2 bytes."))?;
+        Ok(Self { port })
+    }
+}
+
+const HASH_REQUEST_SIZE: usize = HASH_SIZE + 16;
+
+/// Payload of `Hash Request` and `Hash Reject` messages (BEP 52): asks for
+/// `length` hashes of a layer of a file's merkle tree, followed by the uncle
+/// hashes proving them up to `proof_layers` layers higher.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashRequestPayload([u8; HASH_REQUEST_SIZE]);
+
+impl HashRequestPayload {
+    pub fn new(
+        pieces_root: &Hash,
+        base_layer: u32,
+        index: usize,
+        length: usize,
+        proof_layers: u32,
+    ) -> Self {
+        let mut bytes = [0; HASH_REQUEST_SIZE];
+        bytes[..HASH_SIZE].copy_from_slice(pieces_root);
+        bytes[HASH_SIZE..HASH_SIZE + 4].copy_from_slice(&base_layer.to_be_bytes());
+        bytes[HASH_SIZE + 4..HASH_SIZE + 8].copy_from_slice(&(index as u32).to_be_bytes());
+        bytes[HASH_SIZE + 8..HASH_SIZE + 12].copy_from_slice(&(length as u32).to_be_bytes());
+        bytes[HASH_SIZE + 12..].copy_from_slice(&proof_layers.to_be_bytes());
+        Self(bytes)
+    }
+
+    pub fn pieces_root(&self) -> Hash {
+        self.0[..HASH_SIZE]
+            .try_into()
+            .expect("Payload starts with a hash")
+    }
+
+    pub fn base_layer(&self) -> u32 {
+        self.field(0)
+    }
+
+    pub fn index(&self) -> usize {
+        self.field(1) as usize
+    }
+
+    pub fn length(&self) -> usize {
+        self.field(2) as usize
+    }
+
+    pub fn proof_layers(&self) -> u32 {
+        self.field(3)
+    }
+
+    fn field(&self, position: usize) -> u32 {
+        let start = HASH_SIZE + position * 4;
+        u32::from_be_bytes(
+            self.0[start..start + 4]
+                .try_into()
+                .expect("Field is 4 bytes"),
+        )
+    }
+}
+
+impl BytesConvertible for HashRequestPayload {
+    fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl TryFromBytes for HashRequestPayload {
+    fn try_from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let bytes = bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| Error::msg("Hash request payload must be 48 bytes."))?;
+        Ok(Self(bytes))
+    }
+}
+
+/// Payload of a `Hashes` message: the request it answers followed by the
+/// requested hashes and their proof.
+pub struct HashesPayload(Vec<u8>);
+
+impl HashesPayload {
+    pub fn new(request: &HashRequestPayload, hashes: &[Hash]) -> Self {
+        let mut bytes = request.0.to_vec();
+        bytes.extend(hashes.iter().flatten());
+        Self(bytes)
+    }
+
+    pub fn request(&self) -> HashRequestPayload {
+        HashRequestPayload(
+            self.0[..HASH_REQUEST_SIZE]
+                .try_into()
+                .expect("Payload starts with a request"),
+        )
+    }
+
+    pub fn hashes(&self) -> Vec<Hash> {
+        self.0[HASH_REQUEST_SIZE..]
+            .chunks_exact(HASH_SIZE)
+            .map(|hash| hash.try_into().expect("Chunk is a hash"))
+            .collect()
+    }
+}
+
+impl BytesConvertible for HashesPayload {
+    fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl TryFromBytes for HashesPayload {
+    fn try_from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        if bytes.len() < HASH_REQUEST_SIZE
+            || !(bytes.len() - HASH_REQUEST_SIZE).is_multiple_of(HASH_SIZE)
+        {
+            return Err(Error::msg(
+                "Hashes payload must be a request followed by hashes.",
+            ));
+        }
+        Ok(Self(bytes))
+    }
 }
 
 impl BytesConvertible for RequestPayload {
@@ -124,6 +416,32 @@ pub struct Piece {
     pub block: Vec<u8>,
 }
 
+impl Piece {
+    pub fn new(index: usize, begin: usize, block: Vec<u8>) -> Self {
+        Self {
+            index: (index as u32).to_be_bytes(),
+            begin: (begin as u32).to_be_bytes(),
+            block,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        u32::from_be_bytes(self.index) as usize
+    }
+
+    pub fn begin(&self) -> usize {
+        u32::from_be_bytes(self.begin) as usize
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.block.len() + 8);
+        bytes.extend_from_slice(&self.index);
+        bytes.extend_from_slice(&self.begin);
+        bytes.extend(self.block);
+        bytes
+    }
+}
+
 impl TryFromBytes for Piece {
     fn try_from_bytes(mut bytes: Vec<u8>) -> Result<Self> {
         if bytes.len() < 8 {
@@ -140,17 +458,47 @@ impl TryFromBytes for Piece {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Bitfield(Vec<u8>);
 
 impl Bitfield {
+    pub fn new(pieces_count: usize) -> Self {
+        Self(vec![0; pieces_count.div_ceil(8)])
+    }
+
+    pub fn full(pieces_count: usize) -> Self {
+        let mut bitfield = Self::new(pieces_count);
+        (0..pieces_count).for_each(|index| bitfield.set_piece(index));
+        bitfield
+    }
+
     pub fn has_piece(&self, piece_index: usize) -> bool {
         let byte_index = piece_index / 8;
         let bit_index = piece_index % 8;
-        assert!(byte_index < self.0.len());
-        let byte = self.0[byte_index];
+        let Some(byte) = self.0.get(byte_index) else {
+            return false;
+        };
         (byte << bit_index) & 128 == 128
     }
+
+    pub fn set_piece(&mut self, piece_index: usize) {
+        let byte_index = piece_index / 8;
+        let bit_index = piece_index % 8;
+        if byte_index >= self.0.len() {
+            self.0.resize(byte_index + 1, 0);
+        }
+        self.0[byte_index] |= 128 >> bit_index;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|byte| *byte == 0)
+    }
+}
+
+impl BytesConvertible for Bitfield {
+    fn as_bytes(&self) -> &[u8] {
+        self.0.as_slice()
+    }
 }
 
 impl TryFromBytes for Bitfield {
@@ -159,8 +507,19 @@ impl TryFromBytes for Bitfield {
     }
 }
 
+/// Reserved byte and bit advertising a DHT node (BEP 5).
+const DHT: (usize, u8) = (7, 0x01);
+/// Reserved byte and bit advertising the Fast extension (BEP 6).
+const FAST_EXTENSION: (usize, u8) = (7, 0x04);
+/// Reserved byte and bit advertising v2 support (BEP 52).
+const V2: (usize, u8) = (7, 0x10);
+/// Reserved byte and bit advertising the extension protocol (BEP 10).
+const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+/// Number of pieces in an allowed fast set.
+pub const ALLOWED_FAST_SET_SIZE: usize = 10;
+
 impl Handshake {
-    pub fn new(info_hash: &InfoHash, peer_id: [u8; 20]) -> Self {
+    pub fn new(info_hash: &InfoHash, peer_id: [u8; PEER_ID_SIZE]) -> Self {
         Self {
             protocol_len: 19,
             protocol: *b"BitTorrent protocol",
@@ -170,6 +529,46 @@ impl Handshake {
         }
     }
 
+    pub fn info_hash(&self) -> InfoHash {
+        InfoHash(self.info_hash)
+    }
+
+    pub fn set_dht(&mut self) {
+        self.reserved[DHT.0] |= DHT.1;
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[DHT.0] & DHT.1 != 0
+    }
+
+    pub fn set_fast_extension(&mut self) {
+        self.reserved[FAST_EXTENSION.0] |= FAST_EXTENSION.1;
+    }
+
+    pub fn supports_fast_extension(&self) -> bool {
+        self.reserved[FAST_EXTENSION.0] & FAST_EXTENSION.1 != 0
+    }
+
+    pub fn set_v2(&mut self) {
+        self.reserved[V2.0] |= V2.1;
+    }
+
+    pub fn supports_v2(&self) -> bool {
+        self.reserved[V2.0] & V2.1 != 0
+    }
+
+    pub fn set_extension_protocol(&mut self) {
+        self.reserved[EXTENSION_PROTOCOL.0] |= EXTENSION_PROTOCOL.1;
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL.0] & EXTENSION_PROTOCOL.1 != 0
+    }
+
+    pub fn is_bittorrent(&self) -> bool {
+        self.protocol_len == 19 && &self.protocol == b"BitTorrent protocol"
+    }
+
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
         let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
         // Safety: Handshake is a POD with repr(c)
@@ -178,30 +577,61 @@ impl Handshake {
     }
 }
 
-pub async fn handshake(info_hash: &InfoHash, stream: &mut TcpStream) -> Result<String> {
-    let mut handshake = Handshake::new(info_hash, PEER_ID);
+/// Generates the canonical set of pieces a peer at `ip` may request while
+/// choked, as described in BEP 6.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &InfoHash, pieces_count: usize) -> Vec<usize> {
+    let size = ALLOWED_FAST_SET_SIZE.min(pieces_count);
+    let mut pieces = Vec::with_capacity(size);
+    let mut x = (u32::from(ip) & 0xFFFFFF00).to_be_bytes().to_vec();
+    x.extend_from_slice(&info_hash.0);
+    while pieces.len() < size {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if pieces.len() >= size {
+                break;
+            }
+            let y = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            let index = y as usize % pieces_count;
+            if !pieces.contains(&index) {
+                pieces.push(index);
+            }
+        }
+    }
+    pieces
+}
+
+/// Exchanges handshakes and returns the peer's id.
+pub async fn handshake(info_hash: &InfoHash, stream: &mut TcpStream) -> Result<[u8; PEER_ID_SIZE]> {
+    let mut handshake = Handshake::new(info_hash, peer_id::local());
     let bytes = handshake.as_bytes_mut();
 
     stream.write_all(bytes).await?;
     stream.read_exact(bytes).await?;
 
-    Ok(hex::encode(handshake.peer_id))
+    Ok(handshake.peer_id)
 }
 
 pub async fn download_peice(file: &TorrentFile, index: usize) -> Result<Vec<u8>> {
-    assert!(index < file.info.pieces.len());
+    if index >= file.info.pieces.len() {
+        return Err(Error::msg(format!(
+            "Piece {index} is out of range, the torrent has {} pieces",
+            file.info.pieces.len()
+        )));
+    }
 
     let info_hash = file.info.hash()?;
-    let peers =
-        tracker::discover_peers(file.announce.as_str(), &info_hash, file.info.length).await?;
+    let Some(announce) = &file.announce else {
+        return Err(Error::msg("Torrent has no tracker."));
+    };
+    let peers = tracker::discover_peers(announce, &info_hash, file.info.total_length()).await?;
     let Some(peer) = peers.first() else {
         return Err(Error::msg("Peers are empty."));
     };
     let mut stream = tokio::net::TcpStream::connect(&peer.0).await?;
     _ = handshake(&info_hash, &mut stream).await?;
 
-    let bitfield_mesasge = read_message::<Bitfield>(&mut stream).await?;
-    assert_eq!(bitfield_mesasge.message_type, MessageType::Bitfield);
+    let bitfield_mesasge = read_message::<Bitfield>(&mut stream, file.info.pieces.len()).await?;
+    expect_message_type(&bitfield_mesasge, MessageType::Bitfield)?;
 
     send_message(
         Message {
@@ -211,28 +641,48 @@ pub async fn download_peice(file: &TorrentFile, index: usize) -> Result<Vec<u8>>
         &mut stream,
     )
     .await?;
-    let unchoke_message = read_message::<EmptyPayload>(&mut stream).await?;
-    assert_eq!(unchoke_message.message_type, MessageType::Unchoke);
+    let unchoke_message = read_message::<EmptyPayload>(&mut stream, file.info.pieces.len()).await?;
+    expect_message_type(&unchoke_message, MessageType::Unchoke)?;
 
     let hash = &file.info.pieces[index];
     request_peice(
         index,
         file.info.piece_length,
         hash,
-        file.info.length,
+        file.info.total_length(),
         &mut stream,
     )
     .await
 }
 
-async fn read_message<P: TryFromBytes>(stream: &mut TcpStream) -> Result<Message<P>> {
+fn expect_message_type<P>(message: &Message<P>, expected: MessageType) -> Result<()> {
+    if message.message_type != expected {
+        return Err(Error::msg(format!(
+            "Expected a {expected:?} message, received {:?}",
+            message.message_type
+        )));
+    }
+    Ok(())
+}
+
+async fn read_message<P: TryFromBytes>(
+    stream: &mut TcpStream,
+    pieces_count: usize,
+) -> Result<Message<P>> {
     let mut header = [0u8; 4];
-    stream.read_exact(header.as_mut()).await?;
-    let length = u32::from_be_bytes(header) as usize;
+    let length = loop {
+        stream.read_exact(header.as_mut()).await?;
+        let length = u32::from_be_bytes(header) as usize;
+        // Zero length messages are keep-alives.
+        if length > 0 {
+            break length;
+        }
+    };
 
     let mut message_id = [0u8; 1];
     stream.read_exact(message_id.as_mut()).await?;
-    let message_type = MessageType::from(message_id[0]);
+    let message_type = MessageType::try_from(message_id[0])?;
+    message_type.check_payload_length(length - message_id.len(), pieces_count)?;
 
     let mut payload = vec![0; length - message_id.len()];
     if !payload.is_empty() {
@@ -287,13 +737,51 @@ async fn request_peice(
             stream,
         )
         .await?;
-        let chunk = read_message::<Piece>(stream).await?;
-        assert_eq!(chunk.message_type, MessageType::Piece);
-        assert_eq!(chunk.payload.block.len(), block_size);
+        let chunk = read_message::<Piece>(stream, file_length.div_ceil(size)).await?;
+        expect_message_type(&chunk, MessageType::Piece)?;
+        if chunk.payload.index() != piece_index
+            || chunk.payload.begin() != offset
+            || chunk.payload.block.len() != block_size
+        {
+            return Err(Error::msg(format!(
+                "Peer sent block {}+{} of piece {} instead of {offset}+{block_size} of piece {piece_index}",
+                chunk.payload.begin(),
+                chunk.payload.block.len(),
+                chunk.payload.index()
+            )));
+        }
 
         buffer.extend(chunk.payload.block.as_slice());
         offset += block_size;
     }
-    assert_eq!(hash, &PieceHash::from(buffer.as_slice()));
+    if hash != &PieceHash::from(buffer.as_slice()) {
+        return Err(Error::msg(format!(
+            "Piece {piece_index} does not match its hash"
+        )));
+    }
     Ok(buffer)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_allowed_fast_set() {
+        // Example from BEP 6, whose sets of 7 and 9 pieces are the start of
+        // our set of 10.
+        let info_hash = InfoHash([0xaa; 20]);
+        let pieces = allowed_fast_set(Ipv4Addr::new(80, 4, 4, 200), &info_hash, 1313);
+        assert_eq!(pieces[..7], [1059, 431, 808, 1217, 287, 376, 1188]);
+        assert_eq!(pieces[7..9], [353, 508]);
+        assert_eq!(pieces.len(), ALLOWED_FAST_SET_SIZE);
+
+        // Only the /24 network of the peer matters.
+        let other = allowed_fast_set(Ipv4Addr::new(80, 4, 4, 1), &info_hash, 1313);
+        assert_eq!(pieces, other);
+        assert_eq!(
+            allowed_fast_set(Ipv4Addr::LOCALHOST, &info_hash, 3).len(),
+            3
+        );
+    }
+}
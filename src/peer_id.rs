@@ -0,0 +1,197 @@
+use std::sync::OnceLock;
+
+use rand::{distributions::Alphanumeric, Rng};
+
+pub const PEER_ID_SIZE: usize = 20;
+/// Azureus-style prefix of our peer ids: client code and version.
+const CLIENT_PREFIX: &[u8; 8] = b"-CC0001-";
+const CLIENT_NAME: &str = "bittorrent-starter-rust";
+
+/// Clients identifying with `-XX1234-` style prefixes.
+const AZUREUS_CLIENTS: [(&str, &str); 22] = [
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("CC", CLIENT_NAME),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FG", "FlashGet"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("SD", "Thunder"),
+    ("ST", "SymTorrent"),
+    ("TR", "Transmission"),
+    ("TX", "Tixati"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+/// Clients identifying with a single letter followed by version digits.
+const SHADOW_CLIENTS: [(u8, &str); 7] = [
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow's client"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// Digits of Shadow-style versions, by value.
+const SHADOW_DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz.-";
+
+/// Our peer id, generated once per run so that separate runs look like
+/// separate peers to trackers.
+pub fn local() -> [u8; PEER_ID_SIZE] {
+    static PEER_ID: OnceLock<[u8; PEER_ID_SIZE]> = OnceLock::new();
+    *PEER_ID.get_or_init(|| {
+        let mut peer_id = [0; PEER_ID_SIZE];
+        peer_id[..CLIENT_PREFIX.len()].copy_from_slice(CLIENT_PREFIX);
+        let mut rng = rand::thread_rng();
+        for byte in &mut peer_id[CLIENT_PREFIX.len()..] {
+            *byte = rng.sample(Alphanumeric);
+        }
+        peer_id
+    })
+}
+
+/// Name and version of the client which generated a peer id, when it is a
+/// known client following the Azureus, Mainline or Shadow conventions.
+pub fn client_name(peer_id: &[u8; PEER_ID_SIZE]) -> Option<String> {
+    azureus_client(peer_id)
+        .or_else(|| mainline_client(peer_id))
+        .or_else(|| shadow_client(peer_id))
+}
+
+/// `-` followed by a two character client code, four version characters
+/// and another `-`.
+fn azureus_client(peer_id: &[u8; PEER_ID_SIZE]) -> Option<String> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let version = std::str::from_utf8(&peer_id[3..7]).ok()?;
+    if !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '~')
+        || !version.chars().all(|c| c.is_ascii_alphanumeric())
+    {
+        return None;
+    }
+    let (_, name) = AZUREUS_CLIENTS.iter().find(|(known, _)| *known == code)?;
+    let version = match code {
+        // Major version followed by a two digit minor version.
+        "TR" => format!("{}.{}", &version[..1], &version[1..3]),
+        _ => {
+            let mut digits = version
+                .chars()
+                .map(|c| c.to_digit(36).unwrap_or_default())
+                .collect::<Vec<_>>();
+            while digits.len() > 2 && digits.last() == Some(&0) {
+                digits.pop();
+            }
+            digits
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>()
+                .join(".")
+        }
+    };
+    Some(format!("{name} {version}"))
+}
+
+/// `M` followed by a dash separated version, such as `M7-2-2--` or
+/// `M4-20-8-`.
+fn mainline_client(peer_id: &[u8; PEER_ID_SIZE]) -> Option<String> {
+    if peer_id[0] != b'M' || peer_id[7] != b'-' {
+        return None;
+    }
+    let version = std::str::from_utf8(&peer_id[1..7]).ok()?;
+    let parts = version.trim_end_matches('-').split('-').collect::<Vec<_>>();
+    let valid = parts
+        .iter()
+        .all(|part| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit()));
+    if parts.len() != 3 || !valid {
+        return None;
+    }
+    Some(format!("Mainline {}", parts.join(".")))
+}
+
+/// A client letter followed by up to five version digits and at least one
+/// `-`.
+fn shadow_client(peer_id: &[u8; PEER_ID_SIZE]) -> Option<String> {
+    let (_, name) = SHADOW_CLIENTS
+        .iter()
+        .find(|(letter, _)| *letter == peer_id[0])?;
+    let version = &peer_id[1..7];
+    let length = version.iter().position(|byte| *byte == b'-')?;
+    if length == 0 || version[length..].iter().any(|byte| *byte != b'-') {
+        return None;
+    }
+    let version = version[..length]
+        .iter()
+        .map(|byte| {
+            let digit = SHADOW_DIGITS.iter().position(|digit| digit == byte)?;
+            Some(digit.to_string())
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(format!("{name} {}", version.join(".")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(prefix: &[u8]) -> Option<String> {
+        let mut peer_id = *b"12345678901234567890";
+        peer_id[..prefix.len()].copy_from_slice(prefix);
+        client_name(&peer_id)
+    }
+
+    #[test]
+    fn names_known_clients() {
+        assert_eq!(name(b"-qB4520-").as_deref(), Some("qBittorrent 4.5.2"));
+        assert_eq!(name(b"-TR2940-").as_deref(), Some("Transmission 2.94"));
+        assert_eq!(name(b"M7-2-2--").as_deref(), Some("Mainline 7.2.2"));
+        assert_eq!(name(b"M4-20-8-").as_deref(), Some("Mainline 4.20.8"));
+        assert_eq!(
+            name(b"S58B-----").as_deref(),
+            Some("Shadow's client 5.8.11")
+        );
+        assert_eq!(
+            name(&local()).as_deref(),
+            Some("bittorrent-starter-rust 0.0.0.1")
+        );
+    }
+
+    #[test]
+    fn ignores_unknown_ids() {
+        for prefix in [
+            b"-ZZ1000-".as_slice(),
+            b"-qB45 0-",
+            b"-qB4520",
+            b"M7-2-2-1",
+            b"M7--2---",
+            b"S-------",
+            b"X58B----",
+            &[0xff; 20],
+        ] {
+            assert_eq!(name(prefix), None, "{prefix:?}");
+        }
+    }
+
+    #[test]
+    fn local_id_is_stable_and_alphanumeric() {
+        let peer_id = local();
+        assert!(peer_id.starts_with(CLIENT_PREFIX));
+        assert!(peer_id[CLIENT_PREFIX.len()..]
+            .iter()
+            .all(u8::is_ascii_alphanumeric));
+        assert_eq!(local(), peer_id);
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Minimum time between two `ut_pex` messages to the same peer.
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Maximum number of added and dropped peers in a single message.
+pub const MAX_PEX_PEERS: usize = 50;
+
+pub const FLAG_SEED: u8 = 0x02;
+/// The peer accepted our outgoing connection, so it is reachable.
+pub const FLAG_CONNECTABLE: u8 = 0x10;
+
+const IPV4_PEER_SIZE: usize = 6;
+const IPV6_PEER_SIZE: usize = 18;
+
+/// `ut_pex` message (BEP 11) with peers in compact format.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PexMessage {
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+    added: Vec<u8>,
+    #[serde(
+        rename = "added.f",
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "serde_bytes"
+    )]
+    added_flags: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+    added6: Vec<u8>,
+    #[serde(
+        rename = "added6.f",
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "serde_bytes"
+    )]
+    added6_flags: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+    dropped: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty", with = "serde_bytes")]
+    dropped6: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PexPeer {
+    pub address: SocketAddr,
+    pub flags: u8,
+}
+
+impl PexMessage {
+    pub fn new(added: &[PexPeer], dropped: &[SocketAddr]) -> Self {
+        let mut message = Self::default();
+        for peer in added {
+            match peer.address {
+                SocketAddr::V4(address) => {
+                    message.added.extend(compact_v4(&address));
+                    message.added_flags.push(peer.flags);
+                }
+                SocketAddr::V6(address) => {
+                    message.added6.extend(compact_v6(&address));
+                    message.added6_flags.push(peer.flags);
+                }
+            }
+        }
+        for address in dropped {
+            match address {
+                SocketAddr::V4(address) => message.dropped.extend(compact_v4(address)),
+                SocketAddr::V6(address) => message.dropped6.extend(compact_v6(address)),
+            }
+        }
+        message
+    }
+
+    pub fn added(&self) -> Vec<PexPeer> {
+        let v4 = self
+            .added
+            .chunks_exact(IPV4_PEER_SIZE)
+            .map(parse_v4)
+            .zip(flags(&self.added_flags));
+        let v6 = self
+            .added6
+            .chunks_exact(IPV6_PEER_SIZE)
+            .map(parse_v6)
+            .zip(flags(&self.added6_flags));
+        v4.chain(v6)
+            .map(|(address, flags)| PexPeer { address, flags })
+            .collect()
+    }
+}
+
+/// Flags of every added peer, defaulting to none when the sender omitted them.
+fn flags(flags: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    flags.iter().copied().chain(std::iter::repeat(0))
+}
+
+fn compact_v4(address: &SocketAddrV4) -> [u8; IPV4_PEER_SIZE] {
+    let mut bytes = [0; IPV4_PEER_SIZE];
+    bytes[..4].copy_from_slice(&address.ip().octets());
+    bytes[4..].copy_from_slice(&address.port().to_be_bytes());
+    bytes
+}
+
+fn compact_v6(address: &SocketAddrV6) -> [u8; IPV6_PEER_SIZE] {
+    let mut bytes = [0; IPV6_PEER_SIZE];
+    bytes[..16].copy_from_slice(&address.ip().octets());
+    bytes[16..].copy_from_slice(&address.port().to_be_bytes());
+    bytes
+}
+
+fn parse_v4(chunk: &[u8]) -> SocketAddr {
+    let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+    let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+    SocketAddr::V4(SocketAddrV4::new(ip, port))
+}
+
+fn parse_v6(chunk: &[u8]) -> SocketAddr {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(&chunk[..16]);
+    let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+    SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
+}
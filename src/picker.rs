@@ -0,0 +1,161 @@
+use rand::seq::SliceRandom;
+
+use crate::peer::Bitfield;
+
+/// Pieces are picked at random until this many are complete, so that we have
+/// something to trade with peers as soon as possible.
+const RANDOM_FIRST_PIECES: usize = 4;
+
+/// Chooses which piece to download next, preferring the pieces fewest
+/// connected peers have.
+pub struct PiecePicker {
+    /// Number of connected peers having each piece.
+    availability: Vec<usize>,
+    /// Pieces which are neither downloaded nor in flight.
+    wanted: Vec<bool>,
+}
+
+impl PiecePicker {
+    pub fn new(pieces_count: usize) -> Self {
+        Self {
+            availability: vec![0; pieces_count],
+            wanted: vec![true; pieces_count],
+        }
+    }
+
+    pub fn add_bitfield(&mut self, bitfield: &Bitfield) {
+        for (index, count) in self.availability.iter_mut().enumerate() {
+            if bitfield.has_piece(index) {
+                *count += 1;
+            }
+        }
+    }
+
+    pub fn remove_bitfield(&mut self, bitfield: &Bitfield) {
+        for (index, count) in self.availability.iter_mut().enumerate() {
+            if bitfield.has_piece(index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    pub fn add_piece(&mut self, index: usize) {
+        if let Some(count) = self.availability.get_mut(index) {
+            *count += 1;
+        }
+    }
+
+    /// Picks a wanted piece the peer has and marks it as in flight. Pieces
+    /// suggested by the peer are taken before any other.
+    pub fn pick(
+        &mut self,
+        bitfield: &Bitfield,
+        completed: usize,
+        suggested: &[usize],
+    ) -> Option<usize> {
+        let suggestion = suggested.iter().find(|index| {
+            self.wanted.get(**index).copied().unwrap_or(false) && bitfield.has_piece(**index)
+        });
+        if let Some(index) = suggestion {
+            self.wanted[*index] = false;
+            return Some(*index);
+        }
+        let candidates = (0..self.wanted.len())
+            .filter(|index| self.wanted[*index] && bitfield.has_piece(*index))
+            .collect::<Vec<_>>();
+        let mut rng = rand::thread_rng();
+        let index = if completed < RANDOM_FIRST_PIECES {
+            *candidates.choose(&mut rng)?
+        } else {
+            let rarest = candidates
+                .iter()
+                .map(|index| self.availability[*index])
+                .min()?;
+            let rarest = candidates
+                .into_iter()
+                .filter(|index| self.availability[*index] == rarest)
+                .collect::<Vec<_>>();
+            *rarest.choose(&mut rng)?
+        };
+        self.wanted[index] = false;
+        Some(index)
+    }
+
+    /// Makes a piece available for picking again, e.g. after it failed the
+    /// hash check.
+    pub fn unpick(&mut self, index: usize) {
+        self.wanted[index] = true;
+    }
+
+    /// Marks a piece which was verified without being picked.
+    pub fn remove(&mut self, index: usize) {
+        self.wanted[index] = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIECES: usize = 16;
+
+    /// Pieces 0 to 3 are rare, the peer lacks piece 15.
+    fn picker() -> (PiecePicker, Bitfield) {
+        let mut picker = PiecePicker::new(PIECES);
+        let mut bitfield = Bitfield::new(PIECES);
+        for index in 0..PIECES - 1 {
+            bitfield.set_piece(index);
+        }
+        for _ in 0..3 {
+            picker.add_bitfield(&bitfield);
+        }
+        for index in 0..4 {
+            picker.availability[index] = 1;
+        }
+        (picker, bitfield)
+    }
+
+    #[test]
+    fn picks_random_pieces_first() {
+        let mut picked = std::collections::HashSet::new();
+        for _ in 0..100 {
+            let (mut picker, bitfield) = picker();
+            for completed in 0..RANDOM_FIRST_PIECES {
+                let index = picker.pick(&bitfield, completed, &[]).unwrap();
+                assert!(bitfield.has_piece(index));
+                picked.insert(index);
+            }
+        }
+        // Not just the rare pieces, and never one the peer lacks.
+        assert!(picked.iter().any(|index| *index >= 4));
+        assert!(!picked.contains(&(PIECES - 1)));
+    }
+
+    #[test]
+    fn picks_rarest_pieces_after_the_first() {
+        let (mut picker, bitfield) = picker();
+        let mut rare = (0..4)
+            .map(|_| picker.pick(&bitfield, RANDOM_FIRST_PIECES, &[]).unwrap())
+            .collect::<Vec<_>>();
+        rare.sort();
+        assert_eq!(rare, [0, 1, 2, 3]);
+        let index = picker.pick(&bitfield, RANDOM_FIRST_PIECES, &[]).unwrap();
+        assert!((4..PIECES - 1).contains(&index));
+
+        // A failed piece can be picked again.
+        picker.unpick(2);
+        assert_eq!(picker.pick(&bitfield, RANDOM_FIRST_PIECES, &[]), Some(2));
+    }
+
+    #[test]
+    fn picks_suggested_pieces_first() {
+        let (mut picker, bitfield) = picker();
+        picker.remove(9);
+        // Suggestions the peer lacks or we do not want are skipped.
+        let suggested = [PIECES - 1, 9, 12, 10];
+        assert_eq!(picker.pick(&bitfield, 0, &suggested), Some(12));
+        assert_eq!(picker.pick(&bitfield, 0, &suggested), Some(10));
+        let index = picker.pick(&bitfield, RANDOM_FIRST_PIECES, &suggested);
+        assert!(index.is_some_and(|index| index < 4));
+    }
+}
@@ -0,0 +1,318 @@
+use std::{
+    cmp::Ordering,
+    net::{Ipv4Addr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use rand::{Rng, RngCore};
+
+pub const NODE_ID_SIZE: usize = 20;
+/// Maximum number of nodes in a bucket.
+pub const K: usize = 8;
+/// Nodes which have not been heard from for this long are pinged before
+/// they are trusted again.
+const QUESTIONABLE_AGE: Duration = Duration::from_secs(15 * 60);
+/// Nodes failing to respond this many times in a row may be replaced.
+const MAX_FAILURES: usize = 2;
+/// Bits of an IPv4 address which go into a secure node id (BEP 42).
+const IPV4_MASK: u32 = 0x030f3fff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(pub [u8; NODE_ID_SIZE]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        let mut id = [0; NODE_ID_SIZE];
+        rand::thread_rng().fill_bytes(&mut id);
+        Self(id)
+    }
+
+    /// Random id whose first 21 bits are derived from our external address,
+    /// so that nodes cannot choose where they are placed in the DHT (BEP 42).
+    pub fn secure(ip: Ipv4Addr) -> Self {
+        let mut id = Self::random();
+        let crc = secure_prefix(ip, id.0[NODE_ID_SIZE - 1]);
+        id.0[0] = (crc >> 24) as u8;
+        id.0[1] = (crc >> 16) as u8;
+        id.0[2] = ((crc >> 8) as u8 & 0xf8) | (rand::thread_rng().gen::<u8>() & 0x07);
+        id
+    }
+
+    /// Whether a node at the address may use this id. Nodes on local
+    /// networks are exempt.
+    pub fn is_valid_for(&self, ip: &Ipv4Addr) -> bool {
+        if ip.is_private() || ip.is_loopback() || ip.is_link_local() {
+            return true;
+        }
+        let crc = secure_prefix(*ip, self.0[NODE_ID_SIZE - 1]);
+        self.0[0] == (crc >> 24) as u8
+            && self.0[1] == (crc >> 16) as u8
+            && self.0[2] & 0xf8 == (crc >> 8) as u8 & 0xf8
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    pub fn distance(&self, other: &NodeId) -> [u8; NODE_ID_SIZE] {
+        let mut distance = [0; NODE_ID_SIZE];
+        for (index, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[index] ^ other.0[index];
+        }
+        distance
+    }
+
+    /// Orders two nodes by how close they are to this id.
+    pub fn cmp_distance(&self, lhs: &NodeId, rhs: &NodeId) -> Ordering {
+        self.distance(lhs).cmp(&self.distance(rhs))
+    }
+
+    /// Number of leading bits this id shares with the other one.
+    fn common_prefix(&self, other: &NodeId) -> usize {
+        let distance = self.distance(other);
+        let zero_bytes = distance.iter().take_while(|byte| **byte == 0).count();
+        match distance.get(zero_bytes) {
+            Some(byte) => zero_bytes * 8 + byte.leading_zeros() as usize,
+            None => NODE_ID_SIZE * 8,
+        }
+    }
+}
+
+/// CRC32-C of the masked address combined with the low bits of the id's
+/// last byte.
+fn secure_prefix(ip: Ipv4Addr, random: u8) -> u32 {
+    let ip = (u32::from(ip) & IPV4_MASK) | ((random as u32 & 0x07) << 29);
+    crc32c(&ip.to_be_bytes())
+}
+
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: NodeId,
+    pub address: SocketAddrV4,
+    last_seen: Instant,
+    failures: usize,
+}
+
+impl Node {
+    pub fn new(id: NodeId, address: SocketAddrV4) -> Self {
+        Self {
+            id,
+            address,
+            last_seen: Instant::now(),
+            failures: 0,
+        }
+    }
+
+    fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+/// Kademlia routing table (BEP 5). Bucket `n` holds the nodes sharing
+/// exactly `n` leading bits with our own id.
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); NODE_ID_SIZE * 8],
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// Changes our own id, moving every node to its new bucket.
+    pub fn set_id(&mut self, id: NodeId) {
+        let nodes = self.nodes();
+        *self = Self::new(id);
+        for node in nodes {
+            self.insert(node.id, node.address);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    /// Records that a node responded. New nodes are added when their bucket
+    /// has room or holds a node which stopped responding. Nodes whose id does
+    /// not match their address are ignored.
+    pub fn insert(&mut self, id: NodeId, address: SocketAddrV4) {
+        if id == self.id || !id.is_valid_for(address.ip()) {
+            return;
+        }
+        let bucket = &mut self.buckets[self.id.common_prefix(&id)];
+        if let Some(node) = bucket.iter_mut().find(|node| node.id == id) {
+            node.address = address;
+            node.last_seen = Instant::now();
+            node.failures = 0;
+            return;
+        }
+        if bucket.len() >= K {
+            let Some(position) = bucket.iter().position(Node::is_bad) else {
+                return;
+            };
+            bucket.remove(position);
+        }
+        bucket.push(Node::new(id, address));
+    }
+
+    /// Records that a node did not respond to a query.
+    pub fn fail(&mut self, address: &SocketAddrV4) {
+        for node in self.buckets.iter_mut().flatten() {
+            if &node.address == address {
+                node.failures += 1;
+            }
+        }
+    }
+
+    /// Nodes closest to the target, excluding the ones which stopped
+    /// responding.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|node| !node.is_bad())
+            .cloned()
+            .collect::<Vec<_>>();
+        nodes.sort_by(|lhs, rhs| target.cmp_distance(&lhs.id, &rhs.id));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Nodes which should be pinged to find out whether they are still alive.
+    pub fn questionable(&self) -> Vec<Node> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|node| node.last_seen.elapsed() >= QUESTIONABLE_AGE)
+            .cloned()
+            .collect()
+    }
+
+    pub fn nodes(&self) -> Vec<Node> {
+        self.buckets.iter().flatten().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Id sharing exactly `prefix` leading bits with the all-zero id.
+    fn id_with_prefix(prefix: usize, last: u8) -> NodeId {
+        let mut id = [0; NODE_ID_SIZE];
+        id[prefix / 8] = 0x80 >> (prefix % 8);
+        id[NODE_ID_SIZE - 1] |= last;
+        NodeId(id)
+    }
+
+    fn address(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)
+    }
+
+    #[test]
+    fn nodes_go_to_bucket_of_common_prefix() {
+        let mut table = RoutingTable::new(NodeId([0; NODE_ID_SIZE]));
+        table.insert(id_with_prefix(0, 1), address(1));
+        table.insert(id_with_prefix(9, 1), address(2));
+        table.insert(id_with_prefix(159, 0), address(3));
+        assert_eq!(table.buckets[0].len(), 1);
+        assert_eq!(table.buckets[9].len(), 1);
+        assert_eq!(table.buckets[159].len(), 1);
+        assert_eq!(table.len(), 3);
+        // Our own id is never added.
+        table.insert(table.id(), address(4));
+        assert_eq!(table.len(), 3);
+    }
+
+    #[test]
+    fn full_bucket_only_replaces_bad_nodes() {
+        let mut table = RoutingTable::new(NodeId([0; NODE_ID_SIZE]));
+        for index in 0..K {
+            table.insert(id_with_prefix(0, index as u8), address(index as u16));
+        }
+        table.insert(id_with_prefix(0, K as u8), address(K as u16));
+        assert_eq!(table.buckets[0].len(), K);
+        assert!(table.buckets[0]
+            .iter()
+            .all(|node| node.id != id_with_prefix(0, K as u8)));
+
+        for _ in 0..MAX_FAILURES {
+            table.fail(&address(0));
+        }
+        table.insert(id_with_prefix(0, K as u8), address(K as u16));
+        assert_eq!(table.buckets[0].len(), K);
+        assert!(table.buckets[0]
+            .iter()
+            .any(|node| node.id == id_with_prefix(0, K as u8)));
+        assert!(table.buckets[0]
+            .iter()
+            .all(|node| node.address != address(0)));
+    }
+
+    #[test]
+    fn closest_orders_by_distance() {
+        let mut table = RoutingTable::new(NodeId([0; NODE_ID_SIZE]));
+        for prefix in [3, 1, 2] {
+            table.insert(id_with_prefix(prefix, 0), address(prefix as u16));
+        }
+        let target = id_with_prefix(2, 0);
+        let closest = table.closest(&target, 2);
+        assert_eq!(closest[0].id, target);
+        assert_eq!(closest[1].id, id_with_prefix(3, 0));
+    }
+
+    #[test]
+    fn secure_ids_match_their_address() {
+        let ip = Ipv4Addr::new(124, 31, 75, 21);
+        assert!(NodeId::secure(ip).is_valid_for(&ip));
+        assert!(!NodeId::secure(ip).is_valid_for(&Ipv4Addr::new(21, 75, 31, 124)));
+    }
+
+    #[test]
+    fn matches_bep_42_vectors() {
+        for (ip, random, prefix) in [
+            ([124, 31, 75, 21], 1, [0x5f, 0xbf, 0xbf]),
+            ([21, 75, 31, 124], 86, [0x5a, 0x3c, 0xe9]),
+            ([65, 23, 51, 170], 22, [0xa5, 0xd4, 0x32]),
+            ([84, 124, 73, 14], 65, [0x1b, 0x03, 0x21]),
+            ([43, 213, 53, 83], 90, [0xe5, 0x6f, 0x6c]),
+        ] {
+            let ip = Ipv4Addr::from(ip);
+            let crc = secure_prefix(ip, random);
+            assert_eq!(crc >> 24, prefix[0] as u32, "{ip}");
+            assert_eq!(crc >> 16 & 0xff, prefix[1] as u32, "{ip}");
+            assert_eq!(crc >> 8 & 0xf8, prefix[2] as u32 & 0xf8, "{ip}");
+
+            let mut id = [0; NODE_ID_SIZE];
+            id[..3].copy_from_slice(&prefix);
+            id[NODE_ID_SIZE - 1] = random;
+            assert!(NodeId(id).is_valid_for(&ip), "{ip}");
+            id[NODE_ID_SIZE - 1] = random ^ 0x04;
+            assert!(!NodeId(id).is_valid_for(&ip), "{ip}");
+        }
+    }
+}
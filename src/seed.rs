@@ -0,0 +1,112 @@
+use std::{path::Path, sync::Arc, time::Duration};
+
+use anyhow::{Error, Result};
+
+use crate::{
+    choker,
+    dht::{self, Dht},
+    listener::Listener,
+    lsd::Lsd,
+    merkle::MerkleTrees,
+    storage::Storage,
+    swarm::Swarm,
+    torrent_file::TorrentFile,
+    tracker::{self, Announce, Event},
+};
+
+/// Re-announce interval used when the tracker does not provide one.
+const DEFAULT_ANNOUNCE_INTERVAL: u64 = 30 * 60;
+
+/// Verifies the data at `data_path` and serves it to peers until the process
+/// is interrupted.
+pub async fn seed_file(
+    file: TorrentFile,
+    data_path: &Path,
+    port: u16,
+    max_inbound_connections: usize,
+    dht: Option<Arc<Dht>>,
+) -> Result<()> {
+    let merkle = MerkleTrees::new(&file)?;
+    let storage = Storage::open_existing(data_path, &file.info)?;
+    let mut swarm = Swarm::new(file.info, storage)?;
+    swarm.port = port;
+    swarm.dht = dht.clone();
+    swarm.merkle = merkle;
+    let swarm = Arc::new(swarm);
+    let valid = swarm.verify()?;
+    if valid != swarm.pieces_count() {
+        return Err(Error::msg(format!(
+            "Only {valid} of {} pieces are valid in {data_path:?}",
+            swarm.pieces_count()
+        )));
+    }
+    swarm.verify_files()?;
+    swarm.set_seeding(true);
+    choker::spawn(swarm.clone());
+
+    let listener = Listener::bind(port, max_inbound_connections)?;
+    listener.add_torrent(swarm.clone());
+    listener.spawn();
+    match Lsd::bind() {
+        Ok(lsd) => {
+            lsd.add_torrent(swarm.clone());
+            lsd.spawn();
+        }
+        Err(error) => eprintln!("Failed to start local service discovery: {error:?}"),
+    }
+
+    // The started event is sent with every announce until one succeeds, so
+    // an unreachable tracker does not stop us from serving other peers.
+    let mut event = Some(Event::Started);
+    let mut interval = DEFAULT_ANNOUNCE_INTERVAL;
+    if let Some(url) = &file.announce {
+        announce(url, &swarm, port, &mut event, &mut interval).await;
+    }
+    if let Some(dht) = dht {
+        dht.find_peers(&swarm.info_hash, Some(port), true);
+        dht::spawn_announcer(dht, swarm.clone());
+    }
+    println!("Seeding {} on port {port}", hex::encode(swarm.info_hash.0));
+
+    loop {
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                result?;
+                break;
+            }
+            _ = tokio::time::sleep(Duration::from_secs(interval)) => {
+                let Some(url) = &file.announce else {
+                    continue;
+                };
+                announce(url, &swarm, port, &mut event, &mut interval).await;
+            }
+        }
+    }
+
+    if let Some(announce) = &file.announce {
+        tracker::announce(
+            announce,
+            &Announce::from_swarm(&swarm, port, Some(Event::Stopped)),
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+/// Announces to the tracker, keeping `event` for the next announce if this
+/// one fails.
+async fn announce(
+    url: &str,
+    swarm: &Swarm,
+    port: u16,
+    event: &mut Option<Event>,
+    interval: &mut u64,
+) {
+    match tracker::announce(url, &Announce::from_swarm(swarm, port, *event)).await {
+        Ok(response) => {
+            *event = None;
+            *interval = response.interval.unwrap_or(*interval);
+        }
+        Err(error) => eprintln!("Failed to announce to tracker: {error:?}"),
+    }
+}
@@ -0,0 +1,730 @@
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, SocketAddrV4, TcpStream},
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Error, Result};
+use bytes::Buf;
+
+use crate::{
+    extension::{self, ExtendedHandshake, ExtendedPayload},
+    peer::{
+        allowed_fast_set, Bitfield, BytesConvertible, EmptyPayload, Handshake, HashRequestPayload,
+        HashesPayload, HavePayload, Message, MessageType, Piece, PortPayload, RequestPayload,
+        TryFromBytes, MAX_PAYLOAD_LENGTH,
+    },
+    peer_id,
+    pex::{PexMessage, MAX_PEX_PEERS, PEX_INTERVAL},
+    swarm::Swarm,
+};
+
+/// Number of block requests kept in flight to a single peer.
+const PIPELINE_SIZE: usize = 5;
+/// Requests bigger than this are considered abusive and close the connection.
+/// Served blocks must fit in a piece message after its index and begin, under
+/// the same payload limit we apply to peers.
+const MAX_REQUEST_SIZE: usize = MAX_PAYLOAD_LENGTH - 8;
+const TICK: Duration = Duration::from_secs(1);
+/// How long a session with no interest on either side is kept open.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection to a single peer which both downloads missing pieces and
+/// serves the pieces we already have.
+pub struct Session {
+    swarm: Arc<Swarm>,
+    /// Id of the session within the swarm.
+    id: usize,
+    stream: TcpStream,
+    address: SocketAddr,
+    messages: Receiver<Result<Message<Vec<u8>>>>,
+    peer_bitfield: Bitfield,
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+    /// Both sides support the Fast extension (BEP 6).
+    fast: bool,
+    /// Pieces the peer may request from us while choked.
+    allowed_fast: Vec<usize>,
+    /// Pieces we may request from the peer while it chokes us.
+    peer_allowed_fast: Vec<usize>,
+    /// Pieces the peer suggested we download.
+    suggested: Vec<usize>,
+    /// The peer runs a DHT node (BEP 5).
+    dht: bool,
+    /// The peer supports the extension protocol (BEP 10).
+    extensions: bool,
+    /// Both sides know the v2 hashes of the torrent and can exchange
+    /// block hashes (BEP 52).
+    v2: bool,
+    /// Id the peer expects `ut_pex` messages with.
+    pex_id: Option<u8>,
+    /// Peers the peer already knows about from our `ut_pex` messages.
+    pex_sent: Vec<SocketAddr>,
+    last_pex: Option<Instant>,
+    /// Number of completed swarm pieces already announced to the peer.
+    announced: usize,
+    uploads: VecDeque<RequestPayload>,
+    /// Block requests sent to the peer and not answered yet.
+    requests: Vec<RequestPayload>,
+    /// Block hash requests sent to the peer and not answered yet.
+    hash_requests: Vec<HashRequestPayload>,
+    last_message: Instant,
+}
+
+impl Session {
+    /// Starts a session over a stream which has already exchanged handshakes.
+    /// `handshake` is the one received from the peer, `outgoing` tells
+    /// whether we initiated the connection.
+    pub fn new(
+        swarm: Arc<Swarm>,
+        stream: TcpStream,
+        handshake: &Handshake,
+        outgoing: bool,
+    ) -> Result<Self> {
+        let address = stream.peer_addr()?;
+        let mut reader = stream.try_clone()?;
+        let (sender, messages) = mpsc::channel();
+        let pieces_count = swarm.pieces_count();
+        std::thread::spawn(move || loop {
+            let message = read_message::<Vec<u8>>(&mut reader, pieces_count);
+            let failed = message.is_err();
+            if sender.send(message).is_err() || failed {
+                return;
+            }
+        });
+        let id = swarm.connect_session(address, outgoing);
+        let v2 = handshake.supports_v2() && swarm.merkle.is_some();
+        Ok(Self {
+            swarm,
+            id,
+            stream,
+            address,
+            messages,
+            peer_bitfield: Bitfield::new(pieces_count),
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            fast: handshake.supports_fast_extension(),
+            allowed_fast: Vec::new(),
+            peer_allowed_fast: Vec::new(),
+            suggested: Vec::new(),
+            dht: handshake.supports_dht(),
+            extensions: handshake.supports_extension_protocol(),
+            v2,
+            pex_id: None,
+            pex_sent: Vec::new(),
+            last_pex: None,
+            announced: 0,
+            uploads: VecDeque::new(),
+            requests: Vec::new(),
+            hash_requests: Vec::new(),
+            last_message: Instant::now(),
+        })
+    }
+
+    pub fn run(mut self) -> Result<()> {
+        let result = self.run_loop();
+        self.swarm.release_requests(self.id, &self.requests);
+        self.swarm.release_hash_requests(&self.hash_requests);
+        self.swarm.disconnect_session(self.id);
+        self.swarm.remove_peer_bitfield(&self.peer_bitfield);
+        _ = self.stream.shutdown(Shutdown::Both);
+        result
+    }
+
+    fn run_loop(&mut self) -> Result<()> {
+        let bitfield = self.swarm.bitfield();
+        self.announced = self.swarm.completed_since(0).len();
+        if self.fast {
+            if self.announced == self.swarm.pieces_count() {
+                self.send(MessageType::HaveAll, EmptyPayload)?;
+            } else if bitfield.is_empty() {
+                self.send(MessageType::HaveNone, EmptyPayload)?;
+            } else {
+                self.send(MessageType::Bitfield, bitfield)?;
+            }
+            self.send_allowed_fast()?;
+        } else if !bitfield.is_empty() {
+            self.send(MessageType::Bitfield, bitfield)?;
+        }
+        if let Some(dht) = self.swarm.dht.clone().filter(|_| self.dht) {
+            self.send(MessageType::Port, PortPayload::new(dht.port()?))?;
+        }
+        if self.extensions {
+            let handshake = ExtendedHandshake::new(self.swarm.port, !self.swarm.private);
+            let payload = ExtendedPayload::new(extension::HANDSHAKE_ID, &handshake)?;
+            self.send(MessageType::Extended, payload.into_bytes())?;
+        }
+        loop {
+            if self.swarm.is_banned(self.address.ip()) {
+                return Err(Error::msg("Peer is banned after sending corrupt pieces"));
+            }
+            self.announce_pieces()?;
+            self.exchange_peers()?;
+            self.update_interest()?;
+            self.update_choking()?;
+            self.cancel_requests()?;
+            self.request_blocks()?;
+            self.serve_request()?;
+            if self.is_finished() {
+                return Ok(());
+            }
+            let timeout = if self.uploads.is_empty() {
+                TICK
+            } else {
+                Duration::ZERO
+            };
+            match self.messages.recv_timeout(timeout) {
+                Ok(message) => {
+                    self.last_message = Instant::now();
+                    self.handle_message(message?)?
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::msg("Peer connection closed"))
+                }
+            }
+        }
+    }
+
+    fn is_finished(&self) -> bool {
+        if self.swarm.is_complete() && !self.swarm.is_seeding() {
+            return true;
+        }
+        !self.am_interested
+            && !self.peer_interested
+            && self.requests.is_empty()
+            && self.last_message.elapsed() >= IDLE_TIMEOUT
+    }
+
+    fn handle_message(&mut self, message: Message<Vec<u8>>) -> Result<()> {
+        match message.message_type {
+            MessageType::Choke => {
+                self.peer_choking = true;
+                // With the Fast extension dropped requests are rejected
+                // explicitly.
+                if !self.fast {
+                    self.swarm.release_requests(self.id, &self.requests);
+                    self.requests.clear();
+                }
+            }
+            MessageType::Unchoke => self.peer_choking = false,
+            MessageType::Interested => {
+                self.peer_interested = true;
+                self.swarm.choker.set_interested(self.id, true);
+            }
+            MessageType::NotInterested => {
+                self.peer_interested = false;
+                self.swarm.choker.set_interested(self.id, false);
+                self.reject_uploads(|_| true)?;
+            }
+            MessageType::Have => {
+                let have = message.parse_payload::<HavePayload>()?;
+                if have.index() < self.swarm.pieces_count()
+                    && !self.peer_bitfield.has_piece(have.index())
+                {
+                    self.peer_bitfield.set_piece(have.index());
+                    self.swarm.add_peer_piece(have.index());
+                    self.check_peer_seed();
+                }
+            }
+            MessageType::Bitfield => self.set_peer_bitfield(message.parse_payload()?),
+            MessageType::HaveAll => {
+                self.set_peer_bitfield(Bitfield::full(self.swarm.pieces_count()))
+            }
+            MessageType::HaveNone => {
+                self.set_peer_bitfield(Bitfield::new(self.swarm.pieces_count()))
+            }
+            MessageType::Request => {
+                let request = message.parse_payload::<RequestPayload>()?;
+                if request.length() > MAX_REQUEST_SIZE {
+                    return Err(Error::msg(format!(
+                        "Peer requested {} bytes block",
+                        request.length()
+                    )));
+                }
+                let allowed = !self.am_choking || self.allowed_fast.contains(&request.index());
+                if !allowed || !self.swarm.has_piece(request.index()) {
+                    if self.fast {
+                        self.send(MessageType::RejectRequest, request)?;
+                    }
+                } else if !self.uploads.contains(&request) {
+                    self.uploads.push_back(request);
+                }
+            }
+            MessageType::Piece => self.receive_block(message.parse_payload()?)?,
+            MessageType::Cancel => {
+                let request = message.parse_payload::<RequestPayload>()?;
+                self.reject_uploads(|upload| upload == &request)?;
+            }
+            MessageType::SuggestPiece => {
+                let suggestion = message.parse_payload::<HavePayload>()?;
+                if !self.suggested.contains(&suggestion.index()) {
+                    self.suggested.push(suggestion.index());
+                }
+            }
+            MessageType::RejectRequest => {
+                let rejected = message.parse_payload::<RequestPayload>()?;
+                if let Some(position) = self.requests.iter().position(|r| r == &rejected) {
+                    let request = self.requests.remove(position);
+                    self.swarm.release_requests(self.id, &[request]);
+                }
+            }
+            MessageType::Port => {
+                let port = message.parse_payload::<PortPayload>()?.port();
+                if let (Some(dht), IpAddr::V4(ip)) = (self.swarm.dht.clone(), self.address.ip()) {
+                    std::thread::spawn(move || dht.add_node(SocketAddrV4::new(ip, port)));
+                }
+            }
+            MessageType::Extended => self.handle_extended(message.parse_payload()?)?,
+            MessageType::AllowedFast => {
+                let allowed = message.parse_payload::<HavePayload>()?;
+                if !self.peer_allowed_fast.contains(&allowed.index()) {
+                    self.peer_allowed_fast.push(allowed.index());
+                }
+            }
+            MessageType::HashRequest => {
+                let request = message.parse_payload::<HashRequestPayload>()?;
+                match self.swarm.serve_hashes(&request) {
+                    Some(hashes) => {
+                        self.send(MessageType::Hashes, HashesPayload::new(&request, &hashes))?
+                    }
+                    None => self.send(MessageType::HashReject, request)?,
+                }
+            }
+            MessageType::Hashes => {
+                let hashes = message.parse_payload::<HashesPayload>()?;
+                let request = hashes.request();
+                if let Some(position) = self.hash_requests.iter().position(|r| r == &request) {
+                    self.hash_requests.remove(position);
+                    self.swarm.receive_hashes(hashes)?;
+                }
+            }
+            MessageType::HashReject => {
+                let rejected = message.parse_payload::<HashRequestPayload>()?;
+                if let Some(position) = self.hash_requests.iter().position(|r| r == &rejected) {
+                    let request = self.hash_requests.remove(position);
+                    self.swarm.release_hash_requests(&[request]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_extended(&mut self, message: ExtendedPayload) -> Result<()> {
+        match message.id {
+            extension::HANDSHAKE_ID => {
+                let handshake = message.parse::<ExtendedHandshake>()?;
+                if !self.swarm.private {
+                    self.pex_id = handshake.extension_id(extension::UT_PEX);
+                }
+                if let Some(port) = handshake.p.filter(|port| *port != 0) {
+                    self.swarm.set_listen_port(self.id, self.address.ip(), port);
+                }
+            }
+            extension::UT_PEX_ID if !self.swarm.private => {
+                let pex = message.parse::<PexMessage>()?;
+                self.swarm
+                    .add_candidates(pex.added().into_iter().map(|peer| peer.address));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Tells the peer about peers which connected or disconnected since the
+    /// previous `ut_pex` message.
+    fn exchange_peers(&mut self) -> Result<()> {
+        let Some(pex_id) = self.pex_id else {
+            return Ok(());
+        };
+        if self
+            .last_pex
+            .is_some_and(|last_pex| last_pex.elapsed() < PEX_INTERVAL)
+        {
+            return Ok(());
+        }
+        self.last_pex = Some(Instant::now());
+        let peers = self.swarm.pex_peers(self.id);
+        let added = peers
+            .iter()
+            .filter(|peer| !self.pex_sent.contains(&peer.address))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect::<Vec<_>>();
+        let dropped = self
+            .pex_sent
+            .iter()
+            .filter(|address| !peers.iter().any(|peer| peer.address == **address))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect::<Vec<_>>();
+        if added.is_empty() && dropped.is_empty() {
+            return Ok(());
+        }
+        self.pex_sent.retain(|address| !dropped.contains(address));
+        self.pex_sent.extend(added.iter().map(|peer| peer.address));
+        let payload = ExtendedPayload::new(pex_id, &PexMessage::new(&added, &dropped))?;
+        self.send(MessageType::Extended, payload.into_bytes())
+    }
+
+    fn announce_pieces(&mut self) -> Result<()> {
+        for index in self.swarm.completed_since(self.announced) {
+            self.announced += 1;
+            if !self.peer_bitfield.has_piece(index) {
+                self.send(MessageType::Have, HavePayload::new(index))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn update_interest(&mut self) -> Result<()> {
+        let interested =
+            !self.requests.is_empty() || self.swarm.is_interesting(&self.peer_bitfield);
+        if interested != self.am_interested {
+            self.am_interested = interested;
+            let message_type = if interested {
+                MessageType::Interested
+            } else {
+                MessageType::NotInterested
+            };
+            self.send(message_type, EmptyPayload)?;
+        }
+        Ok(())
+    }
+
+    fn update_choking(&mut self) -> Result<()> {
+        let choking = !self.swarm.choker.is_unchoked(self.id);
+        if choking != self.am_choking {
+            self.am_choking = choking;
+            if choking {
+                self.send(MessageType::Choke, EmptyPayload)?;
+                let allowed_fast = std::mem::take(&mut self.allowed_fast);
+                self.reject_uploads(|upload| !allowed_fast.contains(&upload.index()))?;
+                self.allowed_fast = allowed_fast;
+            } else {
+                self.send(MessageType::Unchoke, EmptyPayload)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Cancels requests for blocks another peer has already delivered.
+    fn cancel_requests(&mut self) -> Result<()> {
+        let (needed, cancelled) = std::mem::take(&mut self.requests)
+            .into_iter()
+            .partition(|request| self.swarm.is_block_needed(request));
+        self.requests = needed;
+        for request in cancelled {
+            self.send(MessageType::Cancel, request)?;
+        }
+        Ok(())
+    }
+
+    fn request_blocks(&mut self) -> Result<()> {
+        if !self.am_interested {
+            return Ok(());
+        }
+        // While choked only the allowed fast pieces may be requested.
+        let bitfield = if !self.peer_choking {
+            self.peer_bitfield.clone()
+        } else if self.fast && !self.peer_allowed_fast.is_empty() {
+            let mut bitfield = Bitfield::new(self.swarm.pieces_count());
+            for index in &self.peer_allowed_fast {
+                if self.peer_bitfield.has_piece(*index) {
+                    bitfield.set_piece(*index);
+                }
+            }
+            bitfield
+        } else {
+            return Ok(());
+        };
+        self.suggested.retain(|index| !self.swarm.has_piece(*index));
+        while self.requests.len() < PIPELINE_SIZE {
+            let request = self.swarm.next_request(self.id, &bitfield, &self.suggested);
+            let Some(request) = request else {
+                break;
+            };
+            let hash_request = self.v2.then(|| self.swarm.hash_request(request.index()));
+            self.send(MessageType::Request, request.clone())?;
+            self.requests.push(request);
+            if let Some(hash_request) = hash_request.flatten() {
+                self.send(MessageType::HashRequest, hash_request.clone())?;
+                self.hash_requests.push(hash_request);
+            }
+        }
+        Ok(())
+    }
+
+    fn receive_block(&mut self, piece: Piece) -> Result<()> {
+        let position = self.requests.iter().position(|request| {
+            request.index() == piece.index()
+                && request.begin() == piece.begin()
+                && request.length() == piece.block.len()
+        });
+        let Some(position) = position else {
+            // Most likely a block cancelled after another peer delivered it.
+            self.swarm
+                .duplicate_bytes
+                .fetch_add(piece.block.len(), Ordering::Relaxed);
+            return Ok(());
+        };
+        self.requests.remove(position);
+        self.swarm.add_downloaded(piece.block.len());
+        self.swarm.choker.add_downloaded(self.id, piece.block.len());
+        self.swarm.receive_block(self.id, self.address.ip(), piece)
+    }
+
+    /// Drops queued uploads matching the predicate, explicitly rejecting them
+    /// when the Fast extension is enabled.
+    fn reject_uploads(&mut self, predicate: impl Fn(&RequestPayload) -> bool) -> Result<()> {
+        let (rejected, uploads) = std::mem::take(&mut self.uploads)
+            .into_iter()
+            .partition::<Vec<_>, _>(|upload| predicate(upload));
+        self.uploads = uploads.into();
+        if self.fast {
+            for request in rejected {
+                self.send(MessageType::RejectRequest, request)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lets a fast peer request some of our pieces before it is unchoked.
+    fn send_allowed_fast(&mut self) -> Result<()> {
+        let IpAddr::V4(ip) = self.address.ip() else {
+            return Ok(());
+        };
+        let allowed_fast = allowed_fast_set(ip, &self.swarm.info_hash, self.swarm.pieces_count());
+        for index in allowed_fast {
+            if self.swarm.has_piece(index) {
+                self.send(MessageType::AllowedFast, HavePayload::new(index))?;
+                self.allowed_fast.push(index);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_peer_bitfield(&mut self, bitfield: Bitfield) {
+        self.swarm.add_peer_bitfield(&self.peer_bitfield, &bitfield);
+        self.peer_bitfield = bitfield;
+        self.check_peer_seed();
+    }
+
+    fn check_peer_seed(&self) {
+        let pieces_count = self.swarm.pieces_count();
+        if (0..pieces_count).all(|index| self.peer_bitfield.has_piece(index)) {
+            self.swarm.set_peer_seed(self.id);
+        }
+    }
+
+    fn serve_request(&mut self) -> Result<()> {
+        let Some(request) = self.uploads.pop_front() else {
+            return Ok(());
+        };
+        let block = self
+            .swarm
+            .read_block(request.index(), request.begin(), request.length())?;
+        let length = block.len();
+        let piece = Piece::new(request.index(), request.begin(), block);
+        self.send(MessageType::Piece, piece.into_bytes())?;
+        self.swarm.add_uploaded(length);
+        self.swarm.choker.add_uploaded(self.id, length);
+        Ok(())
+    }
+
+    fn send<P: BytesConvertible>(&mut self, message_type: MessageType, payload: P) -> Result<()> {
+        send_message(
+            Message {
+                message_type,
+                payload,
+            },
+            &mut self.stream,
+        )
+    }
+}
+
+/// Builds our handshake for the swarm, advertising the extensions we support.
+pub fn local_handshake(swarm: &Swarm) -> Handshake {
+    let mut handshake = Handshake::new(&swarm.info_hash, peer_id::local());
+    if swarm.dht.is_some() {
+        handshake.set_dht();
+    }
+    if swarm.merkle.is_some() {
+        handshake.set_v2();
+    }
+    handshake.set_fast_extension();
+    handshake.set_extension_protocol();
+    handshake
+}
+
+/// Exchanges handshakes and returns the one received from the peer.
+pub fn handshake(swarm: &Swarm, stream: &mut TcpStream) -> Result<Handshake> {
+    let mut handshake = local_handshake(swarm);
+    let bytes = handshake.as_bytes_mut();
+
+    stream.write_all(bytes)?;
+    stream.read_exact(bytes)?;
+    Ok(handshake)
+}
+
+pub fn read_message<P: TryFromBytes>(
+    stream: &mut TcpStream,
+    pieces_count: usize,
+) -> Result<Message<P>> {
+    let mut header = [0u8; 4];
+    let length = loop {
+        stream.read_exact(header.as_mut())?;
+        let length = u32::from_be_bytes(header) as usize;
+        // Zero length messages are keep-alives.
+        if length > 0 {
+            break length;
+        }
+    };
+
+    let mut message_id = [0u8; 1];
+    stream.read_exact(message_id.as_mut())?;
+    let message_type = MessageType::try_from(message_id[0])?;
+    message_type.check_payload_length(length - message_id.len(), pieces_count)?;
+
+    let mut payload = vec![0; length - message_id.len()];
+    if !payload.is_empty() {
+        stream.read_exact(payload.as_mut())?;
+    }
+
+    let payload = P::try_from_bytes(payload)?;
+    Ok(Message {
+        message_type,
+        payload,
+    })
+}
+
+pub fn send_message<P: BytesConvertible>(
+    message: Message<P>,
+    stream: &mut TcpStream,
+) -> Result<()> {
+    let mut payload = message.payload.as_bytes();
+    let message_size = (payload.len() as i32 + 1).to_be_bytes();
+    let message_id: u8 = message.message_type.into();
+    let mut buffer = vec![0; payload.len() + 4 + 1];
+
+    message_size.as_ref().copy_to_slice(&mut buffer[0..4]);
+    [message_id].as_ref().copy_to_slice(&mut buffer[4..5]);
+    payload.copy_to_slice(&mut buffer[5..]);
+
+    stream.write_all(&buffer)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+
+    use super::*;
+    use crate::{
+        create::{create_torrent, MetaVersion},
+        storage::Storage,
+        swarm::BLOCK_SIZE,
+        torrent_file::TorrentFile,
+    };
+
+    /// Connected pair of streams on localhost.
+    fn streams() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn skips_keep_alives() {
+        let (mut client, mut server) = streams();
+        client.write_all(&[0, 0, 0, 0, 0, 0, 0, 1, 2]).unwrap();
+        let message = read_message::<Vec<u8>>(&mut server, 8).unwrap();
+        assert_eq!(message.message_type, MessageType::Interested);
+        assert!(message.payload.is_empty());
+    }
+
+    #[test]
+    fn rejects_oversized_messages() {
+        let (mut client, mut server) = streams();
+        // A piece message claiming to be almost 4 GiB long.
+        client.write_all(&[0xff, 0xff, 0xff, 0xff, 7]).unwrap();
+        assert!(read_message::<Vec<u8>>(&mut server, 8).is_err());
+
+        let (mut client, mut server) = streams();
+        // Two bytes of bitfield for a torrent with 8 pieces.
+        client.write_all(&[0, 0, 0, 3, 5, 0xff, 0xff]).unwrap();
+        assert!(read_message::<Vec<u8>>(&mut server, 8).is_err());
+
+        let (mut client, mut server) = streams();
+        client.write_all(&[0, 0, 0, 2, 5, 0xff]).unwrap();
+        let message = read_message::<Vec<u8>>(&mut server, 8).unwrap();
+        assert_eq!(message.payload, [0xff]);
+    }
+
+    #[test]
+    fn rejects_requests_while_choked_except_allowed_fast() {
+        const PIECES: usize = 16;
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        let data = (0..PIECES * BLOCK_SIZE)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(&source, &data).unwrap();
+        let torrent = create_torrent(&source, MetaVersion::V1, BLOCK_SIZE, None, false).unwrap();
+        let torrent = TorrentFile::from_bytes(&torrent).unwrap();
+        let storage = Storage::open_existing(&source, &torrent.info).unwrap();
+        let swarm = Swarm::new(torrent.info, storage).unwrap();
+        assert_eq!(swarm.verify().unwrap(), PIECES);
+        swarm.set_seeding(true);
+        let swarm = Arc::new(swarm);
+
+        let (mut client, server) = streams();
+        let mut handshake = Handshake::new(&swarm.info_hash, [0; 20]);
+        handshake.set_fast_extension();
+        let session = Session::new(swarm.clone(), server, &handshake, false).unwrap();
+        std::thread::spawn(move || session.run());
+
+        let message = read_message::<EmptyPayload>(&mut client, PIECES).unwrap();
+        assert_eq!(message.message_type, MessageType::HaveAll);
+        let allowed = allowed_fast_set(Ipv4Addr::LOCALHOST, &swarm.info_hash, PIECES);
+        for index in &allowed {
+            let message = read_message::<HavePayload>(&mut client, PIECES).unwrap();
+            assert_eq!(message.message_type, MessageType::AllowedFast);
+            assert_eq!(message.payload.index(), *index);
+        }
+
+        // We never sent interested, so the peer keeps choking us.
+        let choked = (0..PIECES).find(|index| !allowed.contains(index)).unwrap();
+        for index in [choked, allowed[0]] {
+            let request = RequestPayload::new(index, 0, BLOCK_SIZE);
+            send_message(
+                Message {
+                    message_type: MessageType::Request,
+                    payload: request,
+                },
+                &mut client,
+            )
+            .unwrap();
+        }
+        let message = read_message::<RequestPayload>(&mut client, PIECES).unwrap();
+        assert_eq!(message.message_type, MessageType::RejectRequest);
+        assert_eq!(message.payload.index(), choked);
+        let message = read_message::<Piece>(&mut client, PIECES).unwrap();
+        assert_eq!(message.message_type, MessageType::Piece);
+        assert_eq!(message.payload.index(), allowed[0]);
+        let offset = allowed[0] * BLOCK_SIZE;
+        assert_eq!(message.payload.block, data[offset..offset + BLOCK_SIZE]);
+    }
+}
@@ -0,0 +1,303 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Result;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+
+use crate::torrent_file::{FileEntry, Info};
+
+/// The torrent's data, concatenated from its files in piece order.
+pub struct Storage {
+    files: Vec<StoredFile>,
+    length: usize,
+}
+
+struct StoredFile {
+    /// Padding files and symlinks have no data on disk. Padding reads as
+    /// zeros.
+    file: Option<Mutex<File>>,
+    path: PathBuf,
+    length: usize,
+    sha1: Option<ByteBuf>,
+}
+
+impl StoredFile {
+    fn new(file: Option<File>, path: PathBuf, entry: &FileEntry) -> Self {
+        Self {
+            file: file.map(Mutex::new),
+            path,
+            length: entry.length,
+            sha1: entry.sha1.clone(),
+        }
+    }
+}
+
+/// Part of a file covered by a range of the torrent's data.
+#[derive(Debug)]
+pub struct FileSlice {
+    pub file: usize,
+    pub offset: usize,
+    pub length: usize,
+}
+
+/// Splits `length` bytes at `offset` of the concatenated data into the parts
+/// of the files with the given lengths.
+pub fn file_slices(lengths: &[usize], offset: usize, length: usize) -> Vec<FileSlice> {
+    let mut slices = Vec::new();
+    let end = offset + length;
+    let mut file_start = 0;
+    for (file, file_length) in lengths.iter().enumerate() {
+        let file_end = file_start + file_length;
+        // Empty files, such as symlinks, hold none of the data.
+        if *file_length > 0 && file_end > offset && file_start < end {
+            let start = offset.max(file_start);
+            slices.push(FileSlice {
+                file,
+                offset: start - file_start,
+                length: end.min(file_end) - start,
+            });
+        }
+        file_start = file_end;
+    }
+    slices
+}
+
+impl Storage {
+    /// Opens the files for both reading and writing, creating them with the
+    /// expected lengths when they do not exist yet. Multi-file torrents are
+    /// stored in a directory at `path`.
+    pub fn open(path: &Path, info: &Info) -> Result<Self> {
+        let mut files = Vec::new();
+        for entry in info.files() {
+            let path = entry.local_path(path)?;
+            if entry.is_padding() {
+                files.push(StoredFile::new(None, path, &entry));
+                continue;
+            }
+            if let Some(parent) = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+            {
+                std::fs::create_dir_all(parent)?;
+            }
+            if entry.is_symlink() {
+                create_symlink(&entry, &path)?;
+                files.push(StoredFile::new(None, path, &entry));
+                continue;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            if file.metadata()?.len() != entry.length as u64 {
+                file.set_len(entry.length as u64)?;
+            }
+            if entry.is_executable() {
+                set_executable(&file)?;
+            }
+            files.push(StoredFile::new(Some(file), path, &entry));
+        }
+        Ok(Self {
+            files,
+            length: info.total_length(),
+        })
+    }
+
+    /// Opens data which is expected to be already on disk.
+    pub fn open_existing(path: &Path, info: &Info) -> Result<Self> {
+        let mut files = Vec::new();
+        for entry in info.files() {
+            let path = entry.local_path(path)?;
+            if entry.is_padding() || entry.is_symlink() {
+                files.push(StoredFile::new(None, path, &entry));
+                continue;
+            }
+            let file = OpenOptions::new().read(true).write(true).open(&path)?;
+            let file_length = file.metadata()?.len();
+            if file_length != entry.length as u64 {
+                return Err(anyhow::Error::msg(format!(
+                    "{path:?} is {file_length} bytes long, expected {}",
+                    entry.length
+                )));
+            }
+            files.push(StoredFile::new(Some(file), path, &entry));
+        }
+        Ok(Self {
+            files,
+            length: info.total_length(),
+        })
+    }
+
+    pub fn read(&self, offset: usize, length: usize) -> Result<Vec<u8>> {
+        if offset + length > self.length {
+            return Err(anyhow::Error::msg(format!(
+                "Read of {length} bytes at {offset} is out of bounds"
+            )));
+        }
+        let mut buffer = vec![0; length];
+        let mut position = 0;
+        for slice in self.slices(offset, length) {
+            if let Some(file) = &self.files[slice.file].file {
+                let mut file = file.lock().unwrap();
+                file.seek(SeekFrom::Start(slice.offset as u64))?;
+                file.read_exact(&mut buffer[position..position + slice.length])?;
+            }
+            position += slice.length;
+        }
+        Ok(buffer)
+    }
+
+    pub fn write(&self, offset: usize, bytes: &[u8]) -> Result<()> {
+        if offset + bytes.len() > self.length {
+            return Err(anyhow::Error::msg(format!(
+                "Write of {} bytes at {offset} is out of bounds",
+                bytes.len()
+            )));
+        }
+        let mut position = 0;
+        for slice in self.slices(offset, bytes.len()) {
+            if let Some(file) = &self.files[slice.file].file {
+                let mut file = file.lock().unwrap();
+                file.seek(SeekFrom::Start(slice.offset as u64))?;
+                file.write_all(&bytes[position..position + slice.length])?;
+            }
+            position += slice.length;
+        }
+        Ok(())
+    }
+
+    /// Checks the files which carry a SHA-1 hash of their own (BEP 47).
+    pub fn verify_file_hashes(&self) -> Result<()> {
+        for stored in &self.files {
+            let (Some(file), Some(sha1)) = (&stored.file, &stored.sha1) else {
+                continue;
+            };
+            let mut file = file.lock().unwrap();
+            file.seek(SeekFrom::Start(0))?;
+            let mut hasher = Sha1::new();
+            std::io::copy(&mut (&mut *file).take(stored.length as u64), &mut hasher)?;
+            if hasher.finalize().as_slice() != sha1.as_slice() {
+                return Err(anyhow::Error::msg(format!(
+                    "{:?} does not match its SHA-1 hash",
+                    stored.path
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn slices(&self, offset: usize, length: usize) -> Vec<FileSlice> {
+        let lengths = self
+            .files
+            .iter()
+            .map(|file| file.length)
+            .collect::<Vec<_>>();
+        file_slices(&lengths, offset, length)
+    }
+}
+
+/// Links `path` to the entry's target unless something is already there.
+fn create_symlink(entry: &FileEntry, path: &Path) -> Result<()> {
+    let target = entry.symlink_target()?;
+    if path.symlink_metadata().is_ok() {
+        return Ok(());
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target, path)?;
+    #[cfg(not(unix))]
+    eprintln!("Not creating symlink {path:?} to {target:?}: unsupported platform");
+    Ok(())
+}
+
+fn set_executable(file: &File) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mut permissions = file.metadata()?.permissions();
+        permissions.set_mode(permissions.mode() | 0o111);
+        file.set_permissions(permissions)?;
+    }
+    #[cfg(not(unix))]
+    let _ = file;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Multi-file info dictionary with a padding file, an executable and a
+    /// symlink to `target`.
+    fn info(target: &str) -> Info {
+        let info = format!(
+            "d5:filesl\
+             d6:lengthi3e4:pathl1:aee\
+             d4:attr1:p6:lengthi13e4:pathl4:.pad2:13ee\
+             d4:attr1:x6:lengthi4e4:pathl3:bin3:runee\
+             d4:attr1:l6:lengthi0e4:pathl3:bin4:linke12:symlink pathl{}:{target}ee\
+             e4:name3:dir12:piece lengthi16384e6:pieces20:{}e",
+            target.len(),
+            "a".repeat(20)
+        );
+        serde_bencode::from_bytes(info.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn skips_padding_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().join("dir");
+        let storage = Storage::open(&root, &info("a")).unwrap();
+        let data = (1..=20).collect::<Vec<u8>>();
+        storage.write(0, &data).unwrap();
+
+        assert_eq!(std::fs::read(root.join("a")).unwrap(), data[..3]);
+        assert_eq!(std::fs::read(root.join("bin/run")).unwrap(), data[16..]);
+        assert!(!root.join(".pad").exists());
+        // Padding reads as zeros, whatever was written over it.
+        let mut expected = data.clone();
+        expected[3..16].fill(0);
+        assert_eq!(storage.read(0, 20).unwrap(), expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn applies_file_attributes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let root = directory.path().join("dir");
+        Storage::open(&root, &info("a")).unwrap();
+        let mode = std::fs::metadata(root.join("bin/run"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o111, 0o111);
+        assert_eq!(
+            std::fs::metadata(root.join("a"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o111,
+            0
+        );
+
+        // The link points at a file of the torrent, within its directory.
+        let link = root.join("bin/link");
+        assert_eq!(std::fs::read_link(&link).unwrap(), Path::new("../a"));
+        assert_eq!(
+            link.canonicalize().unwrap(),
+            root.canonicalize().unwrap().join("a")
+        );
+
+        let root = directory.path().join("escape");
+        assert!(Storage::open(&root, &info("..")).is_err());
+        assert!(root.join("bin/link").symlink_metadata().is_err());
+    }
+}
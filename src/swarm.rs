@@ -0,0 +1,809 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Result;
+
+use crate::{
+    choker::Choker,
+    dht::Dht,
+    listener::DEFAULT_PORT,
+    merkle::{self, Hash, MerkleTrees},
+    peer::{Bitfield, HashRequestPayload, HashesPayload, Piece, RequestPayload},
+    pex::{PexPeer, FLAG_CONNECTABLE, FLAG_SEED},
+    picker::PiecePicker,
+    storage::Storage,
+    torrent_file::{Info, InfoHash, Piece as PieceHash},
+};
+
+pub const BLOCK_SIZE: usize = 1 << 14;
+pub const DEFAULT_MAX_HASH_FAILURES: usize = 3;
+/// Peers waiting to be connected to. Further peers from trackers, the DHT
+/// and PEX are ignored until some are taken.
+const MAX_CANDIDATES: usize = 1000;
+
+/// State shared by every peer session of a single torrent.
+pub struct Swarm {
+    pub info_hash: InfoHash,
+    pub length: usize,
+    pub piece_length: usize,
+    /// SHA-1 hashes of the pieces. v2-only torrents have none and are
+    /// checked with `merkle` instead.
+    pub hashes: Vec<PieceHash>,
+    pub storage: Storage,
+    /// Private torrents only use peers from their trackers (BEP 27).
+    pub private: bool,
+    /// Port we accept peer connections on.
+    pub port: u16,
+    pub uploaded: AtomicUsize,
+    pub downloaded: AtomicUsize,
+    pub choker: Choker,
+    /// Bytes received for blocks which another peer had already delivered.
+    pub duplicate_bytes: AtomicUsize,
+    /// Block requests sent to a peer while another peer was already asked
+    /// for the same block.
+    pub endgame_requests: AtomicUsize,
+    /// Number of corrupt pieces a peer may contribute to before it is banned.
+    pub max_hash_failures: usize,
+    /// DHT node advertised to peers with `Port` messages.
+    pub dht: Option<Arc<Dht>>,
+    /// v2 hashes, which are used to check single blocks and, for v2-only
+    /// torrents, whole pieces.
+    pub merkle: Option<MerkleTrees>,
+    pieces_count: usize,
+    /// Keeps sessions open after the download is complete.
+    seeding: AtomicBool,
+    state: Mutex<SwarmState>,
+}
+
+struct SwarmState {
+    have: Bitfield,
+    /// Pieces in the order they were completed. Sessions remember how much of
+    /// it they have already announced with `Have` messages.
+    completed: Vec<usize>,
+    picker: PiecePicker,
+    /// Pieces which have at least one block requested.
+    partial: BTreeMap<usize, PartialPiece>,
+    /// Number of failed pieces each peer delivered blocks for.
+    hash_failures: HashMap<IpAddr, usize>,
+    banned: HashSet<IpAddr>,
+    sessions: HashMap<usize, ConnectedPeer>,
+    /// Peers to connect to, learned from trackers and other peers.
+    candidates: VecDeque<SocketAddr>,
+    /// Hashes of the blocks of pieces, received with `Hashes` messages.
+    block_hashes: HashMap<usize, Vec<Hash>>,
+    /// Pieces whose block hashes were requested and not received yet.
+    hash_requests: HashSet<usize>,
+}
+
+struct ConnectedPeer {
+    /// Address the peer accepts connections on, if known.
+    address: Option<SocketAddr>,
+    /// `ut_pex` flags describing the peer.
+    flags: u8,
+}
+
+struct PartialPiece {
+    buffer: Vec<u8>,
+    blocks: Vec<Block>,
+}
+
+#[derive(Clone, Default)]
+struct Block {
+    received: bool,
+    /// Peer which delivered the block.
+    from: Option<IpAddr>,
+    /// Sessions with an outstanding request for the block.
+    requested_by: Vec<usize>,
+}
+
+impl PartialPiece {
+    fn new(size: usize) -> Self {
+        Self {
+            buffer: vec![0; size],
+            blocks: vec![Block::default(); size.div_ceil(BLOCK_SIZE)],
+        }
+    }
+
+    fn block_size(&self, block: usize) -> usize {
+        BLOCK_SIZE.min(self.buffer.len() - block * BLOCK_SIZE)
+    }
+
+    fn request(&mut self, index: usize, block: usize, session: usize) -> RequestPayload {
+        self.blocks[block].requested_by.push(session);
+        RequestPayload::new(index, block * BLOCK_SIZE, self.block_size(block))
+    }
+
+    fn is_complete(&self) -> bool {
+        self.blocks.iter().all(|block| block.received)
+    }
+}
+
+impl Swarm {
+    pub fn new(info: Info, storage: Storage) -> Result<Self> {
+        let pieces_count = if info.is_v1() {
+            info.pieces.len()
+        } else {
+            info.total_length().div_ceil(info.piece_length)
+        };
+        Ok(Self {
+            info_hash: info.hash()?,
+            private: info.is_private(),
+            port: DEFAULT_PORT,
+            length: info.total_length(),
+            piece_length: info.piece_length,
+            hashes: info.pieces,
+            storage,
+            uploaded: AtomicUsize::new(0),
+            downloaded: AtomicUsize::new(0),
+            choker: Choker::new(),
+            duplicate_bytes: AtomicUsize::new(0),
+            endgame_requests: AtomicUsize::new(0),
+            max_hash_failures: DEFAULT_MAX_HASH_FAILURES,
+            dht: None,
+            merkle: None,
+            pieces_count,
+            seeding: AtomicBool::new(false),
+            state: Mutex::new(SwarmState {
+                have: Bitfield::new(pieces_count),
+                completed: Vec::new(),
+                picker: PiecePicker::new(pieces_count),
+                partial: BTreeMap::new(),
+                hash_failures: HashMap::new(),
+                banned: HashSet::new(),
+                sessions: HashMap::new(),
+                candidates: VecDeque::new(),
+                block_hashes: HashMap::new(),
+                hash_requests: HashSet::new(),
+            }),
+        })
+    }
+
+    pub fn pieces_count(&self) -> usize {
+        self.pieces_count
+    }
+
+    pub fn piece_size(&self, index: usize) -> usize {
+        self.piece_length
+            .min(self.length - index * self.piece_length)
+    }
+
+    pub fn bitfield(&self) -> Bitfield {
+        self.state.lock().unwrap().have.clone()
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.state.lock().unwrap().have.has_piece(index)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.completed.len() == self.pieces_count
+    }
+
+    pub fn is_seeding(&self) -> bool {
+        self.seeding.load(Ordering::Relaxed)
+    }
+
+    pub fn set_seeding(&self, seeding: bool) {
+        self.seeding.store(seeding, Ordering::Relaxed);
+    }
+
+    /// Hashes the data already in storage and marks valid pieces as
+    /// downloaded. Returns the number of valid pieces.
+    pub fn verify(&self) -> Result<usize> {
+        let mut valid = 0;
+        for index in 0..self.pieces_count {
+            let piece = self
+                .storage
+                .read(index * self.piece_length, self.piece_size(index))?;
+            if !self.is_valid_piece(index, &piece) {
+                continue;
+            }
+            valid += 1;
+            let mut state = self.state.lock().unwrap();
+            if !state.have.has_piece(index) {
+                state.have.set_piece(index);
+                state.completed.push(index);
+                state.picker.remove(index);
+            }
+        }
+        Ok(valid)
+    }
+
+    /// Checks files which have hashes of their own, once their pieces are
+    /// complete.
+    pub fn verify_files(&self) -> Result<()> {
+        self.storage.verify_file_hashes()
+    }
+
+    pub fn left(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state
+            .completed
+            .iter()
+            .fold(self.length, |left, index| left - self.piece_size(*index))
+    }
+
+    /// Returns pieces completed after the first `announced` ones.
+    pub fn completed_since(&self, announced: usize) -> Vec<usize> {
+        let state = self.state.lock().unwrap();
+        state.completed[announced.min(state.completed.len())..].to_vec()
+    }
+
+    /// Checks whether the peer has any piece we are still missing.
+    pub fn is_interesting(&self, bitfield: &Bitfield) -> bool {
+        let state = self.state.lock().unwrap();
+        (0..self.pieces_count)
+            .any(|index| !state.have.has_piece(index) && bitfield.has_piece(index))
+    }
+
+    /// Chooses the next block the session should request from its peer.
+    ///
+    /// Unrequested blocks of partially downloaded pieces come first, then a
+    /// new piece from the picker, preferring pieces the peer suggested. Once
+    /// every missing block is in flight the swarm enters endgame and hands out
+    /// blocks already requested from other peers.
+    pub fn next_request(
+        &self,
+        session: usize,
+        bitfield: &Bitfield,
+        suggested: &[usize],
+    ) -> Option<RequestPayload> {
+        let mut state = self.state.lock().unwrap();
+        for (index, partial) in state.partial.iter_mut() {
+            if !bitfield.has_piece(*index) {
+                continue;
+            }
+            let block = partial
+                .blocks
+                .iter()
+                .position(|block| !block.received && block.requested_by.is_empty());
+            if let Some(block) = block {
+                return Some(partial.request(*index, block, session));
+            }
+        }
+
+        let completed = state.completed.len();
+        if let Some(index) = state.picker.pick(bitfield, completed, suggested) {
+            let mut partial = PartialPiece::new(self.piece_size(index));
+            let request = partial.request(index, 0, session);
+            state.partial.insert(index, partial);
+            return Some(request);
+        }
+
+        for (index, partial) in state.partial.iter_mut() {
+            if !bitfield.has_piece(*index) {
+                continue;
+            }
+            let block = partial
+                .blocks
+                .iter()
+                .position(|block| !block.received && !block.requested_by.contains(&session));
+            if let Some(block) = block {
+                self.endgame_requests.fetch_add(1, Ordering::Relaxed);
+                return Some(partial.request(*index, block, session));
+            }
+        }
+        None
+    }
+
+    /// Checks whether a requested block still has to be downloaded.
+    pub fn is_block_needed(&self, request: &RequestPayload) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .partial
+            .get(&request.index())
+            .and_then(|partial| partial.blocks.get(request.begin() / BLOCK_SIZE))
+            .is_some_and(|block| !block.received)
+    }
+
+    /// Forgets requests the session will not receive an answer for, so the
+    /// blocks can be requested from other peers.
+    pub fn release_requests(&self, session: usize, requests: &[RequestPayload]) {
+        let mut state = self.state.lock().unwrap();
+        for request in requests {
+            let block = state
+                .partial
+                .get_mut(&request.index())
+                .and_then(|partial| partial.blocks.get_mut(request.begin() / BLOCK_SIZE));
+            if let Some(block) = block {
+                block.requested_by.retain(|id| *id != session);
+            }
+        }
+    }
+
+    /// Stores a block received from a peer, completing the piece once every
+    /// block has arrived.
+    pub fn receive_block(&self, session: usize, address: IpAddr, piece: Piece) -> Result<()> {
+        let index = piece.index();
+        let block = piece.begin() / BLOCK_SIZE;
+        let mut state = self.state.lock().unwrap();
+        let Some(partial) = state.partial.get_mut(&index) else {
+            self.duplicate_bytes
+                .fetch_add(piece.block.len(), Ordering::Relaxed);
+            return Ok(());
+        };
+        if block >= partial.blocks.len() || partial.block_size(block) != piece.block.len() {
+            return Err(anyhow::Error::msg(format!(
+                "Peer sent invalid block {} of piece {index}",
+                piece.begin()
+            )));
+        }
+        partial.blocks[block]
+            .requested_by
+            .retain(|id| *id != session);
+        if partial.blocks[block].received {
+            self.duplicate_bytes
+                .fetch_add(piece.block.len(), Ordering::Relaxed);
+            return Ok(());
+        }
+        if let (Some(merkle), Some(leaves)) = (&self.merkle, state.block_hashes.get(&index)) {
+            if !merkle.verify_block(index, piece.begin(), &piece.block, leaves) {
+                eprintln!(
+                    "Block {} of piece {index} from {address} failed hash check",
+                    piece.begin()
+                );
+                self.strike(&mut state, address);
+                return Ok(());
+            }
+        }
+        let partial = state.partial.get_mut(&index).expect("Piece is in progress");
+        partial.buffer[piece.begin()..piece.begin() + piece.block.len()]
+            .copy_from_slice(&piece.block);
+        partial.blocks[block].received = true;
+        partial.blocks[block].from = Some(address);
+        if !partial.is_complete() {
+            return Ok(());
+        }
+        let partial = state.partial.remove(&index).expect("Piece is in progress");
+        drop(state);
+        if self.is_valid_piece(index, &partial.buffer) {
+            return self.complete_piece(index, &partial.buffer);
+        }
+        self.fail_piece(index, partial);
+        Ok(())
+    }
+
+    /// Claims a whole missing piece for a source which has every piece, such
+    /// as a web seed.
+    pub fn next_piece(&self) -> Option<usize> {
+        let mut state = self.state.lock().unwrap();
+        let completed = state.completed.len();
+        state
+            .picker
+            .pick(&Bitfield::full(self.pieces_count), completed, &[])
+    }
+
+    /// Returns a piece claimed with `next_piece` which could not be
+    /// downloaded, so that it can be picked again.
+    pub fn release_piece(&self, index: usize) {
+        self.state.lock().unwrap().picker.unpick(index);
+    }
+
+    /// Stores a whole piece claimed with `next_piece`. Returns whether it
+    /// matched its hash; pieces which do not are released.
+    pub fn receive_piece(&self, index: usize, piece: &[u8]) -> Result<bool> {
+        if !self.is_valid_piece(index, piece) {
+            self.release_piece(index);
+            return Ok(false);
+        }
+        self.complete_piece(index, piece)?;
+        Ok(true)
+    }
+
+    /// Discards a piece which does not match its hash and strikes every peer
+    /// which delivered a block of it.
+    fn fail_piece(&self, index: usize, partial: PartialPiece) {
+        let mut state = self.state.lock().unwrap();
+        state.picker.unpick(index);
+        let contributors = partial
+            .blocks
+            .iter()
+            .filter_map(|block| block.from)
+            .collect::<HashSet<_>>();
+        for address in contributors {
+            eprintln!("Piece {index} from {address} failed hash check");
+            self.strike(&mut state, address);
+        }
+    }
+
+    /// Counts corrupt data from a peer, banning it after too many.
+    fn strike(&self, state: &mut SwarmState, address: IpAddr) {
+        let failures = state.hash_failures.entry(address).or_default();
+        *failures += 1;
+        let failures = *failures;
+        eprintln!("{address} sent corrupt data {failures} times");
+        if failures >= self.max_hash_failures {
+            state.banned.insert(address);
+        }
+    }
+
+    /// Request for the block hashes of a piece, unless they are known or
+    /// already requested. Hashes of single block pieces are known from the
+    /// torrent.
+    pub fn hash_request(&self, index: usize) -> Option<HashRequestPayload> {
+        let tree = self.merkle.as_ref()?.piece_tree(index)?;
+        let mut state = self.state.lock().unwrap();
+        if state.have.has_piece(index)
+            || state.block_hashes.contains_key(&index)
+            || state.hash_requests.contains(&index)
+        {
+            return None;
+        }
+        if tree.width == 1 {
+            state.block_hashes.insert(index, vec![tree.root]);
+            return None;
+        }
+        state.hash_requests.insert(index);
+        Some(HashRequestPayload::new(
+            &tree.pieces_root,
+            0,
+            tree.index,
+            tree.width,
+            0,
+        ))
+    }
+
+    /// Forgets hash requests which will not be answered, so that they can be
+    /// sent to other peers.
+    pub fn release_hash_requests(&self, requests: &[HashRequestPayload]) {
+        let Some(merkle) = &self.merkle else {
+            return;
+        };
+        let mut state = self.state.lock().unwrap();
+        for request in requests {
+            if let Some(index) = merkle.piece_at(&request.pieces_root(), request.index()) {
+                state.hash_requests.remove(&index);
+            }
+        }
+    }
+
+    /// Stores the block hashes of a piece once they hash up to the piece's
+    /// root, and drops the blocks already received which do not match them.
+    pub fn receive_hashes(&self, hashes: HashesPayload) -> Result<()> {
+        let request = hashes.request();
+        let Some(merkle) = &self.merkle else {
+            return Ok(());
+        };
+        let Some(index) = merkle.piece_at(&request.pieces_root(), request.index()) else {
+            return Ok(());
+        };
+        let tree = merkle.piece_tree(index).expect("Piece has a tree");
+        let leaves = hashes.hashes();
+        let valid = request.base_layer() == 0
+            && request.index() == tree.index
+            && leaves.len() >= tree.width
+            && merkle::root(&leaves[..tree.width], 0, tree.width) == tree.root;
+        let mut state = self.state.lock().unwrap();
+        state.hash_requests.remove(&index);
+        if !valid {
+            return Err(anyhow::Error::msg(format!(
+                "Peer sent hashes of piece {index} which do not match its root"
+            )));
+        }
+        let leaves = leaves[..tree.width].to_vec();
+        let mut corrupt = Vec::new();
+        if let Some(partial) = state.partial.get_mut(&index) {
+            for (block, received) in partial.blocks.iter_mut().enumerate() {
+                let begin = block * BLOCK_SIZE;
+                let end = begin + BLOCK_SIZE.min(partial.buffer.len() - begin);
+                if received.received
+                    && !merkle.verify_block(index, begin, &partial.buffer[begin..end], &leaves)
+                {
+                    received.received = false;
+                    corrupt.extend(received.from.take());
+                }
+            }
+        }
+        for address in corrupt {
+            eprintln!("Block of piece {index} from {address} failed hash check");
+            self.strike(&mut state, address);
+        }
+        state.block_hashes.insert(index, leaves);
+        Ok(())
+    }
+
+    /// Hashes answering a peer's request, if we have the data they cover.
+    pub fn serve_hashes(&self, request: &HashRequestPayload) -> Option<Vec<Hash>> {
+        self.merkle.as_ref()?.hashes(request, |offset, length| {
+            let first = offset / self.piece_length;
+            let last = (offset + length - 1) / self.piece_length;
+            if !(first..=last).all(|index| self.has_piece(index)) {
+                return None;
+            }
+            self.storage.read(offset, length).ok()
+        })
+    }
+
+    /// Registers a session with the swarm and returns its id.
+    pub fn connect_session(&self, address: SocketAddr, outgoing: bool) -> usize {
+        let id = self.choker.connect();
+        let peer = ConnectedPeer {
+            address: outgoing.then_some(address),
+            flags: if outgoing { FLAG_CONNECTABLE } else { 0 },
+        };
+        self.state.lock().unwrap().sessions.insert(id, peer);
+        id
+    }
+
+    pub fn disconnect_session(&self, id: usize) {
+        self.choker.disconnect(id);
+        self.state.lock().unwrap().sessions.remove(&id);
+    }
+
+    pub fn session_count(&self) -> usize {
+        self.state.lock().unwrap().sessions.len()
+    }
+
+    /// Records the port a peer listens on, learned from its extension
+    /// handshake.
+    pub fn set_listen_port(&self, id: usize, ip: IpAddr, port: u16) {
+        if let Some(peer) = self.state.lock().unwrap().sessions.get_mut(&id) {
+            peer.address = Some(SocketAddr::new(ip, port));
+        }
+    }
+
+    pub fn set_peer_seed(&self, id: usize) {
+        if let Some(peer) = self.state.lock().unwrap().sessions.get_mut(&id) {
+            peer.flags |= FLAG_SEED;
+        }
+    }
+
+    /// Connected peers to advertise with `ut_pex`, except the session's own.
+    pub fn pex_peers(&self, except: usize) -> Vec<PexPeer> {
+        let state = self.state.lock().unwrap();
+        state
+            .sessions
+            .iter()
+            .filter(|(id, _)| **id != except)
+            .filter_map(|(_, peer)| {
+                Some(PexPeer {
+                    address: peer.address?,
+                    flags: peer.flags,
+                })
+            })
+            .collect()
+    }
+
+    pub fn add_candidates(&self, addresses: impl IntoIterator<Item = SocketAddr>) {
+        let mut state = self.state.lock().unwrap();
+        for address in addresses {
+            let connected = state
+                .sessions
+                .values()
+                .any(|peer| peer.address == Some(address));
+            if state.candidates.len() >= MAX_CANDIDATES {
+                break;
+            }
+            if !connected && !state.candidates.contains(&address) {
+                state.candidates.push_back(address);
+            }
+        }
+    }
+
+    /// Adds peers which did not come from the torrent's trackers. Private
+    /// torrents ignore them (BEP 27).
+    pub fn add_external_candidates(&self, addresses: Vec<SocketAddr>, source: &str) {
+        if !self.private {
+            self.add_candidates(addresses);
+        } else if !addresses.is_empty() {
+            eprintln!(
+                "Ignoring {} peers from {source}: the torrent is private",
+                addresses.len()
+            );
+        }
+    }
+
+    /// Takes the next peer to connect to which is neither banned nor
+    /// connected already.
+    pub fn next_candidate(&self) -> Option<SocketAddr> {
+        let mut state = self.state.lock().unwrap();
+        while let Some(address) = state.candidates.pop_front() {
+            let connected = state
+                .sessions
+                .values()
+                .any(|peer| peer.address == Some(address));
+            if !connected && !state.banned.contains(&address.ip()) {
+                return Some(address);
+            }
+        }
+        None
+    }
+
+    pub fn is_banned(&self, address: IpAddr) -> bool {
+        self.state.lock().unwrap().banned.contains(&address)
+    }
+
+    /// Counts pieces of a newly received peer bitfield, replacing the
+    /// previous one when the peer sent several.
+    pub fn add_peer_bitfield(&self, previous: &Bitfield, bitfield: &Bitfield) {
+        let mut state = self.state.lock().unwrap();
+        state.picker.remove_bitfield(previous);
+        state.picker.add_bitfield(bitfield);
+    }
+
+    pub fn add_peer_piece(&self, index: usize) {
+        self.state.lock().unwrap().picker.add_piece(index);
+    }
+
+    /// Forgets pieces of a disconnected peer.
+    pub fn remove_peer_bitfield(&self, bitfield: &Bitfield) {
+        self.state.lock().unwrap().picker.remove_bitfield(bitfield);
+    }
+
+    /// Checks a whole piece against its SHA-1 hash, or against the merkle
+    /// trees when the torrent has no v1 hashes.
+    fn is_valid_piece(&self, index: usize, piece: &[u8]) -> bool {
+        match (self.hashes.get(index), &self.merkle) {
+            (Some(hash), _) => *hash == PieceHash::from(piece),
+            (None, Some(merkle)) => merkle.verify_piece(index, piece),
+            (None, None) => false,
+        }
+    }
+
+    fn complete_piece(&self, index: usize, piece: &[u8]) -> Result<()> {
+        self.storage.write(index * self.piece_length, piece)?;
+        let mut state = self.state.lock().unwrap();
+        if !state.have.has_piece(index) {
+            state.have.set_piece(index);
+            state.completed.push(index);
+        }
+        Ok(())
+    }
+
+    pub fn read_block(&self, index: usize, begin: usize, length: usize) -> Result<Vec<u8>> {
+        if index >= self.pieces_count || begin + length > self.piece_size(index) {
+            return Err(anyhow::Error::msg(format!(
+                "Block {begin}+{length} of piece {index} is out of bounds"
+            )));
+        }
+        if !self.has_piece(index) {
+            return Err(anyhow::Error::msg(format!(
+                "Piece {index} is not downloaded"
+            )));
+        }
+        self.storage.read(index * self.piece_length + begin, length)
+    }
+
+    pub fn add_uploaded(&self, bytes: usize) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_downloaded(&self, bytes: usize) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        create::{create_torrent, MetaVersion},
+        torrent_file::TorrentFile,
+    };
+
+    /// Two pieces of two blocks each.
+    const PIECE_LENGTH: usize = 2 * BLOCK_SIZE;
+
+    fn swarm(data: &[u8]) -> (Swarm, TempDir) {
+        let directory = tempfile::tempdir().unwrap();
+        let source = directory.path().join("source");
+        std::fs::write(&source, data).unwrap();
+        let torrent = create_torrent(&source, MetaVersion::V1, PIECE_LENGTH, None, false).unwrap();
+        let torrent = TorrentFile::from_bytes(&torrent).unwrap();
+        let storage = Storage::open(&directory.path().join("output"), &torrent.info).unwrap();
+        (Swarm::new(torrent.info, storage).unwrap(), directory)
+    }
+
+    fn data() -> Vec<u8> {
+        (0..2 * PIECE_LENGTH).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn block(data: &[u8], request: &RequestPayload) -> Piece {
+        let offset = request.index() * PIECE_LENGTH + request.begin();
+        Piece::new(
+            request.index(),
+            request.begin(),
+            data[offset..offset + request.length()].to_vec(),
+        )
+    }
+
+    #[test]
+    fn duplicates_requests_in_endgame_only() {
+        let data = data();
+        let (swarm, _directory) = swarm(&data);
+        let bitfield = Bitfield::full(swarm.pieces_count());
+        let address = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let mut requests = (0..3)
+            .map(|_| swarm.next_request(1, &bitfield, &[]).unwrap())
+            .collect::<Vec<_>>();
+        // The last block is still unrequested, so it is not a duplicate.
+        let last = swarm.next_request(2, &bitfield, &[]).unwrap();
+        assert!(!requests.contains(&last));
+        assert_eq!(swarm.endgame_requests.load(Ordering::Relaxed), 0);
+        requests.push(last.clone());
+
+        // Every block is in flight, so each session duplicates the other's
+        // requests, but never its own.
+        let duplicate = swarm.next_request(2, &bitfield, &[]).unwrap();
+        assert!(requests[..3].contains(&duplicate));
+        assert_eq!(swarm.next_request(1, &bitfield, &[]), Some(last));
+        assert!(swarm.next_request(1, &bitfield, &[]).is_none());
+        assert_eq!(swarm.endgame_requests.load(Ordering::Relaxed), 2);
+
+        // The block arriving from the first session cancels the duplicate.
+        assert!(swarm.is_block_needed(&duplicate));
+        swarm
+            .receive_block(1, address, block(&data, &duplicate))
+            .unwrap();
+        assert!(!swarm.is_block_needed(&duplicate));
+        swarm
+            .receive_block(2, address, block(&data, &duplicate))
+            .unwrap();
+        assert_eq!(
+            swarm.duplicate_bytes.load(Ordering::Relaxed),
+            duplicate.length()
+        );
+
+        for request in &requests {
+            if request != &duplicate {
+                swarm
+                    .receive_block(1, address, block(&data, request))
+                    .unwrap();
+            }
+        }
+        assert!(swarm.is_complete());
+    }
+
+    #[test]
+    fn bans_peers_contributing_to_failed_pieces() {
+        let data = data();
+        let (mut swarm, _directory) = swarm(&data);
+        swarm.max_hash_failures = 2;
+        let bitfield = Bitfield::full(swarm.pieces_count());
+        let honest = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let corrupt = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let corrupt_block = |request: &RequestPayload| {
+            let mut piece = block(&data, request);
+            piece.block[0] ^= 0xff;
+            piece
+        };
+
+        // Both peers contribute to a piece which fails the hash check.
+        let first = swarm.next_request(1, &bitfield, &[]).unwrap();
+        let second = swarm.next_request(1, &bitfield, &[]).unwrap();
+        assert_eq!(first.index(), second.index());
+        swarm
+            .receive_block(1, honest, block(&data, &first))
+            .unwrap();
+        swarm
+            .receive_block(2, corrupt, corrupt_block(&second))
+            .unwrap();
+        assert!(!swarm.has_piece(first.index()));
+        assert!(!swarm.is_banned(honest) && !swarm.is_banned(corrupt));
+
+        // Only the peer delivering the whole of the next failed piece reaches
+        // the limit.
+        let first = swarm.next_request(1, &bitfield, &[]).unwrap();
+        let second = swarm.next_request(1, &bitfield, &[]).unwrap();
+        swarm
+            .receive_block(2, corrupt, corrupt_block(&first))
+            .unwrap();
+        swarm
+            .receive_block(2, corrupt, block(&data, &second))
+            .unwrap();
+        assert!(swarm.is_banned(corrupt));
+        assert!(!swarm.is_banned(honest));
+    }
+}
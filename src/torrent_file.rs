@@ -1,16 +1,52 @@
-use anyhow::Result;
+use anyhow::{Error, Result};
+use serde_bencode::value::Value;
+use serde_bytes::{ByteBuf, Bytes};
 use sha1::{Digest, Sha1};
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     fmt::{self, Display},
     marker::PhantomData,
+    path::{Path, PathBuf},
 };
 
-use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::{
+    decode,
+    merkle::{self, Hash, HASH_SIZE},
+};
 
 #[derive(Deserialize)]
 pub struct TorrentFile {
-    pub announce: String,
+    /// Trackerless torrents rely on the DHT to find peers.
+    #[serde(default)]
+    pub announce: Option<String>,
+    /// Tiers of tracker URLs (BEP 12).
+    #[serde(default, rename = "announce-list")]
+    pub announce_list: Vec<Vec<String>>,
+    /// DHT nodes (`host`, `port`) to bootstrap from for trackerless torrents.
+    #[serde(default, deserialize_with = "deserialize_nodes")]
+    pub nodes: Vec<(String, u16)>,
+    /// Web seeds (BEP 19) serving the torrent's files over HTTP.
+    #[serde(
+        default,
+        rename = "url-list",
+        deserialize_with = "deserialize_url_list"
+    )]
+    pub url_list: Vec<String>,
+    /// HTTP seeds (BEP 17) serving pieces by index.
+    #[serde(default)]
+    pub httpseeds: Vec<String>,
+    #[serde(deserialize_with = "deserialize_info")]
     pub info: Info,
+    /// Concatenated hashes of the pieces of each v2 file larger than a piece,
+    /// keyed by the file's pieces root.
+    #[serde(default, rename = "piece layers")]
+    pub piece_layers: HashMap<ByteBuf, ByteBuf>,
 }
 
 const PIECE_LEN: usize = 20;
@@ -23,19 +59,148 @@ impl From<&[u8]> for Piece {
         let mut hasher = Sha1::new();
         hasher.update(value);
         let result = hasher.finalize();
-        Self(result.try_into().expect("Unable to hash piece"))
+        Self(result.into())
     }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct Info {
-    pub length: usize,
+    /// Length of a single-file torrent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+    /// Files of a multi-file torrent, which are stored in a directory called
+    /// `name`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<FileEntry>,
     pub name: String,
     #[serde(rename = "piece length")]
     pub piece_length: usize,
-    #[serde(deserialize_with = "deserialize_piece")]
-    #[serde(serialize_with = "serialize_piece")]
+    /// SHA-1 hashes of the pieces. v2-only torrents have none.
+    #[serde(default, deserialize_with = "deserialize_piece")]
+    #[serde(
+        serialize_with = "serialize_piece",
+        skip_serializing_if = "Vec::is_empty"
+    )]
     pub pieces: Vec<Piece>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+    /// Attributes of the file of a single-file torrent.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub attr: String,
+    /// SHA-1 hash of the file of a single-file torrent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<ByteBuf>,
+    /// 2 for v2 and hybrid torrents (BEP 52).
+    #[serde(
+        default,
+        rename = "meta version",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u8>,
+    /// Files of a v2 torrent as nested dictionaries of path components.
+    #[serde(default, rename = "file tree", skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<Value>,
+    /// The info dictionary as it appeared in the torrent file, so that keys
+    /// this struct does not know about are part of the info hash.
+    #[serde(skip)]
+    encoded: Vec<u8>,
+}
+
+/// File of a v2 torrent. Each file starts at a piece boundary and is hashed
+/// on its own.
+#[derive(Debug, Clone)]
+pub struct V2File {
+    pub path: Vec<String>,
+    pub length: usize,
+    /// Root of the merkle tree over the file's 16 KiB blocks. Empty files
+    /// have none.
+    pub pieces_root: Option<Hash>,
+    pub attr: String,
+    pub symlink_path: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct FileEntry {
+    pub length: usize,
+    /// Path components relative to the torrent's directory. Empty for the
+    /// file of a single-file torrent.
+    pub path: Vec<String>,
+    /// File attributes (BEP 47): `p` for padding, `x` for executable, `h`
+    /// for hidden and `l` for symlink. Hidden files need no special handling
+    /// on Unix.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub attr: String,
+    /// Target of a symlink, relative to the torrent's directory.
+    #[serde(
+        default,
+        rename = "symlink path",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub symlink_path: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<ByteBuf>,
+}
+
+impl FileEntry {
+    /// Padding files align the next file to a piece boundary. Their data is
+    /// all zeros and is never stored.
+    pub fn is_padding(&self) -> bool {
+        self.attr.contains('p')
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.attr.contains('x')
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.attr.contains('l')
+    }
+
+    /// Target of a symlink relative to the directory the link is in.
+    pub fn symlink_target(&self) -> Result<PathBuf> {
+        let mut target = PathBuf::new();
+        for _ in 1..self.path.len() {
+            target.push("..");
+        }
+        for component in &self.symlink_path {
+            check_path_component(component)?;
+            target.push(component);
+        }
+        if self.symlink_path.is_empty() {
+            return Err(Error::msg(format!("Symlink {:?} has no target", self.path)));
+        }
+        Ok(target)
+    }
+
+    /// Where the file is stored when the torrent is saved at `root`.
+    pub fn local_path(&self, root: &Path) -> Result<PathBuf> {
+        let mut path = root.to_path_buf();
+        for component in &self.path {
+            check_path_component(component)?;
+            path.push(component);
+        }
+        Ok(path)
+    }
+}
+
+/// Rejects components which would escape the torrent's directory.
+pub fn check_path_component(component: &str) -> Result<()> {
+    if component.is_empty()
+        || component == "."
+        || component == ".."
+        || component.contains(['/', '\\'])
+    {
+        return Err(Error::msg(format!(
+            "Invalid file path component {component:?}"
+        )));
+    }
+    Ok(())
+}
+
+impl AsRef<[u8]> for Piece {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
 }
 
 impl<'a> IntoIterator for &'a Piece {
@@ -49,36 +214,287 @@ impl<'a> IntoIterator for &'a Piece {
 
 impl Display for TorrentFile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "Tracker URL: {}", self.announce)?;
-        writeln!(f, "Length: {}", self.info.length)?;
-        writeln!(
-            f,
-            "Info Hash: {}",
-            hex::encode(self.info.hash().expect("Unable to hash info").0)
-        )?;
+        if let Some(announce) = &self.announce {
+            writeln!(f, "Tracker URL: {announce}")?;
+        }
+        writeln!(f, "Length: {}", self.info.total_length())?;
+        if self.info.is_v1() {
+            writeln!(
+                f,
+                "Info Hash: {}",
+                hex::encode(self.info.hash().expect("Unable to hash info").0)
+            )?;
+        }
+        if let Some(hash) = self.info.hash_v2().expect("Unable to hash info") {
+            writeln!(f, "Info Hash v2: {}", hex::encode(hash.0))?;
+        }
         writeln!(f, "Piece Length: {}", self.info.piece_length)?;
         writeln!(f, "Piece Hashes:")?;
         for piece in &self.info.pieces {
             writeln!(f, "{}", hex::encode(piece.0))?;
         }
+        if self.info.is_v2() {
+            writeln!(f, "Files:")?;
+            for file in self.info.v2_files().expect("Unable to read file tree") {
+                let pieces_root = file.pieces_root.map(hex::encode).unwrap_or_default();
+                writeln!(f, "{} {} {pieces_root}", file.path.join("/"), file.length)?;
+            }
+        }
         Ok(())
     }
 }
 
 const INFO_HASH_SIZE: usize = 20;
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct InfoHash(pub [u8; INFO_HASH_SIZE]);
 
+/// SHA-256 hash of the info dictionary of a v2 torrent.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InfoHashV2(pub [u8; HASH_SIZE]);
+
+impl InfoHashV2 {
+    /// First 20 bytes of the hash, used where protocols expect a v1 sized
+    /// info hash, such as trackers and handshakes.
+    pub fn truncated(&self) -> InfoHash {
+        let mut hash = [0; INFO_HASH_SIZE];
+        hash.copy_from_slice(&self.0[..INFO_HASH_SIZE]);
+        InfoHash(hash)
+    }
+}
+
+impl TorrentFile {
+    /// Parses a torrent file. The info hash covers the info dictionary as it
+    /// appears in the file, since re-encoding it would sort keys which the
+    /// file may have left unsorted.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut torrent = serde_bencode::from_bytes::<Self>(bytes)?;
+        let entries = decode::dictionary_entries(bytes)?;
+        if let Some((_, info)) = entries.iter().find(|(key, _)| key == b"info") {
+            torrent.info.encoded = info.to_vec();
+        }
+        Ok(torrent)
+    }
+
+    /// Piece layers of the v2 files, keyed by pieces root. Every file larger
+    /// than a piece must have a layer which hashes up to its root.
+    pub fn piece_layers(&self) -> Result<HashMap<Hash, Vec<Hash>>> {
+        let mut layers = HashMap::new();
+        for file in self.info.v2_files()? {
+            let Some(pieces_root) = file.pieces_root else {
+                continue;
+            };
+            if file.length <= self.info.piece_length {
+                continue;
+            }
+            let layer = self
+                .piece_layers
+                .get(Bytes::new(&pieces_root))
+                .ok_or_else(|| Error::msg(format!("Missing piece layer for {:?}", file.path)))?;
+            let layer = layer
+                .chunks_exact(HASH_SIZE)
+                .map(|hash| hash.try_into().expect("Chunk is a hash"))
+                .collect::<Vec<Hash>>();
+            let root = merkle::root(
+                &layer,
+                merkle::piece_height(self.info.piece_length),
+                layer.len().next_power_of_two(),
+            );
+            if layer.len() != file.length.div_ceil(self.info.piece_length) || root != pieces_root {
+                return Err(Error::msg(format!(
+                    "Piece layer of {:?} does not match its pieces root",
+                    file.path
+                )));
+            }
+            layers.insert(pieces_root, layer);
+        }
+        Ok(layers)
+    }
+}
+
 impl Info {
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    /// Whether the torrent has v1 piece hashes. Hybrid torrents are both v1
+    /// and v2.
+    pub fn is_v1(&self) -> bool {
+        !self.pieces.is_empty() || self.meta_version.is_none()
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2)
+    }
+
+    /// Length of the data in the pieces, including the padding which aligns
+    /// the files of v2-only torrents to pieces.
+    pub fn total_length(&self) -> usize {
+        match self.length {
+            Some(length) => length,
+            None => self.files().iter().map(|file| file.length).sum(),
+        }
+    }
+
+    /// Files in the order their data appears in the pieces. v2-only torrents
+    /// start every file at a piece boundary, so padding files are added
+    /// between their files like hybrid torrents have.
+    pub fn files(&self) -> Vec<FileEntry> {
+        if !self.is_v1() {
+            let mut files = self.v2_files().unwrap_or_default();
+            // A single file named after the torrent is stored like a v1
+            // single-file torrent.
+            if let [file] = files.as_mut_slice() {
+                if file.path == [self.name.as_str()] {
+                    file.path.clear();
+                }
+            }
+            let mut entries = Vec::new();
+            for (index, file) in files.iter().enumerate() {
+                entries.push(FileEntry {
+                    length: file.length,
+                    path: file.path.clone(),
+                    attr: file.attr.clone(),
+                    symlink_path: file.symlink_path.clone(),
+                    sha1: None,
+                });
+                let padding = file
+                    .length
+                    .checked_next_multiple_of(self.piece_length)
+                    .unwrap_or(file.length)
+                    - file.length;
+                if padding > 0 && files[index + 1..].iter().any(|file| file.length > 0) {
+                    entries.push(FileEntry {
+                        length: padding,
+                        path: vec![".pad".to_string(), padding.to_string()],
+                        attr: "p".to_string(),
+                        symlink_path: Vec::new(),
+                        sha1: None,
+                    });
+                }
+            }
+            return entries;
+        }
+        if self.files.is_empty() {
+            return vec![FileEntry {
+                length: self.length.unwrap_or_default(),
+                path: Vec::new(),
+                attr: self.attr.clone(),
+                symlink_path: Vec::new(),
+                sha1: self.sha1.clone(),
+            }];
+        }
+        self.files.clone()
+    }
+
+    /// Files of the v2 file tree, ordered by path.
+    pub fn v2_files(&self) -> Result<Vec<V2File>> {
+        let mut files = Vec::new();
+        if let Some(tree) = &self.file_tree {
+            walk_file_tree(tree, &mut Vec::new(), &mut files)?;
+        }
+        Ok(files)
+    }
+
+    /// Identifies the torrent to trackers and peers: the SHA-1 hash of the
+    /// info dictionary, or the truncated v2 hash for v2-only torrents.
     pub fn hash(&self) -> Result<InfoHash> {
-        let encoded_info = serde_bencode::to_bytes(self)?;
+        if !self.is_v1() {
+            if let Some(hash) = self.hash_v2()? {
+                return Ok(hash.truncated());
+            }
+        }
         let mut hasher = Sha1::new();
-        hasher.update(&encoded_info);
+        hasher.update(&self.encoded()?);
         let result = hasher.finalize();
-        Ok(InfoHash(result.try_into().expect("Unable to hash info")))
+        Ok(InfoHash(result.into()))
+    }
+
+    pub fn hash_v2(&self) -> Result<Option<InfoHashV2>> {
+        if !self.is_v2() {
+            return Ok(None);
+        }
+        Ok(Some(InfoHashV2(merkle::hash(&self.encoded()?))))
+    }
+
+    fn encoded(&self) -> Result<Cow<'_, [u8]>> {
+        if self.encoded.is_empty() {
+            return Ok(Cow::Owned(serde_bencode::to_bytes(self)?));
+        }
+        Ok(Cow::Borrowed(&self.encoded))
     }
 }
 
+/// Collects the files below a node of the file tree. Files are nodes with a
+/// single empty key describing them.
+fn walk_file_tree(node: &Value, path: &mut Vec<String>, files: &mut Vec<V2File>) -> Result<()> {
+    let Value::Dict(entries) = node else {
+        return Err(Error::msg(format!("Invalid file tree entry at {path:?}")));
+    };
+    if let Some(file) = entries.get(b"".as_slice()) {
+        let Value::Dict(file) = file else {
+            return Err(Error::msg(format!("Invalid file tree entry at {path:?}")));
+        };
+        let length = match file.get(b"length".as_slice()) {
+            Some(Value::Int(length)) if *length >= 0 => *length as usize,
+            _ => return Err(Error::msg(format!("Invalid length of {path:?}"))),
+        };
+        let pieces_root = match file.get(b"pieces root".as_slice()) {
+            Some(Value::Bytes(root)) => Some(
+                root.as_slice()
+                    .try_into()
+                    .map_err(|_| Error::msg(format!("Invalid pieces root of {path:?}")))?,
+            ),
+            _ if length == 0 => None,
+            _ => return Err(Error::msg(format!("Missing pieces root of {path:?}"))),
+        };
+        let attr = match file.get(b"attr".as_slice()) {
+            Some(Value::Bytes(attr)) => String::from_utf8_lossy(attr).into_owned(),
+            _ => String::new(),
+        };
+        let symlink_path = match file.get(b"symlink path".as_slice()) {
+            Some(Value::List(components)) => components
+                .iter()
+                .map(|component| match component {
+                    Value::Bytes(component) => Ok(String::from_utf8(component.clone())?),
+                    _ => Err(Error::msg(format!("Invalid symlink path of {path:?}"))),
+                })
+                .collect::<Result<_>>()?,
+            _ => Vec::new(),
+        };
+        files.push(V2File {
+            path: path.clone(),
+            length,
+            pieces_root,
+            attr,
+            symlink_path,
+        });
+        return Ok(());
+    }
+    let mut names = entries.keys().collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        path.push(String::from_utf8(name.clone())?);
+        walk_file_tree(&entries[name], path, files)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+/// Keeps the bytes of the info dictionary along with its fields, so that its
+/// hash does not depend on which keys `Info` knows about. They are re-encoded
+/// with sorted keys until `TorrentFile::from_bytes` replaces them with the
+/// bytes from the file.
+fn deserialize_info<'de, D>(deserializer: D) -> Result<Info, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Value::deserialize(deserializer)?;
+    let encoded = serde_bencode::to_bytes(&value).map_err(de::Error::custom)?;
+    let mut info = serde_bencode::from_bytes::<Info>(&encoded).map_err(de::Error::custom)?;
+    info.encoded = encoded;
+    Ok(info)
+}
+
 fn deserialize_piece<'de, D>(deserializer: D) -> Result<Vec<Piece>, D::Error>
 where
     D: Deserializer<'de>,
@@ -91,13 +507,16 @@ where
         type Value = Vec<Piece>;
 
         fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("a nonempty sequence of numbers")
+            formatter.write_str("concatenated 20 byte piece hashes")
         }
 
         fn visit_bytes<E>(self, v: &[u8]) -> std::result::Result<Self::Value, E>
         where
             E: serde::de::Error,
         {
+            if !v.len().is_multiple_of(PIECE_LEN) {
+                return Err(E::invalid_length(v.len(), &self));
+            }
             let mut pieces = Vec::new();
             let mut start = 0;
             while v.len() - start >= PIECE_LEN {
@@ -119,6 +538,54 @@ where
     deserializer.deserialize_seq(visitor)
 }
 
+/// `url-list` is either a single URL or a list of them. Empty URLs are
+/// ignored.
+fn deserialize_url_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let urls = match Value::deserialize(deserializer)? {
+        Value::Bytes(url) => vec![url],
+        Value::List(urls) => urls
+            .into_iter()
+            .filter_map(|url| match url {
+                Value::Bytes(url) => Some(url),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Ok(urls
+        .into_iter()
+        .filter_map(|url| String::from_utf8(url).ok())
+        .filter(|url| !url.is_empty())
+        .collect())
+}
+
+/// `nodes` is a list of `[host, port]` lists, which serde_bencode cannot
+/// read as tuples. Malformed nodes are ignored.
+fn deserialize_nodes<'de, D>(deserializer: D) -> Result<Vec<(String, u16)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Value::List(nodes) = Value::deserialize(deserializer)? else {
+        return Ok(Vec::new());
+    };
+    Ok(nodes
+        .into_iter()
+        .filter_map(|node| match node {
+            Value::List(node) => match node.as_slice() {
+                [Value::Bytes(host), Value::Int(port)] => Some((
+                    String::from_utf8(host.clone()).ok()?,
+                    u16::try_from(*port).ok()?,
+                )),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect())
+}
+
 fn serialize_piece<S>(piece: &[Piece], s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -126,3 +593,31 @@ where
     let bytes = piece.iter().flatten().copied().collect::<Vec<u8>>();
     s.serialize_bytes(&bytes)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_dht_nodes() {
+        let torrent = b"d4:infod6:lengthi3e4:name4:file12:piece lengthi16384e\
+            6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodesll9:127.0.0.1i6881eel1:hi70000eeee";
+        let torrent = TorrentFile::from_bytes(torrent).unwrap();
+        assert_eq!(torrent.nodes, [("127.0.0.1".to_string(), 6881)]);
+    }
+
+    #[test]
+    fn hashes_info_bytes_from_the_file() {
+        // Keys out of order, and one which `Info` does not know about.
+        let info = b"d4:name4:file6:lengthi3e12:piece lengthi16384e\
+            6:pieces20:aaaaaaaaaaaaaaaaaaaa6:sourcei1ee";
+        let mut torrent = b"d4:info".to_vec();
+        torrent.extend(info);
+        torrent.push(b'e');
+        let torrent = TorrentFile::from_bytes(&torrent).unwrap();
+        assert_eq!(
+            torrent.info.hash().unwrap(),
+            InfoHash(Sha1::digest(info).into())
+        );
+    }
+}
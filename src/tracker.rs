@@ -1,26 +1,66 @@
-use crate::torrent_file::InfoHash;
+use crate::{listener::DEFAULT_PORT, peer_id, swarm::Swarm, torrent_file::InfoHash};
 use anyhow::Result;
 use serde::{de::Visitor, Deserialize, Deserializer, Serialize};
 use std::{
     fmt,
     marker::PhantomData,
     net::{Ipv4Addr, SocketAddrV4},
+    sync::atomic::Ordering,
 };
 
 #[derive(Debug, Serialize)]
 struct TrackerRequest {
     peer_id: String,
-    port: usize,
+    port: u16,
     uploaded: usize,
     downloaded: usize,
     left: usize,
     compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<Event>,
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
+}
+
+/// Transfer statistics reported to the tracker.
+#[derive(Debug)]
+pub struct Announce<'a> {
+    pub info_hash: &'a InfoHash,
+    pub port: u16,
+    pub uploaded: usize,
+    pub downloaded: usize,
+    pub left: usize,
+    pub event: Option<Event>,
+}
+
+impl<'a> Announce<'a> {
+    pub fn from_swarm(swarm: &'a Swarm, port: u16, event: Option<Event>) -> Self {
+        Self {
+            info_hash: &swarm.info_hash,
+            port,
+            uploaded: swarm.uploaded.load(Ordering::Relaxed),
+            downloaded: swarm.downloaded.load(Ordering::Relaxed),
+            left: swarm.left(),
+            event,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
-struct TrackerResponse {
+pub struct TrackerResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    /// Seconds the tracker wants us to wait between regular announces.
+    pub interval: Option<u64>,
+    #[serde(default)]
     #[serde(deserialize_with = "deserialize_peers")]
-    peers: Vec<Peer>,
+    pub peers: Vec<Peer>,
 }
 
 pub async fn discover_peers(
@@ -28,26 +68,44 @@ pub async fn discover_peers(
     info_hash: &InfoHash,
     file_size: usize,
 ) -> Result<Vec<Peer>> {
-    let request = TrackerRequest {
-        port: 6881,
-        peer_id: "00112233445566778899".to_string(),
+    let request = Announce {
+        info_hash,
+        port: DEFAULT_PORT,
         uploaded: 0,
         downloaded: 0,
         left: file_size,
+        event: None,
+    };
+    let response = self::announce(announce, &request).await?;
+    Ok(response.peers)
+}
+
+pub async fn announce(announce: &str, request: &Announce<'_>) -> Result<TrackerResponse> {
+    let tracker_request = TrackerRequest {
+        port: request.port,
+        // Our peer ids are printable, so they need no binary encoding.
+        peer_id: String::from_utf8_lossy(&peer_id::local()).into_owned(),
+        uploaded: request.uploaded,
+        downloaded: request.downloaded,
+        left: request.left,
         compact: 1,
+        event: request.event,
     };
 
-    let url_params = serde_urlencoded::to_string(&request)?;
+    let url_params = serde_urlencoded::to_string(&tracker_request)?;
     let tracker_url = format!(
         "{}?{}&info_hash={}",
         announce,
         url_params,
-        &urlencode(&info_hash)
+        &urlencode(request.info_hash)
     );
 
     let bytes = reqwest::get(tracker_url).await?.bytes().await?;
     let response = serde_bencode::from_bytes::<TrackerResponse>(&bytes)?;
-    Ok(response.peers)
+    if let Some(reason) = response.failure_reason {
+        return Err(anyhow::Error::msg(format!("Tracker failure: {reason}")));
+    }
+    Ok(response)
 }
 
 const PEER_SIZE: usize = 6;
@@ -87,7 +145,7 @@ where
     deserializer.deserialize_seq(PieceVisitor(PhantomData))
 }
 
-fn urlencode(hash: &InfoHash) -> String {
+pub fn urlencode(hash: &InfoHash) -> String {
     let mut encoded = String::with_capacity(3 * hash.0.len());
     for &byte in &hash.0 {
         encoded.push('%');
@@ -0,0 +1,504 @@
+use std::{sync::Arc, thread::JoinHandle, time::Duration};
+
+use anyhow::{Error, Result};
+use reqwest::{
+    blocking::{Client, Response},
+    header::{RANGE, RETRY_AFTER},
+    StatusCode,
+};
+
+use crate::{
+    storage::file_slices,
+    swarm::Swarm,
+    torrent_file::{Info, InfoHash},
+    tracker,
+};
+
+/// Delay before retrying a failing web seed, doubled after each further
+/// failure.
+#[cfg(not(test))]
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+#[cfg(test)]
+const INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Consecutive failures after which a web seed is abandoned.
+const MAX_FAILURES: u32 = 5;
+/// Delay used when a busy HTTP seed does not say how long to wait.
+const DEFAULT_BUSY_DELAY: Duration = Duration::from_secs(30);
+const MAX_BUSY_DELAY: Duration = Duration::from_secs(10 * 60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often an idle web seed checks whether peers released a piece.
+const PIECE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// HTTP server serving the torrent's pieces.
+pub struct WebSeed {
+    url: String,
+    protocol: Protocol,
+}
+
+enum Protocol {
+    /// Plain file server (BEP 19). Pieces are fetched with range requests on
+    /// the files they span.
+    GetRight {
+        /// URL of each file, in the order their data appears in the pieces.
+        /// Padding files are not fetched.
+        file_urls: Vec<Option<String>>,
+        file_lengths: Vec<usize>,
+    },
+    /// Script serving whole pieces by index (BEP 17).
+    Hoffman { info_hash: InfoHash },
+}
+
+enum Fetched {
+    Piece(Vec<u8>),
+    /// The server asked us to come back later.
+    Busy(Duration),
+}
+
+impl WebSeed {
+    /// `url-list` entries of single-file torrents point at the file itself
+    /// unless they end with a slash, in which case the torrent's name is
+    /// appended. Files of multi-file torrents are found below the torrent's
+    /// name.
+    pub fn get_right(url: &str, info: &Info) -> Self {
+        let files = info.files();
+        let single_file = matches!(files.as_slice(), [file] if file.path.is_empty());
+        let file_urls = if single_file && !url.ends_with('/') {
+            vec![Some(url.to_string())]
+        } else {
+            let base = format!("{url}{}", if url.ends_with('/') { "" } else { "/" });
+            files
+                .iter()
+                .map(|file| {
+                    if file.is_padding() {
+                        return None;
+                    }
+                    let path = std::iter::once(&info.name)
+                        .chain(&file.path)
+                        .map(|component| urlencoding::encode(component))
+                        .collect::<Vec<_>>();
+                    Some(format!("{base}{}", path.join("/")))
+                })
+                .collect()
+        };
+        Self {
+            url: url.to_string(),
+            protocol: Protocol::GetRight {
+                file_urls,
+                file_lengths: files.iter().map(|file| file.length).collect(),
+            },
+        }
+    }
+
+    /// `httpseeds` entry, queried with the torrent's info hash and a piece
+    /// index.
+    pub fn hoffman(url: &str, info_hash: InfoHash) -> Self {
+        Self {
+            url: url.to_string(),
+            protocol: Protocol::Hoffman { info_hash },
+        }
+    }
+
+    /// Downloads pieces on a background thread until the torrent is complete
+    /// or the web seed keeps failing.
+    pub fn spawn(self, swarm: Arc<Swarm>) -> JoinHandle<()> {
+        std::thread::spawn(move || {
+            if let Err(error) = self.run(swarm) {
+                eprintln!("Web seed {} failed with error: {error:?}", self.url);
+            }
+        })
+    }
+
+    fn run(&self, swarm: Arc<Swarm>) -> Result<()> {
+        let client = Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        let mut failures = 0;
+        while !swarm.is_complete() {
+            let Some(index) = swarm.next_piece() else {
+                std::thread::sleep(PIECE_POLL_INTERVAL);
+                continue;
+            };
+            match self.fetch_piece(&client, &swarm, index) {
+                Ok(Fetched::Piece(piece)) => {
+                    swarm.add_downloaded(piece.len());
+                    if swarm.receive_piece(index, &piece)? {
+                        failures = 0;
+                        continue;
+                    }
+                    eprintln!("Piece {index} from web seed {} failed hash check", self.url);
+                }
+                Ok(Fetched::Busy(delay)) => {
+                    swarm.release_piece(index);
+                    eprintln!("Web seed {} is busy, retrying in {delay:?}", self.url);
+                    std::thread::sleep(delay);
+                    continue;
+                }
+                Err(error) => {
+                    swarm.release_piece(index);
+                    eprintln!(
+                        "Failed to download piece {index} from web seed {}: {error:?}",
+                        self.url
+                    );
+                }
+            }
+            failures += 1;
+            if failures >= MAX_FAILURES {
+                return Err(Error::msg(format!("Gave up after {failures} failures")));
+            }
+            std::thread::sleep(MAX_BACKOFF.min(INITIAL_BACKOFF * 2u32.pow(failures - 1)));
+        }
+        Ok(())
+    }
+
+    fn fetch_piece(&self, client: &Client, swarm: &Swarm, index: usize) -> Result<Fetched> {
+        let size = swarm.piece_size(index);
+        match &self.protocol {
+            Protocol::GetRight {
+                file_urls,
+                file_lengths,
+            } => {
+                let mut piece = Vec::with_capacity(size);
+                for slice in file_slices(file_lengths, index * swarm.piece_length, size) {
+                    let Some(url) = &file_urls[slice.file] else {
+                        piece.resize(piece.len() + slice.length, 0);
+                        continue;
+                    };
+                    let response = client
+                        .get(url)
+                        .header(
+                            RANGE,
+                            format!("bytes={}-{}", slice.offset, slice.offset + slice.length - 1),
+                        )
+                        .send()?;
+                    let status = response.status();
+                    // Servers ignoring the range send the whole file, which is
+                    // only usable when the range covers it.
+                    let whole_file =
+                        status == StatusCode::OK && slice.length == file_lengths[slice.file];
+                    if status != StatusCode::PARTIAL_CONTENT && !whole_file {
+                        return Err(Error::msg(format!("Unexpected response status {status}")));
+                    }
+                    piece.extend_from_slice(&read_body(response, slice.length)?);
+                }
+                Ok(Fetched::Piece(piece))
+            }
+            Protocol::Hoffman { info_hash } => {
+                // Whole pieces are requested, so `ranges` is left out.
+                let separator = if self.url.contains('?') { '&' } else { '?' };
+                let url = format!(
+                    "{}{separator}info_hash={}&piece={index}",
+                    self.url,
+                    tracker::urlencode(info_hash)
+                );
+                let response = client.get(url).send()?;
+                match response.status() {
+                    StatusCode::OK => Ok(Fetched::Piece(read_body(response, size)?)),
+                    StatusCode::SERVICE_UNAVAILABLE => Ok(Fetched::Busy(busy_delay(response))),
+                    status => Err(Error::msg(format!("Unexpected response status {status}"))),
+                }
+            }
+        }
+    }
+}
+
+fn read_body(response: Response, length: usize) -> Result<Vec<u8>> {
+    let bytes = response.bytes()?;
+    if bytes.len() != length {
+        return Err(Error::msg(format!(
+            "Expected {length} bytes, received {}",
+            bytes.len()
+        )));
+    }
+    Ok(bytes.to_vec())
+}
+
+/// Busy HTTP seeds answer with the number of seconds to wait, either in a
+/// `Retry-After` header or as the response body.
+fn busy_delay(response: Response) -> Duration {
+    let header = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    let seconds = header.or_else(|| response.text().ok()?.trim().parse().ok());
+    seconds
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_BUSY_DELAY)
+        .min(MAX_BUSY_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        path::Path,
+        sync::Mutex,
+        time::Instant,
+    };
+
+    use super::*;
+    use crate::{
+        create::{create_torrent, MetaVersion},
+        storage::Storage,
+        torrent_file::TorrentFile,
+    };
+
+    const PIECE_LENGTH: usize = 1 << 14;
+
+    struct Request {
+        target: String,
+        range: Option<String>,
+        time: Instant,
+    }
+
+    struct Reply {
+        status: u16,
+        headers: Vec<(&'static str, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Reply {
+        fn new(status: u16, body: Vec<u8>) -> Self {
+            Self {
+                status,
+                headers: Vec::new(),
+                body,
+            }
+        }
+    }
+
+    /// Answers HTTP requests on localhost with the handler, recording every
+    /// request. Returns the server's base URL.
+    fn serve(
+        handler: impl Fn(&str, Option<&str>) -> Reply + Send + 'static,
+    ) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut lines = BufReader::new(&stream).lines().map(Result::unwrap);
+                let request_line = lines.next().unwrap();
+                let target = request_line.split(' ').nth(1).unwrap().to_string();
+                let range = lines.take_while(|line| !line.is_empty()).find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("range")
+                        .then(|| value.trim().to_string())
+                });
+                let reply = handler(&target, range.as_deref());
+                recorded.lock().unwrap().push(Request {
+                    target,
+                    range,
+                    time: Instant::now(),
+                });
+                let mut response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: {}\r\nConnection: close\r\n",
+                    reply.status,
+                    reply.body.len()
+                );
+                for (name, value) in reply.headers {
+                    response.push_str(&format!("{name}: {value}\r\n"));
+                }
+                response.push_str("\r\n");
+                _ = stream.write_all(response.as_bytes());
+                _ = stream.write_all(&reply.body);
+            }
+        });
+        (url, requests)
+    }
+
+    /// Two files whose lengths are not multiples of the piece length, so that
+    /// the second piece spans both of them.
+    fn files() -> Vec<(&'static str, Vec<u8>)> {
+        vec![
+            ("a.bin", (0..20000).map(|i| (i % 251) as u8).collect()),
+            ("b.bin", (0..30000).map(|i| (i % 241) as u8).collect()),
+        ]
+    }
+
+    /// Creates a torrent of `files()` and an empty download for it.
+    fn swarm(directory: &Path) -> (Info, Arc<Swarm>) {
+        let source = directory.join("seed");
+        std::fs::create_dir(&source).unwrap();
+        for (name, data) in files() {
+            std::fs::write(source.join(name), data).unwrap();
+        }
+        let torrent = create_torrent(&source, MetaVersion::V1, PIECE_LENGTH, None, false).unwrap();
+        // Parsed twice, since the swarm takes ownership of its copy.
+        let info = || TorrentFile::from_bytes(&torrent).unwrap().info;
+        let storage = Storage::open(&directory.join("download"), &info()).unwrap();
+        let swarm = Swarm::new(info(), storage).unwrap();
+        (info(), Arc::new(swarm))
+    }
+
+    /// Serves byte ranges of `files()` below `/seed/`.
+    fn file_server(corrupt: bool) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let files = files()
+            .into_iter()
+            .map(|(name, data)| (format!("/seed/{name}"), data))
+            .collect::<HashMap<_, _>>();
+        serve(move |target, range| {
+            let (Some(data), Some(range)) = (files.get(target), range) else {
+                return Reply::new(404, Vec::new());
+            };
+            let (start, end) = range
+                .strip_prefix("bytes=")
+                .and_then(|range| range.split_once('-'))
+                .unwrap();
+            let mut body = data[start.parse().unwrap()..=end.parse().unwrap()].to_vec();
+            if corrupt {
+                body.iter_mut().for_each(|byte| *byte = !*byte);
+            }
+            Reply::new(206, body)
+        })
+    }
+
+    #[test]
+    fn get_right_fetches_pieces_spanning_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let (info, swarm) = swarm(directory.path());
+        let (url, requests) = file_server(false);
+
+        WebSeed::get_right(&format!("{url}/"), &info)
+            .run(swarm.clone())
+            .unwrap();
+
+        assert!(swarm.is_complete());
+        let data = files()
+            .into_iter()
+            .flat_map(|(_, data)| data)
+            .collect::<Vec<_>>();
+        assert_eq!(swarm.storage.read(0, data.len()).unwrap(), data);
+        let mut ranges = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|request| (request.target.clone(), request.range.clone().unwrap()))
+            .collect::<Vec<_>>();
+        ranges.sort();
+        let expected = [
+            ("/seed/a.bin", "bytes=0-16383"),
+            ("/seed/a.bin", "bytes=16384-19999"),
+            ("/seed/b.bin", "bytes=0-12767"),
+            ("/seed/b.bin", "bytes=12768-29151"),
+            ("/seed/b.bin", "bytes=29152-29999"),
+        ]
+        .map(|(target, range)| (target.to_string(), range.to_string()));
+        assert_eq!(ranges, expected);
+    }
+
+    #[test]
+    fn get_right_seed_sending_corrupt_data_is_abandoned() {
+        let directory = tempfile::tempdir().unwrap();
+        let (info, swarm) = swarm(directory.path());
+        let (url, _) = file_server(true);
+
+        let error = WebSeed::get_right(&format!("{url}/"), &info)
+            .run(swarm.clone())
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            format!("Gave up after {MAX_FAILURES} failures")
+        );
+        assert!(!swarm.is_complete());
+        // Every failed piece was released for other sources.
+        assert_eq!(swarm.left(), info.total_length());
+        assert!(swarm.next_piece().is_some());
+    }
+
+    #[test]
+    fn failing_get_right_seed_is_backed_off() {
+        let directory = tempfile::tempdir().unwrap();
+        let (info, swarm) = swarm(directory.path());
+        let (url, requests) = serve(|_, _| Reply::new(500, Vec::new()));
+
+        let error = WebSeed::get_right(&format!("{url}/"), &info)
+            .run(swarm.clone())
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            format!("Gave up after {MAX_FAILURES} failures")
+        );
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), MAX_FAILURES as usize);
+        for (failures, pair) in (1..).zip(requests.windows(2)) {
+            let delay = pair[1].time - pair[0].time;
+            assert!(delay >= INITIAL_BACKOFF * 2u32.pow(failures - 1));
+        }
+    }
+
+    /// Serves whole pieces of `files()` for `?info_hash=...&piece=N` queries.
+    fn piece_server(info_hash: InfoHash) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let data = files()
+            .into_iter()
+            .flat_map(|(_, data)| data)
+            .collect::<Vec<_>>();
+        let query = format!("/seed?info_hash={}&piece=", tracker::urlencode(&info_hash));
+        serve(move |target, _| {
+            let Some(index) = target
+                .strip_prefix(&query)
+                .and_then(|index| index.parse::<usize>().ok())
+            else {
+                return Reply::new(400, Vec::new());
+            };
+            let start = index * PIECE_LENGTH;
+            let end = data.len().min(start + PIECE_LENGTH);
+            Reply::new(200, data[start..end].to_vec())
+        })
+    }
+
+    #[test]
+    fn hoffman_fetches_pieces_by_index() {
+        let directory = tempfile::tempdir().unwrap();
+        let (_, swarm) = swarm(directory.path());
+        let (url, requests) = piece_server(swarm.info_hash.clone());
+
+        WebSeed::hoffman(&format!("{url}/seed"), swarm.info_hash.clone())
+            .run(swarm.clone())
+            .unwrap();
+
+        assert!(swarm.is_complete());
+        let data = files()
+            .into_iter()
+            .flat_map(|(_, data)| data)
+            .collect::<Vec<_>>();
+        assert_eq!(swarm.storage.read(0, data.len()).unwrap(), data);
+        assert_eq!(requests.lock().unwrap().len(), swarm.pieces_count());
+    }
+
+    fn fetch_first_piece(reply: impl Fn() -> Reply + Send + 'static) -> Fetched {
+        let directory = tempfile::tempdir().unwrap();
+        let (_, swarm) = swarm(directory.path());
+        let (url, _) = serve(move |_, _| reply());
+        WebSeed::hoffman(&format!("{url}/seed"), swarm.info_hash.clone())
+            .fetch_piece(&Client::new(), &swarm, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn busy_hoffman_seed_delay_from_retry_after() {
+        let fetched = fetch_first_piece(|| Reply {
+            status: 503,
+            headers: vec![("Retry-After", "7".to_string())],
+            body: b"busy".to_vec(),
+        });
+        assert!(matches!(fetched, Fetched::Busy(delay) if delay == Duration::from_secs(7)));
+    }
+
+    #[test]
+    fn busy_hoffman_seed_delay_from_body() {
+        let fetched = fetch_first_piece(|| Reply::new(503, b"12\n".to_vec()));
+        assert!(matches!(fetched, Fetched::Busy(delay) if delay == Duration::from_secs(12)));
+    }
+
+    #[test]
+    fn busy_hoffman_seed_delay_is_capped() {
+        let fetched = fetch_first_piece(|| Reply::new(503, b"86400".to_vec()));
+        assert!(matches!(fetched, Fetched::Busy(delay) if delay == MAX_BUSY_DELAY));
+    }
+}